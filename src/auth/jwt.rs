@@ -0,0 +1,327 @@
+//! JSON Web Token inspection, verification and minting
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use base64::{Engine, engine::general_purpose};
+use clap::Subcommand;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde_json::{Map, Value};
+use time::OffsetDateTime;
+use time::macros::format_description;
+
+use crate::protocols::{ApiRequest, ApiResponse};
+
+// Tokens this close to expiring get a warning, they'll likely lapse mid-session
+const EXPIRY_WARNING_SECS: i64 = 5 * 60;
+
+#[derive(Clone, Subcommand)]
+pub enum JwtCommand {
+    /// Show a token's header and claims
+    Decode { token: String },
+
+    /// Check a token's signature and time claims
+    Verify {
+        token: String,
+
+        /// Shared secret for HS256/384/512
+        #[arg(long, conflicts_with_all = ["key", "jwks"])]
+        secret: Option<String>,
+
+        /// PEM public key for RS*, PS*, ES* and EdDSA
+        #[arg(long, value_name = "FILE", conflicts_with = "jwks")]
+        key: Option<PathBuf>,
+
+        /// Local JWKS file, the key is picked by the token's `kid`
+        #[arg(long, value_name = "FILE")]
+        jwks: Option<PathBuf>,
+    },
+
+    /// Create a signed token for testing
+    Mint {
+        #[arg(long, default_value = "HS256")]
+        alg: String,
+
+        /// Shared secret for HS256/384/512
+        #[arg(long, conflicts_with = "key")]
+        secret: Option<String>,
+
+        /// PEM private key for RS*, PS*, ES* and EdDSA
+        #[arg(long, value_name = "FILE")]
+        key: Option<PathBuf>,
+
+        /// Key id to put in the header
+        #[arg(long)]
+        kid: Option<String>,
+
+        /// Claims to include, values that parse as JSON are kept as such
+        #[arg(long, value_name = "NAME=VALUE")]
+        claim: Vec<String>,
+
+        /// Seconds until the token expires
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+        expires_in: i64,
+    },
+}
+
+/// A decoded, but not verified, token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Jwt {
+    pub header: Value,
+    pub claims: Value,
+}
+
+impl Jwt {
+    pub fn decode(token: &str) -> Result<Jwt, Box<dyn Error>> {
+        let parts: Vec<&str> = token.trim().split('.').collect();
+        let [header, claims, _signature] = parts[..] else {
+            return Err("A JWT has three dot separated parts".into());
+        };
+
+        let part = |encoded: &str| -> Result<Value, Box<dyn Error>> {
+            let json = general_purpose::URL_SAFE_NO_PAD.decode(encoded.trim_end_matches('='))?;
+            match serde_json::from_slice(&json)? {
+                value @ Value::Object(_) => Ok(value),
+                _ => Err("A JWT part must be a JSON object".into()),
+            }
+        };
+        Ok(Jwt {
+            header: part(header)?,
+            claims: part(claims)?,
+        })
+    }
+
+    /// Human readable lines describing the token, with warnings about its validity period
+    pub fn describe(&self) -> Vec<String> {
+        let mut lines = vec![format!("header: {}", self.header)];
+        lines.push("claims:".to_string());
+        let claims = serde_json::to_string_pretty(&self.claims).unwrap_or_default();
+        lines.extend(claims.lines().map(|line| format!("  {}", line)));
+
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        for name in ["iat", "nbf", "exp"] {
+            let Some(at) = self.claims.get(name).and_then(Value::as_i64) else {
+                continue;
+            };
+            let relative = match at - now {
+                delta if delta >= 0 => format!("in {}", humanize(delta)),
+                delta => format!("{} ago", humanize(-delta)),
+            };
+            lines.push(format!("{}: {} ({})", name, timestamp(at), relative));
+
+            match name {
+                "exp" if at <= now => lines.push(format!("⚠️  Expired {} ago", humanize(now - at))),
+                "exp" if at - now < EXPIRY_WARNING_SECS => {
+                    lines.push(format!("⚠️  Expires in {}", humanize(at - now)))
+                }
+                "nbf" if at > now => {
+                    lines.push(format!("⚠️  Not valid for another {}", humanize(at - now)))
+                }
+                _ => {}
+            }
+        }
+        lines
+    }
+}
+
+/// Finds the tokens in an exchange: Bearer credentials sent, and strings in a JSON response body
+pub fn collect(request: &ApiRequest, response: &ApiResponse) -> Vec<(String, Jwt)> {
+    let mut tokens = vec![];
+
+    for (name, value) in request.headers.iter().flatten() {
+        if let Some((scheme, token)) = value.split_once(' ')
+            && scheme.eq_ignore_ascii_case("bearer")
+            && let Ok(jwt) = Jwt::decode(token)
+        {
+            tokens.push((format!("{} header", name), jwt));
+        }
+    }
+
+    if let Some(body) = response.body_text()
+        && let Ok(json) = serde_json::from_str::<Value>(&body)
+    {
+        find_in_json(&json, "$".to_string(), &mut tokens);
+    }
+    tokens
+}
+
+fn find_in_json(value: &Value, path: String, tokens: &mut Vec<(String, Jwt)>) {
+    match value {
+        // Every JWT header starts with `{"`, which always encodes to `eyJ`
+        Value::String(text) if text.starts_with("eyJ") => {
+            if let Ok(jwt) = Jwt::decode(text) {
+                tokens.push((format!("response body at {}", path), jwt));
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                find_in_json(item, format!("{}[{}]", path, i), tokens);
+            }
+        }
+        Value::Object(fields) => {
+            for (key, item) in fields {
+                find_in_json(item, format!("{}.{}", path, key), tokens);
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn run(command: JwtCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        JwtCommand::Decode { token } => {
+            for line in Jwt::decode(&token)?.describe() {
+                println!("{}", line);
+            }
+        }
+        JwtCommand::Verify {
+            token,
+            secret,
+            key,
+            jwks,
+        } => {
+            let header = jsonwebtoken::decode_header(&token)?;
+            let decoding_key = match (secret, key, jwks) {
+                (Some(secret), _, _) => DecodingKey::from_secret(secret.as_bytes()),
+                (_, Some(path), _) => decoding_key(header.alg, &fs::read(path)?)?,
+                (_, _, Some(path)) => {
+                    let set: JwkSet = serde_json::from_slice(&fs::read(path)?)?;
+                    let jwk = match &header.kid {
+                        Some(kid) => set
+                            .find(kid)
+                            .ok_or(format!("No key with kid {} in the JWKS", kid))?,
+                        None if set.keys.len() == 1 => &set.keys[0],
+                        None => {
+                            return Err("The token has no kid to pick a key from the JWKS".into());
+                        }
+                    };
+                    DecodingKey::from_jwk(jwk)?
+                }
+                _ => return Err("Verifying needs --secret, --key or --jwks".into()),
+            };
+
+            let mut validation = Validation::new(header.alg);
+            // Check exp and nbf when they're there, but don't insist on any particular claims
+            validation.required_spec_claims.clear();
+            validation.validate_nbf = true;
+            validation.validate_aud = false;
+
+            jsonwebtoken::decode::<Value>(&token, &decoding_key, &validation)?;
+            println!("✅ Signature verified ({:?})", header.alg);
+        }
+        JwtCommand::Mint {
+            alg,
+            secret,
+            key,
+            kid,
+            claim,
+            expires_in,
+        } => {
+            let alg = Algorithm::from_str(&alg)?;
+            let encoding_key = match (secret, key) {
+                (Some(secret), _) => EncodingKey::from_secret(secret.as_bytes()),
+                (_, Some(path)) => encoding_key(alg, &fs::read(path)?)?,
+                _ => return Err("Minting needs --secret or --key".into()),
+            };
+
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let mut claims = Map::new();
+            claims.insert("iat".to_string(), now.into());
+            claims.insert("exp".to_string(), (now + expires_in).into());
+            for claim in claim {
+                let (name, value) = claim
+                    .split_once('=')
+                    .ok_or("Expected --claim as name=value")?;
+                let value = serde_json::from_str(value).unwrap_or_else(|_| value.into());
+                claims.insert(name.to_string(), value);
+            }
+
+            let header = Header {
+                kid,
+                ..Header::new(alg)
+            };
+            println!("{}", jsonwebtoken::encode(&header, &claims, &encoding_key)?);
+        }
+    }
+    Ok(())
+}
+
+fn decoding_key(alg: Algorithm, pem: &[u8]) -> Result<DecodingKey, Box<dyn Error>> {
+    use Algorithm::*;
+    Ok(match alg {
+        RS256 | RS384 | RS512 | PS256 | PS384 | PS512 => DecodingKey::from_rsa_pem(pem)?,
+        ES256 | ES384 => DecodingKey::from_ec_pem(pem)?,
+        EdDSA => DecodingKey::from_ed_pem(pem)?,
+        HS256 | HS384 | HS512 => return Err("HMAC tokens are verified with --secret".into()),
+    })
+}
+
+fn encoding_key(alg: Algorithm, pem: &[u8]) -> Result<EncodingKey, Box<dyn Error>> {
+    use Algorithm::*;
+    Ok(match alg {
+        RS256 | RS384 | RS512 | PS256 | PS384 | PS512 => EncodingKey::from_rsa_pem(pem)?,
+        ES256 | ES384 => EncodingKey::from_ec_pem(pem)?,
+        EdDSA => EncodingKey::from_ed_pem(pem)?,
+        HS256 | HS384 | HS512 => return Err("HMAC tokens are minted with --secret".into()),
+    })
+}
+
+fn timestamp(at: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(at)
+        .ok()
+        .and_then(|at| {
+            at.format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute]:[second] UTC"
+            ))
+            .ok()
+        })
+        .unwrap_or_else(|| at.to_string())
+}
+
+// The two largest units are precise enough to judge a token's lifetime by
+fn humanize(seconds: i64) -> String {
+    let units = [("d", 86400), ("h", 3600), ("m", 60), ("s", 1)];
+    let parts: Vec<String> = units
+        .iter()
+        .scan(seconds, |remaining, (unit, size)| {
+            let count = *remaining / size;
+            *remaining %= size;
+            Some((count, unit))
+        })
+        .skip_while(|(count, _)| *count == 0)
+        .take(2)
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, unit)| format!("{}{}", count, unit))
+        .collect();
+
+    match parts.is_empty() {
+        true => "0s".to_string(),
+        false => parts.join(" "),
+    }
+}
+
+#[test]
+fn test_decode_and_describe() -> Result<(), Box<dyn Error>> {
+    // {"alg":"HS256","typ":"JWT"}.{"sub":"1234567890","name":"John Doe","exp":1516239022}
+    let jwt = Jwt::decode(
+        "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+         eyJzdWIiOiIxMjM0NTY3ODkwIiwibmFtZSI6IkpvaG4gRG9lIiwiZXhwIjoxNTE2MjM5MDIyfQ.\
+         signature",
+    )?;
+    assert_eq!(jwt.claims["sub"], "1234567890");
+
+    let lines = jwt.describe();
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with("exp: 2018-01-18 01:30:22 UTC ("))
+    );
+    assert!(lines.iter().any(|line| line.starts_with("⚠️  Expired")));
+    assert_eq!(humanize(3725), "1h 2m");
+    assert_eq!(humanize(90061), "1d 1h");
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+pub mod digest;
+pub mod jwt;
+pub mod oauth2;
+pub mod sigv4;
+
+use std::error::Error;
+
+use base64::{Engine, engine::general_purpose};
+use clap::ValueEnum;
+use hyper::HeaderMap;
+use hyper::header::{AUTHORIZATION, HeaderName, HeaderValue};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum AuthType {
+    /// `user:password`, sent up front
+    #[default]
+    Basic,
+
+    /// `user:password`, sent in response to the server's 401 challenge
+    Digest,
+
+    /// A token, sent as is
+    Bearer,
+}
+
+/// Credentials for the `Authorization` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Auth {
+    Basic { user: String, password: String },
+    Digest { user: String, password: String },
+    Bearer { token: String },
+}
+
+impl Auth {
+    /// Builds credentials from the `--auth` value, which is `user:password` or a bare token
+    pub fn parse(value: &str, auth_type: AuthType) -> Result<Auth, Box<dyn Error>> {
+        let user_password = || {
+            value
+                .split_once(':')
+                .map(|(user, password)| (user.to_string(), password.to_string()))
+                .ok_or_else(|| format!("Expected user:password for {:?} auth", auth_type))
+        };
+
+        Ok(match auth_type {
+            AuthType::Basic => {
+                let (user, password) = user_password()?;
+                Auth::Basic { user, password }
+            }
+            AuthType::Digest => {
+                let (user, password) = user_password()?;
+                Auth::Digest { user, password }
+            }
+            AuthType::Bearer => Auth::Bearer {
+                token: value.to_string(),
+            },
+        })
+    }
+
+    /// Adds the `Authorization` header for schemes that don't wait for a challenge
+    pub fn apply(&self, headers: &mut HeaderMap) -> Result<(), Box<dyn Error>> {
+        let value = match self {
+            Auth::Basic { user, password } => format!(
+                "Basic {}",
+                general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+            ),
+            Auth::Bearer { token } => format!("Bearer {}", token),
+            // Needs the server's nonce first, see `digest::respond`
+            Auth::Digest { .. } => return Ok(()),
+        };
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&value)?);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ApiKeyLocation {
+    #[default]
+    Header,
+    Query,
+}
+
+/// A static API key, sent as a header or query parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub name: String,
+    pub value: String,
+    pub location: ApiKeyLocation,
+}
+
+impl ApiKey {
+    /// Parses `name=value` or `name:value`
+    pub fn parse(value: &str, location: ApiKeyLocation) -> Result<ApiKey, Box<dyn Error>> {
+        let separator = value
+            .find([':', '='])
+            .ok_or("Expected the API key as name=value")?;
+        Ok(ApiKey {
+            name: value[..separator].trim().to_string(),
+            value: value[separator + 1..].trim().to_string(),
+            location,
+        })
+    }
+
+    pub fn apply(&self, url: &mut Url, headers: &mut HeaderMap) -> Result<(), Box<dyn Error>> {
+        match self.location {
+            ApiKeyLocation::Header => {
+                headers.insert(
+                    HeaderName::from_bytes(self.name.as_bytes())?,
+                    HeaderValue::from_str(&self.value)?,
+                );
+            }
+            ApiKeyLocation::Query => {
+                url.query_pairs_mut().append_pair(&self.name, &self.value);
+            }
+        }
+        Ok(())
+    }
+}
+
+const MASK: &str = "****";
+
+// Header and parameter names that give away a credential
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    matches!(
+        name.as_str(),
+        "authorization" | "proxy-authorization" | "cookie" | "set-cookie"
+    ) || ["key", "token", "secret", "password", "signature"]
+        .iter()
+        .any(|hint| name.contains(hint))
+}
+
+/// Hides credentials in a header value, keeping the scheme so it's still clear what was sent
+pub fn mask_header(name: &str, value: &str) -> String {
+    if !is_sensitive(name) {
+        return value.to_string();
+    }
+
+    match value.split_once(' ') {
+        Some((scheme, _)) if name.to_ascii_lowercase().ends_with("authorization") => {
+            format!("{} {}", scheme, MASK)
+        }
+        _ => MASK.to_string(),
+    }
+}
+
+/// Hides credentials passed as query parameters
+pub fn mask_url(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    if !parsed.query_pairs().any(|(name, _)| is_sensitive(&name)) {
+        return url.to_string();
+    }
+
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(name, value)| {
+            let value = if is_sensitive(&name) {
+                MASK.to_string()
+            } else {
+                value.into_owned()
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+#[test]
+fn test_masking() {
+    assert_eq!(
+        mask_header("Authorization", "Bearer abc.def"),
+        "Bearer ****"
+    );
+    assert_eq!(mask_header("X-API-Key", "s3cret"), "****");
+    assert_eq!(mask_header("Accept", "*/*"), "*/*");
+    assert_eq!(
+        mask_url("https://example.com/a?api_key=s3cret&page=2"),
+        "https://example.com/a?api_key=****&page=2"
+    );
+}
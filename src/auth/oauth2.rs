@@ -0,0 +1,644 @@
+//! OAuth2 token acquisition (RFC 6749, RFC 8628 device flow, RFC 7636 PKCE) with an on-disk cache
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{Engine, engine::general_purpose};
+use clap::{Args, ValueEnum};
+use hyper::Method;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use url::Url;
+
+use super::Auth;
+use crate::diagnostics::AUTH;
+use crate::protocols::http::{HttpClient, HttpVersion};
+use crate::protocols::{ApiCall, ApiProtocol};
+
+// Treat tokens this close to expiry as expired, so they don't lapse mid-request
+const EXPIRY_LEEWAY: Duration = Duration::from_secs(30);
+
+// RFC 8628 says clients must wait this long between polls unless told otherwise
+const DEVICE_POLL_INTERVAL: u64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Grant {
+    /// The client's own credentials, for service-to-service calls
+    ClientCredentials,
+
+    /// The resource owner's `--auth user:password`
+    Password,
+
+    /// Trade `--refresh-token` for a new access token
+    RefreshToken,
+
+    /// Show a code to enter on another device, then poll until it's approved
+    DeviceCode,
+
+    /// Approve in the browser and catch the redirect on a loopback listener, secured with PKCE
+    AuthorizationCode,
+}
+
+#[derive(Debug, Clone, Args)]
+#[command(next_help_heading = "OAuth2")]
+pub struct OAuth2Options {
+    /// Get a token with this grant and send it as a Bearer token
+    #[arg(long, value_enum, value_name = "GRANT")]
+    pub oauth2: Option<Grant>,
+
+    /// The token endpoint
+    #[arg(long, value_name = "URL")]
+    pub token_url: Option<String>,
+
+    /// The authorization endpoint, for the authorization-code grant
+    #[arg(long, value_name = "URL")]
+    pub authorize_url: Option<String>,
+
+    /// The device authorization endpoint, for the device-code grant
+    #[arg(long, value_name = "URL")]
+    pub device_url: Option<String>,
+
+    #[arg(long)]
+    pub client_id: Option<String>,
+
+    #[arg(long)]
+    pub client_secret: Option<String>,
+
+    /// Space separated scopes to request
+    #[arg(long)]
+    pub scope: Option<String>,
+
+    /// A refresh token, for the refresh-token grant
+    #[arg(long)]
+    pub refresh_token: Option<String>,
+
+    /// Port for the loopback redirect listener, a free one is picked by default
+    #[arg(long, default_value_t = 0)]
+    pub redirect_port: u16,
+
+    /// Name to cache tokens under, so several accounts can be kept apart
+    #[arg(long, default_value = "default")]
+    pub profile: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl Token {
+    fn is_fresh(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => now() + EXPIRY_LEEWAY.as_secs() < expires_at,
+            None => true,
+        }
+    }
+}
+
+// What gets written to the cache, with enough context to tell whether it's still applicable
+#[derive(Serialize, Deserialize)]
+struct CachedToken {
+    token_url: String,
+    client_id: Option<String>,
+    #[serde(default)]
+    grant: Option<Grant>,
+    /// The scope asked for, which may differ from the one granted
+    #[serde(default)]
+    scope: Option<String>,
+    token: Token,
+}
+
+// The token endpoint's answer, successful or not (RFC 6749 section 5)
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    token_type: Option<String>,
+    expires_in: Option<u64>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+impl OAuth2Options {
+    /// Gets a token, from the cache when it's still good, and returns it as Bearer credentials.
+    ///
+    /// `credentials` is the `--auth` value, which the password grant uses as the resource owner.
+    pub async fn authenticate(&self, credentials: Option<&Auth>) -> Result<Auth, Box<dyn Error>> {
+        let grant = self.oauth2.ok_or("No OAuth2 grant selected")?;
+        let token_url = self
+            .token_url
+            .as_deref()
+            .ok_or("--token-url is required for OAuth2")?;
+
+        let cached = self.load(token_url);
+        let token = match cached {
+            Some(token) if token.is_fresh() => {
+                info!(target: AUTH, profile = %self.profile, "Using cached OAuth2 token");
+                token
+            }
+            Some(Token {
+                refresh_token: Some(refresh_token),
+                ..
+            }) => match self.refresh(token_url, &refresh_token).await {
+                Ok(token) => token,
+                Err(err) => {
+                    warn!(target: AUTH, error = %err, "Token refresh failed, requesting a new one");
+                    self.request(grant, token_url, credentials).await?
+                }
+            },
+            _ => self.request(grant, token_url, credentials).await?,
+        };
+
+        self.save(token_url, &token)?;
+        Ok(Auth::Bearer {
+            token: token.access_token,
+        })
+    }
+
+    async fn request(
+        &self,
+        grant: Grant,
+        token_url: &str,
+        credentials: Option<&Auth>,
+    ) -> Result<Token, Box<dyn Error>> {
+        info!(target: AUTH, ?grant, "Requesting OAuth2 token");
+
+        match grant {
+            Grant::ClientCredentials => {
+                let mut params = vec![("grant_type", "client_credentials")];
+                params.extend(self.scope.as_deref().map(|scope| ("scope", scope)));
+                self.token(token_url, &params).await
+            }
+            Grant::Password => {
+                let (user, password) = match credentials {
+                    Some(Auth::Basic { user, password }) => (user, password),
+                    _ => return Err("The password grant needs --auth user:password".into()),
+                };
+                let mut params = vec![
+                    ("grant_type", "password"),
+                    ("username", user.as_str()),
+                    ("password", password.as_str()),
+                ];
+                params.extend(self.scope.as_deref().map(|scope| ("scope", scope)));
+                self.token(token_url, &params).await
+            }
+            Grant::RefreshToken => {
+                let refresh_token = self
+                    .refresh_token
+                    .as_deref()
+                    .ok_or("The refresh-token grant needs --refresh-token")?;
+                self.refresh(token_url, refresh_token).await
+            }
+            Grant::DeviceCode => self.device_code(token_url).await,
+            Grant::AuthorizationCode => self.authorization_code(token_url).await,
+        }
+    }
+
+    async fn refresh(&self, token_url: &str, refresh_token: &str) -> Result<Token, Box<dyn Error>> {
+        info!(target: AUTH, "Refreshing OAuth2 token");
+        let mut token = self
+            .token(
+                token_url,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token),
+                ],
+            )
+            .await?;
+        // Servers may keep the refresh token the same and not send it again
+        token
+            .refresh_token
+            .get_or_insert_with(|| refresh_token.to_string());
+        Ok(token)
+    }
+
+    async fn device_code(&self, token_url: &str) -> Result<Token, Box<dyn Error>> {
+        let device_url = self
+            .device_url
+            .as_deref()
+            .ok_or("The device-code grant needs --device-url")?;
+
+        let mut params = vec![];
+        params.extend(self.scope.as_deref().map(|scope| ("scope", scope)));
+        let body = self.post(device_url, &params).await?;
+        let authorization: DeviceAuthorization = serde_json::from_slice(&body)?;
+
+        eprintln!(
+            "To authorize, visit {} and enter the code {}",
+            authorization.verification_uri, authorization.user_code
+        );
+        if let Some(complete) = &authorization.verification_uri_complete {
+            eprintln!("Or open {}", complete);
+        }
+
+        let deadline = now() + authorization.expires_in;
+        let mut interval = authorization.interval.unwrap_or(DEVICE_POLL_INTERVAL);
+        while now() < deadline {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+
+            let params = [
+                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                ("device_code", authorization.device_code.as_str()),
+            ];
+            let response = self.token_response(token_url, &params).await?;
+            match response.error.as_deref() {
+                Some("authorization_pending") => {}
+                Some("slow_down") => interval += DEVICE_POLL_INTERVAL,
+                _ => return into_token(response),
+            }
+        }
+
+        Err("The device code expired before it was approved".into())
+    }
+
+    async fn authorization_code(&self, token_url: &str) -> Result<Token, Box<dyn Error>> {
+        let authorize_url = self
+            .authorize_url
+            .as_deref()
+            .ok_or("The authorization-code grant needs --authorize-url")?;
+        let client_id = self
+            .client_id
+            .as_deref()
+            .ok_or("The authorization-code grant needs --client-id")?;
+
+        let listener = TcpListener::bind(("127.0.0.1", self.redirect_port)).await?;
+        let redirect_uri = format!(
+            "http://127.0.0.1:{}/callback",
+            listener.local_addr()?.port()
+        );
+
+        let verifier = random_token();
+        let state = random_token();
+
+        let mut url = Url::parse(authorize_url)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", client_id)
+            .append_pair("redirect_uri", &redirect_uri)
+            .append_pair("state", &state)
+            .append_pair("code_challenge", &pkce_challenge(&verifier))
+            .append_pair("code_challenge_method", "S256");
+        if let Some(scope) = &self.scope {
+            url.query_pairs_mut().append_pair("scope", scope);
+        }
+
+        eprintln!("To authorize, open {}", url);
+        let code = receive_code(&listener, &state).await?;
+
+        self.token(
+            token_url,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code.as_str()),
+                ("redirect_uri", redirect_uri.as_str()),
+                ("code_verifier", verifier.as_str()),
+            ],
+        )
+        .await
+    }
+
+    async fn token(
+        &self,
+        token_url: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Token, Box<dyn Error>> {
+        into_token(self.token_response(token_url, params).await?)
+    }
+
+    async fn token_response(
+        &self,
+        token_url: &str,
+        params: &[(&str, &str)],
+    ) -> Result<TokenResponse, Box<dyn Error>> {
+        let body = self.post(token_url, params).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    // Posts a form, authenticating the client with its secret when it has one (RFC 6749 section 2.3.1)
+    async fn post(&self, url: &str, params: &[(&str, &str)]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut form = url::form_urlencoded::Serializer::new(String::new());
+        form.extend_pairs(params);
+
+        let mut builder = ApiCall::builder(Method::POST, url);
+        match (&self.client_id, &self.client_secret) {
+            (Some(client_id), Some(client_secret)) => {
+                builder = builder.auth(Auth::Basic {
+                    user: client_id.clone(),
+                    password: client_secret.clone(),
+                });
+            }
+            (Some(client_id), None) => {
+                form.append_pair("client_id", client_id);
+            }
+            _ => {}
+        }
+        let call = builder
+            .body("application/x-www-form-urlencoded", form.finish())
+            .build()?;
+
+        let client = HttpClient {
+            version: HttpVersion::Http1,
+        };
+        let (_, response) = client.execute(call).await?;
+        let status = response.status.unwrap_or_default();
+        let body = response.body.unwrap_or_default();
+
+        // Error responses are JSON too, and say more than the status does
+        if !(200..300).contains(&status) && serde_json::from_slice::<TokenResponse>(&body).is_err()
+        {
+            return Err(format!("{} responded with status {}", url, status).into());
+        }
+        Ok(body)
+    }
+
+    fn cache_path(&self) -> Option<PathBuf> {
+        Some(
+            dirs::cache_dir()?
+                .join("apigrok")
+                .join("oauth2")
+                .join(format!("{}.json", self.profile)),
+        )
+    }
+
+    fn load(&self, token_url: &str) -> Option<Token> {
+        let cached: CachedToken =
+            serde_json::from_slice(&fs::read(self.cache_path()?).ok()?).ok()?;
+        self.applies(&cached, token_url).then_some(cached.token)
+    }
+
+    // A profile reused against another server, client, grant or scope shouldn't hand its token
+    // over, least of all one with narrower scope
+    fn applies(&self, cached: &CachedToken, token_url: &str) -> bool {
+        cached.token_url == token_url
+            && cached.client_id == self.client_id
+            && cached.grant == self.oauth2
+            && cached.scope == self.scope
+    }
+
+    fn save(&self, token_url: &str, token: &Token) -> Result<(), Box<dyn Error>> {
+        let Some(path) = self.cache_path() else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let cached = CachedToken {
+            token_url: token_url.to_string(),
+            client_id: self.client_id.clone(),
+            grant: self.oauth2,
+            scope: self.scope.clone(),
+            token: token.clone(),
+        };
+
+        // Tokens are as good as passwords, keep them to ourselves from the moment the file exists
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to new files, an older cache may have been left readable
+            if let Ok(metadata) = fs::metadata(&path) {
+                let mut permissions = metadata.permissions();
+                permissions.set_mode(0o600);
+                fs::set_permissions(&path, permissions)?;
+            }
+        }
+        let mut file = options.open(&path)?;
+        file.write_all(&serde_json::to_vec_pretty(&cached)?)?;
+        Ok(())
+    }
+}
+
+fn into_token(response: TokenResponse) -> Result<Token, Box<dyn Error>> {
+    if let Some(error) = response.error {
+        return Err(match response.error_description {
+            Some(description) => format!("OAuth2 error {}: {}", error, description),
+            None => format!("OAuth2 error {}", error),
+        }
+        .into());
+    }
+
+    Ok(Token {
+        access_token: response
+            .access_token
+            .ok_or("The token response has no access_token")?,
+        token_type: response.token_type.unwrap_or_else(|| "Bearer".to_string()),
+        expires_at: response.expires_in.map(|expires_in| now() + expires_in),
+        refresh_token: response.refresh_token,
+        scope: response.scope,
+    })
+}
+
+// Waits for the browser to come back to the loopback redirect and pulls out the code
+async fn receive_code(listener: &TcpListener, state: &str) -> Result<String, Box<dyn Error>> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request_line = String::new();
+        BufReader::new(&mut stream)
+            .read_line(&mut request_line)
+            .await?;
+
+        // GET /callback?code=...&state=... HTTP/1.1
+        let Some(target) = request_line.split_whitespace().nth(1) else {
+            continue;
+        };
+        let url = Url::parse("http://127.0.0.1")?.join(target)?;
+        if url.path() != "/callback" {
+            // Browsers like to ask for a favicon too
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await?;
+            continue;
+        }
+
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        let result = match (param("code"), param("error")) {
+            _ if param("state").as_deref() != Some(state) => {
+                Err("The authorization response had the wrong state".to_string())
+            }
+            (Some(code), None) => Ok(code),
+            (_, Some(error)) => Err(format!("Authorization was denied: {}", error)),
+            (None, None) => Err("The authorization response had no code".to_string()),
+        };
+
+        let message = match &result {
+            Ok(_) => "Authorized, you can close this window and return to apigrok.",
+            Err(_) => "Authorization failed, see apigrok for details.",
+        };
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    message.len(),
+                    message
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        return result.map_err(Into::into);
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_pkce_challenge() {
+    // The example from RFC 7636 appendix B
+    assert_eq!(
+        pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+        "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+    );
+}
+
+#[tokio::test]
+async fn test_token_endpoints() -> Result<(), Box<dyn Error>> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    // A stand-in for the authorization server, answering each form by its grant_type
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("http://{}", listener.local_addr()?);
+    let polls = Arc::new(AtomicUsize::new(0));
+    let seen = Arc::clone(&polls);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut chunk = [0u8; 4096];
+            // Read until the whole form is in, as Content-Length tells
+            while let Ok(read @ 1..) = stream.read(&mut chunk).await {
+                request.extend_from_slice(&chunk[..read]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().parse().unwrap_or(0))
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let request = String::from_utf8_lossy(&request).into_owned();
+            let form = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+            let answer = if request.starts_with("POST /device ") {
+                r#"{"device_code": "dev", "user_code": "ABCD", "verification_uri": "http://x",
+                    "expires_in": 60, "interval": 0}"#
+            } else if form.contains("grant_type=client_credentials&scope=read") {
+                r#"{"access_token": "cc", "token_type": "Bearer", "expires_in": 3600}"#
+            } else if form.contains("grant_type=refresh_token&refresh_token=old") {
+                r#"{"access_token": "refreshed", "expires_in": 3600}"#
+            } else if form.contains("device_code=dev") && seen.fetch_add(1, Ordering::SeqCst) == 0 {
+                r#"{"error": "authorization_pending"}"#
+            } else if form.contains("device_code=dev") {
+                r#"{"access_token": "device", "refresh_token": "new"}"#
+            } else {
+                r#"{"error": "invalid_grant", "error_description": "unexpected"}"#
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                answer.len(),
+                answer
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let options = OAuth2Options {
+        oauth2: Some(Grant::ClientCredentials),
+        token_url: Some(format!("{}/token", base)),
+        authorize_url: None,
+        device_url: Some(format!("{}/device", base)),
+        client_id: Some("app".to_string()),
+        client_secret: Some("secret".to_string()),
+        scope: Some("read".to_string()),
+        refresh_token: None,
+        redirect_port: 0,
+        profile: "test".to_string(),
+    };
+    let token_url = format!("{}/token", base);
+
+    let token = options
+        .request(Grant::ClientCredentials, &token_url, None)
+        .await?;
+    assert_eq!(token.access_token, "cc");
+    assert!(token.is_fresh());
+
+    // The refresh token is kept when the server doesn't send a new one
+    let token = options.refresh(&token_url, "old").await?;
+    assert_eq!(token.access_token, "refreshed");
+    assert_eq!(token.refresh_token.as_deref(), Some("old"));
+
+    let token = options.request(Grant::DeviceCode, &token_url, None).await?;
+    assert_eq!(token.access_token, "device");
+    assert_eq!(polls.load(Ordering::SeqCst), 2);
+
+    let error = options.refresh(&token_url, "revoked").await.unwrap_err();
+    assert_eq!(error.to_string(), "OAuth2 error invalid_grant: unexpected");
+
+    // A token cached for a narrower scope doesn't stand in for a wider one
+    let cached = CachedToken {
+        token_url: token_url.clone(),
+        client_id: options.client_id.clone(),
+        grant: options.oauth2,
+        scope: options.scope.clone(),
+        token,
+    };
+    assert!(options.applies(&cached, &token_url));
+    let wider = OAuth2Options {
+        scope: Some("read write".to_string()),
+        ..options.clone()
+    };
+    assert!(!wider.applies(&cached, &token_url));
+    Ok(())
+}
@@ -1,11 +1,11 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{sync::Arc, time::Duration};
 
 use http_body_util::Empty;
 use hyper::{
     HeaderMap,
     body::{Bytes, Incoming},
     client::conn,
-    header::{ACCEPT, ACCEPT_ENCODING, HOST, HeaderName, HeaderValue, USER_AGENT},
+    header::{COOKIE, HOST, HeaderName, HeaderValue, USER_AGENT},
 };
 
 use hyper_util::rt::TokioIo;
@@ -13,6 +13,7 @@ use tokio::{net::TcpStream, runtime::Runtime, sync::Mutex};
 use url::Url;
 
 use crate::clients::http::ClientConfiguration;
+use crate::cookies::CookieJar;
 
 use super::{request::Request, response::Response};
 
@@ -20,6 +21,7 @@ pub struct Client {
     sender: Arc<Mutex<conn::http1::SendRequest<Empty<Bytes>>>>,
     rt: Runtime,
     config: ClientConfiguration,
+    cookie_jar: Option<Arc<std::sync::Mutex<CookieJar>>>,
 }
 
 pub struct ClientBuilder {
@@ -28,6 +30,7 @@ pub struct ClientBuilder {
     port: u16,
     timeout: Duration,
     headers: HeaderMap,
+    cookie_jar: Option<Arc<std::sync::Mutex<CookieJar>>>,
 }
 
 impl Client {
@@ -38,6 +41,7 @@ impl Client {
             port: 80,
             timeout: Duration::from_secs(10),
             headers: HeaderMap::new(),
+            cookie_jar: None,
         }
     }
 
@@ -50,19 +54,37 @@ impl Client {
         }
     }
 
-    pub fn execute(&self, request: Request) -> Result<Response, Box<dyn std::error::Error>> {
+    pub fn execute(&self, mut request: Request) -> Result<Response, Box<dyn std::error::Error>> {
         let method = request.method.clone();
         let url = request.url.clone();
+        let parsed_url = Url::parse(&url)?;
+
+        if let Some(jar) = &self.cookie_jar {
+            let jar = jar.lock().map_err(|_| "Cookie jar poisoned")?;
+            if let Some(cookies) = jar.header_for(&parsed_url, &method, false) {
+                request
+                    .headers
+                    .get_or_insert_with(HeaderMap::new)
+                    .insert(COOKIE, cookies.parse()?);
+            }
+        }
 
         let sender = Arc::clone(&self.sender);
-        let mut http_req = build_http_request(request, self.config.clone())?; // map your internal Request to hyper::Request
+        let http_req = build_http_request(request, self.config.clone())?; // map your internal Request to hyper::Request
 
-        let response = self.rt.block_on(async {
+        let (response, headers) = self.rt.block_on(async {
             let mut locked = sender.lock().await;
             let resp = locked.send_request(http_req).await?;
-            build_http_response(resp)
+            let headers = resp.headers().clone();
+            Ok::<_, Box<dyn std::error::Error>>((build_http_response(resp)?, headers))
         })?;
 
+        if let Some(jar) = &self.cookie_jar {
+            jar.lock()
+                .map_err(|_| "Cookie jar poisoned")?
+                .store_response(&parsed_url, &headers);
+        }
+
         println!("Executing {:?} {:?}", method, url);
 
         Ok(response)
@@ -152,6 +174,10 @@ impl ClientBuilder {
         self.headers.insert(key.into(), value.into());
         self
     }
+    pub fn cookie_jar(mut self, cookie_jar: Arc<std::sync::Mutex<CookieJar>>) -> Self {
+        self.cookie_jar = Some(cookie_jar);
+        self
+    }
 
     pub fn build(self) -> Result<Client, Box<dyn std::error::Error>> {
         let base_url = self.base_url.ok_or("Missing base_url")?;
@@ -183,12 +209,15 @@ impl ClientBuilder {
                 base_url,
                 port,
             },
+            cookie_jar: self.cookie_jar,
         })
     }
 }
 
 #[test]
 fn test_blocking_client() -> Result<(), Box<dyn std::error::Error>> {
+    use hyper::header::ACCEPT;
+
     let parsed_url = Url::parse("http://myrstack.tech")?;
     let scheme = parsed_url.scheme();
     let port = parsed_url
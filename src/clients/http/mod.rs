@@ -1,10 +1,6 @@
 use std::time::Duration;
 
-use http_body_util::Empty;
-use hyper::{
-    HeaderMap, Request, Response,
-    body::{Bytes, Incoming},
-};
+use hyper::HeaderMap;
 
 mod async_client;
 mod blocking_client;
@@ -14,19 +14,51 @@ macro_rules! response_output {
 }
 pub(crate) use response_output;
 
+// The request and response lines are diagnostics, so like curl -v they go to stderr
 macro_rules! color_output {
     ($color:expr, $expression:block) => {
-        use io::stdout;
-        use std::io::{IsTerminal, Write};
+        use std::io::{IsTerminal, Write, stderr};
 
-        let mut stdout = stdout();
-        if stdout.is_terminal() {
-            write!(stdout, "{}", $color.prefix())?;
+        let mut stderr = stderr();
+        if stderr.is_terminal() {
+            write!(stderr, "{}", $color.prefix())?;
         }
         $expression
-        if stdout.is_terminal() {
-            write!(stdout, "{}", $color.suffix())?;
+        if stderr.is_terminal() {
+            write!(stderr, "{}", $color.suffix())?;
         }
     };
 }
 pub(crate) use color_output;
+
+/// The kinds of token a body renderer can ask to highlight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Token {
+    Key,
+    String,
+    Number,
+    Literal,
+    Punctuation,
+    Tag,
+    Attribute,
+    Comment,
+    Keyword,
+}
+
+/// The style each token is highlighted with
+pub fn style(token: Token) -> ansi_term::Style {
+    use ansi_term::Color::{Blue, Cyan, Green, Purple, Yellow};
+    use ansi_term::Style;
+
+    match token {
+        Token::Key => Blue.bold(),
+        Token::String => Green.normal(),
+        Token::Number => Cyan.normal(),
+        Token::Literal => Purple.normal(),
+        Token::Punctuation => Style::new().dimmed(),
+        Token::Tag => Blue.bold(),
+        Token::Attribute => Yellow.normal(),
+        Token::Comment => Style::new().dimmed().italic(),
+        Token::Keyword => Purple.bold(),
+    }
+}
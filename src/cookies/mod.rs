@@ -0,0 +1,421 @@
+//! An RFC 6265 cookie store, persisted in the Netscape cookie file format curl and wget use
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::header::SET_COOKIE;
+use hyper::{HeaderMap, Method};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::diagnostics::HTTP;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    /// Only sent to the exact host that set it, not its subdomains
+    pub host_only: bool,
+    pub path: String,
+    pub secure: bool,
+    pub http_only: bool,
+    /// Seconds since the Unix epoch, `None` for session cookies
+    pub expires: Option<i64>,
+    pub same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        let host = host(url).unwrap_or_default();
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(&host, &self.domain)
+        };
+        let secure_ok = !self.secure || is_secure(url);
+
+        domain_ok && secure_ok && path_match(url.path(), &self.path)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Adds every cookie from another jar, replacing ones with the same name, domain and path
+    pub fn extend(&mut self, other: CookieJar) {
+        for cookie in other.cookies {
+            self.cookies.retain(|existing| {
+                (&existing.name, &existing.domain, &existing.path)
+                    != (&cookie.name, &cookie.domain, &cookie.path)
+            });
+            self.cookies.push(cookie);
+        }
+    }
+
+    /// Records the cookies a response sets
+    pub fn store_response(&mut self, url: &Url, headers: &HeaderMap) {
+        for value in headers.get_all(SET_COOKIE) {
+            if let Ok(value) = value.to_str() {
+                self.store(url, value);
+            }
+        }
+    }
+
+    /// Records a single `Set-Cookie` value, ignoring it if the rules say the server may not set it
+    pub fn store(&mut self, url: &Url, set_cookie: &str) {
+        let Some(cookie) = parse_set_cookie(url, set_cookie, now()) else {
+            debug!(target: HTTP, set_cookie, "Rejected cookie");
+            return;
+        };
+
+        self.cookies.retain(|existing| {
+            (&existing.name, &existing.domain, &existing.path)
+                != (&cookie.name, &cookie.domain, &cookie.path)
+        });
+
+        // Setting an already expired cookie is how servers delete them
+        if cookie.is_expired(now()) {
+            info!(target: HTTP, name = %cookie.name, domain = %cookie.domain, "Removed cookie");
+        } else {
+            info!(target: HTTP, name = %cookie.name, domain = %cookie.domain, path = %cookie.path, "Stored cookie");
+            self.cookies.push(cookie);
+        }
+    }
+
+    /// The `Cookie` header value for a request, if any cookies apply.
+    ///
+    /// `cross_site` is whether the request was reached by a redirect from another site, in which
+    /// case SameSite cookies are held back the way a browser would.
+    pub fn header_for(&self, url: &Url, method: &Method, cross_site: bool) -> Option<String> {
+        let now = now();
+        let mut cookies: Vec<&Cookie> = self
+            .cookies
+            .iter()
+            .filter(|cookie| !cookie.is_expired(now) && cookie.matches(url))
+            .filter(|cookie| match cookie.same_site {
+                _ if !cross_site => true,
+                Some(SameSite::Strict) => false,
+                // Browsers default to Lax, which still allows top-level navigation
+                Some(SameSite::Lax) | None => matches!(*method, Method::GET | Method::HEAD),
+                Some(SameSite::None) => true,
+            })
+            .collect();
+
+        // Longer paths first, as RFC 6265 section 5.4 recommends
+        cookies.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+
+        (!cookies.is_empty()).then(|| {
+            cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+    }
+
+    /// Reads a Netscape format cookie file
+    pub fn load(path: &Path) -> Result<CookieJar, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let mut jar = CookieJar::default();
+
+        for line in contents.lines() {
+            // curl marks HttpOnly cookies with a prefix on what would otherwise be a comment
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, subdomains, path, secure, expires, name, value] = fields[..] else {
+                return Err(format!("Malformed cookie file line: {}", line).into());
+            };
+            let expires: i64 = expires.parse()?;
+
+            jar.cookies.push(Cookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                domain: domain.trim_start_matches('.').to_ascii_lowercase(),
+                host_only: subdomains != "TRUE",
+                path: path.to_string(),
+                secure: secure == "TRUE",
+                http_only,
+                expires: (expires != 0).then_some(expires),
+                // Not part of the file format
+                same_site: None,
+            });
+        }
+
+        Ok(jar)
+    }
+
+    /// Writes the jar as a Netscape format cookie file, session cookies included so a login
+    /// carries over to the next run
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let now = now();
+        let mut contents = String::from("# Netscape HTTP Cookie File\n# Written by apigrok\n\n");
+
+        for cookie in self.cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+            let bool = |value: bool| if value { "TRUE" } else { "FALSE" };
+            contents.push_str(&format!(
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { "#HttpOnly_" } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                bool(!cookie.host_only),
+                cookie.path,
+                bool(cookie.secure),
+                cookie.expires.unwrap_or(0),
+                cookie.name,
+                cookie.value
+            ));
+        }
+
+        // Session cookies log in as the user, so the file is private from the moment it exists
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to new files, an older jar may have been left readable
+            if let Ok(metadata) = fs::metadata(path) {
+                let mut permissions = metadata.permissions();
+                permissions.set_mode(0o600);
+                fs::set_permissions(path, permissions)?;
+            }
+        }
+        options.open(path)?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+}
+
+// RFC 6265 section 5.2, plus the SameSite and Secure checks browsers apply on top
+fn parse_set_cookie(url: &Url, set_cookie: &str, now: i64) -> Option<Cookie> {
+    let mut parts = set_cookie.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+
+    let host = host(url)?;
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: value.trim().trim_matches('"').to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url),
+        secure: false,
+        http_only: false,
+        expires: None,
+        same_site: None,
+    };
+
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = match attribute.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (attribute.trim(), ""),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                // Servers may only widen a cookie to a domain they belong to, and never to a bare
+                // top-level domain or onto an IP address
+                if !domain_match(&host, &domain)
+                    || !domain.contains('.')
+                    || host.parse::<IpAddr>().is_ok()
+                {
+                    return None;
+                }
+                cookie.domain = domain;
+                cookie.host_only = false;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => {
+                // Dashes show up in the older `Wed, 21-Oct-2015` form
+                if let Ok(at) = httpdate::parse_http_date(&value.replace('-', " ")) {
+                    cookie.expires = Some(
+                        at.duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_secs() as i64),
+                    );
+                }
+            }
+            "samesite" => {
+                cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                    "strict" => Some(SameSite::Strict),
+                    "lax" => Some(SameSite::Lax),
+                    "none" => Some(SameSite::None),
+                    _ => None,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Max-Age wins over Expires
+    if let Some(max_age) = max_age {
+        cookie.expires = Some(if max_age <= 0 { 0 } else { now + max_age });
+    }
+
+    if (cookie.secure || cookie.same_site == Some(SameSite::None)) && !is_secure(url) {
+        return None;
+    }
+    if cookie.same_site == Some(SameSite::None) && !cookie.secure {
+        return None;
+    }
+    if name.starts_with("__Secure-") && !cookie.secure {
+        return None;
+    }
+    if name.starts_with("__Host-") && (!cookie.secure || !cookie.host_only || cookie.path != "/") {
+        return None;
+    }
+
+    Some(cookie)
+}
+
+/// Whether two URLs belong to the same site, as far as SameSite is concerned.
+///
+/// This compares the last two labels of the host, which is right for most domains but not for
+/// ones under multi-label public suffixes like `co.uk`.
+pub fn same_site(a: &Url, b: &Url) -> bool {
+    let site = |url: &Url| {
+        let host = host(url).unwrap_or_default();
+        if host.parse::<IpAddr>().is_ok() {
+            return host;
+        }
+        let labels: Vec<&str> = host.rsplitn(3, '.').collect();
+        labels
+            .iter()
+            .take(2)
+            .rev()
+            .copied()
+            .collect::<Vec<_>>()
+            .join(".")
+    };
+    site(a) == site(b)
+}
+
+// Lowercased, and without the brackets around an IPv6 address so it parses as an `IpAddr`
+fn host(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some(host.to_ascii_lowercase())
+}
+
+// Like browsers, loopback counts as secure even over plain http so local servers can be tested
+fn is_secure(url: &Url) -> bool {
+    matches!(url.scheme(), "https" | "wss")
+        || match url.host() {
+            Some(url::Host::Domain(domain)) => domain == "localhost",
+            Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        }
+}
+
+fn domain_match(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+// The directory of the request path, RFC 6265 section 5.1.4
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(slash) => url.path()[..slash].to_string(),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[test]
+fn test_cookie_rules() -> Result<(), Box<dyn Error>> {
+    let login = Url::parse("https://api.example.com/auth/login")?;
+    let mut jar = CookieJar::default();
+    jar.store(
+        &login,
+        "session=abc; Path=/; Domain=example.com; Secure; HttpOnly",
+    );
+    jar.store(&login, "csrf=xyz; SameSite=Strict; Secure");
+    jar.store(&login, "tracker=1; Domain=com");
+    jar.store(&login, "pref=dark; Max-Age=0");
+
+    let api = Url::parse("https://www.example.com/v1/items")?;
+    assert_eq!(
+        jar.header_for(&api, &Method::GET, false).as_deref(),
+        Some("session=abc")
+    );
+    assert_eq!(
+        jar.header_for(&Url::parse("http://www.example.com/")?, &Method::GET, false),
+        None
+    );
+
+    // csrf defaults to the /auth path and is host only
+    let auth = Url::parse("https://api.example.com/auth/refresh")?;
+    assert_eq!(
+        jar.header_for(&auth, &Method::POST, false).as_deref(),
+        Some("csrf=xyz; session=abc")
+    );
+    // Reached from another site, Strict cookies stay behind and Lax ones only go along on GET
+    assert_eq!(
+        jar.header_for(&auth, &Method::GET, true).as_deref(),
+        Some("session=abc")
+    );
+    assert_eq!(jar.header_for(&auth, &Method::POST, true), None);
+
+    // An IPv6 host is an address, so no Domain can widen its cookies
+    let local = Url::parse("http://[::1]:8080/")?;
+    assert_eq!(parse_set_cookie(&local, "a=1; Domain=::1", now()), None);
+    let cookie = parse_set_cookie(&local, "a=1", now()).ok_or("Cookie rejected")?;
+    assert_eq!(cookie.domain, "::1");
+
+    let path = std::env::temp_dir().join(format!("apigrok-cookies-{}.txt", std::process::id()));
+    jar.save(&path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+    }
+    fs::remove_file(path)?;
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{IsTerminal, stderr};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::{VerboseDetail, Verbosity};
+
+// Event targets, one per diagnostic section. Each one maps onto a `VerboseDetail` so the user can
+// pick which sections end up on stderr.
+pub const CONNECTION: &str = "apigrok::connection";
+pub const TLS: &str = "apigrok::tls";
+pub const HTTP: &str = "apigrok::http";
+pub const AUTH: &str = "apigrok::auth";
+pub const FRAMES: &str = "apigrok::frames";
+
+/// Installs the global subscriber.
+///
+/// Diagnostics always go to stderr so they never end up interleaved with a response body on
+/// stdout. When a trace file is given, every event is additionally written to it as one JSON
+/// object per line, regardless of the verbosity.
+pub fn init(
+    verbosity: Verbosity,
+    verbose_detail: &HashSet<VerboseDetail>,
+    trace_file: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let stderr_layer = tracing_subscriber::fmt::layer()
+        .with_writer(stderr)
+        .with_ansi(stderr().is_terminal())
+        .event_format(CurlStyle)
+        .with_filter(stderr_filter(verbosity, verbose_detail));
+
+    let file_layer = match trace_file {
+        Some(path) => {
+            let file = File::create(path)?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_current_span(false)
+                    .with_span_list(false)
+                    .with_writer(Mutex::new(file))
+                    .with_filter(Targets::new().with_target("apigrok", LevelFilter::TRACE)),
+            )
+        }
+        None => None,
+    };
+
+    Registry::default()
+        .with(stderr_layer)
+        .with(file_layer)
+        .try_init()?;
+
+    Ok(())
+}
+
+fn stderr_filter(verbosity: Verbosity, verbose_detail: &HashSet<VerboseDetail>) -> Targets {
+    // Warnings and errors are always worth showing, unless we've been asked to keep quiet
+    let baseline = match verbosity {
+        Verbosity::Quiet => LevelFilter::ERROR,
+        _ => LevelFilter::WARN,
+    };
+    let detailed = match verbosity {
+        Verbosity::Quiet | Verbosity::Normal => baseline,
+        Verbosity::Verbose => LevelFilter::INFO,
+        Verbosity::Debug => LevelFilter::TRACE,
+    };
+
+    let wants = |detail: VerboseDetail| {
+        verbose_detail.contains(&VerboseDetail::All) || verbose_detail.contains(&detail)
+    };
+    let level_for = |detail: VerboseDetail| if wants(detail) { detailed } else { baseline };
+
+    Targets::new()
+        .with_default(baseline)
+        .with_target(CONNECTION, level_for(VerboseDetail::Connection))
+        .with_target(TLS, level_for(VerboseDetail::Tls))
+        .with_target(HTTP, level_for(VerboseDetail::ResponseDetails))
+        .with_target(AUTH, level_for(VerboseDetail::Auth))
+        .with_target(FRAMES, level_for(VerboseDetail::Frames))
+}
+
+// Renders events the way curl renders its own diagnostics: a `*` marker, the message, and then
+// any structured fields.
+struct CurlStyle;
+
+impl<S, N> FormatEvent<S, N> for CurlStyle
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let marker = match *event.metadata().level() {
+            tracing::Level::ERROR => "!",
+            tracing::Level::WARN => "⚠️ ",
+            _ => "*",
+        };
+        write!(writer, "{} ", marker)?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+#[test]
+fn test_stderr_events() -> Result<(), Box<dyn Error>> {
+    use std::io::{self, Write};
+    use std::sync::Arc;
+    use tracing::Level;
+
+    let tls_only = stderr_filter(Verbosity::Verbose, &HashSet::from([VerboseDetail::Tls]));
+    assert!(tls_only.would_enable(TLS, &Level::INFO));
+    assert!(!tls_only.would_enable(TLS, &Level::DEBUG));
+    assert!(!tls_only.would_enable(CONNECTION, &Level::INFO));
+    assert!(tls_only.would_enable(CONNECTION, &Level::WARN));
+    let quiet = stderr_filter(Verbosity::Quiet, &HashSet::from([VerboseDetail::All]));
+    assert!(!quiet.would_enable(HTTP, &Level::WARN));
+    assert!(quiet.would_enable(HTTP, &Level::ERROR));
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .event_format(CurlStyle)
+        .with_filter(tls_only);
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(target: TLS, depth = 0, "Peer certificate");
+        tracing::info!(target: CONNECTION, port = 443, "Connected");
+        tracing::warn!(target: HTTP, "Too many redirects, stopping");
+    });
+    assert_eq!(
+        String::from_utf8(captured.0.lock().unwrap().clone())?,
+        "* Peer certificate depth=0\n⚠️  Too many redirects, stopping\n"
+    );
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! Saving response bodies straight to disk, with a progress bar, resuming and partial fetches
+
+use std::error::Error;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use hyper::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_RANGE, LAST_MODIFIED};
+use hyper::header::{HeaderValue, RANGE};
+use hyper::{HeaderMap, StatusCode};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tracing::info;
+use url::Url;
+
+use crate::diagnostics::HTTP;
+
+/// Where `--continue-at` picks up from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContinueAt {
+    /// The end of whatever is already on disk
+    End,
+    Offset(u64),
+}
+
+impl ContinueAt {
+    /// Parses `-` or a byte offset
+    pub fn parse(value: &str) -> Result<ContinueAt, Box<dyn Error>> {
+        match value {
+            "-" => Ok(ContinueAt::End),
+            offset => match offset.parse() {
+                Ok(offset) => Ok(ContinueAt::Offset(offset)),
+                Err(_) => {
+                    Err(format!("Expected --continue-at as - or an offset, not {}", offset).into())
+                }
+            },
+        }
+    }
+}
+
+/// A response body to write to a file rather than hold in memory
+#[derive(Debug, Clone)]
+pub struct Download {
+    path: PathBuf,
+    /// Bytes already on disk to append to, 0 when starting over
+    offset: u64,
+    /// A `--range` to fetch instead of the whole resource
+    range: Option<String>,
+    /// What the server said about the resource when the partial file was started
+    validators: Option<Validators>,
+    progress: bool,
+}
+
+// Kept next to a partial download so a resume can ask for the same version with If-Range
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Download {
+    pub fn new(path: PathBuf, progress: bool) -> Download {
+        Download {
+            path,
+            offset: 0,
+            range: None,
+            validators: None,
+            progress,
+        }
+    }
+
+    /// The file name a URL's last path segment suggests, as `--remote-name` uses
+    pub fn remote_name(url: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let url = Url::parse(url)?;
+        let name = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|name| !name.is_empty())
+            .ok_or("The URL has no file name to save to, use --output-file instead")?;
+        let name = percent_encoding::percent_decode_str(name).decode_utf8()?;
+
+        // Only ever write into the current directory, whatever the URL says
+        match Path::new(name.as_ref()).file_name() {
+            Some(name) => Ok(PathBuf::from(name)),
+            None => Err(format!("Can't save to {}, use --output-file instead", name).into()),
+        }
+    }
+
+    /// Resumes a partial file rather than starting over
+    pub fn continue_at(mut self, continue_at: ContinueAt) -> Result<Download, Box<dyn Error>> {
+        let on_disk = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        self.offset = match continue_at {
+            ContinueAt::End => on_disk,
+            ContinueAt::Offset(offset) if offset <= on_disk => offset,
+            ContinueAt::Offset(offset) => {
+                return Err(format!(
+                    "Can't continue at {}, {} only has {} bytes",
+                    offset,
+                    self.path.display(),
+                    on_disk
+                )
+                .into());
+            }
+        };
+        self.validators = fs::read(sidecar(&self.path))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok());
+        Ok(self)
+    }
+
+    /// Fetches only part of the resource, given as in a `Range` header without the unit
+    pub fn range(mut self, range: &str) -> Download {
+        self.range = Some(range.to_string());
+        self
+    }
+
+    /// Adds the `Range` and `If-Range` headers resuming or a partial fetch needs
+    pub fn apply(&self, headers: &mut HeaderMap) -> Result<(), Box<dyn Error>> {
+        if let Some(range) = &self.range {
+            headers.insert(RANGE, format!("bytes={}", range).parse()?);
+        } else if self.offset > 0 {
+            headers.insert(RANGE, format!("bytes={}-", self.offset).parse()?);
+
+            // Only a strong ETag guarantees the bytes we have match the ones we'll get
+            let validator = self.validators.as_ref().and_then(|validators| {
+                validators
+                    .etag
+                    .clone()
+                    .filter(|etag| !etag.starts_with("W/"))
+                    .or(validators.last_modified.clone())
+            });
+            if let Some(validator) = validator {
+                headers.insert(IF_RANGE, HeaderValue::from_str(&validator)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a response with this status should go to the file
+    pub fn wants(&self, status: StatusCode) -> bool {
+        status.is_success() || (status == StatusCode::RANGE_NOT_SATISFIABLE && self.offset > 0)
+    }
+
+    /// Streams the body to the file, checking a partial response lines up with what's on disk
+    pub async fn save<B>(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        mut body: B,
+    ) -> Result<u64, Box<dyn Error>>
+    where
+        B: Body<Data = Bytes, Error = Box<dyn Error + Send + Sync>> + Unpin,
+    {
+        let content_range = headers
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(ContentRange::parse);
+
+        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+            return match content_range.and_then(|range| range.total) {
+                Some(total) if total == self.offset => {
+                    eprintln!("{} is already complete", self.path.display());
+                    let _ = fs::remove_file(sidecar(&self.path));
+                    Ok(0)
+                }
+                _ => Err(format!(
+                    "The server can't continue {} at byte {}",
+                    self.path.display(),
+                    self.offset
+                )
+                .into()),
+            };
+        }
+
+        let append = match (status, &content_range) {
+            (StatusCode::PARTIAL_CONTENT, Some(ContentRange { range, .. })) => {
+                let start = range.map(|(start, _)| start);
+                let expected = match &self.range {
+                    Some(range) => range_start(range),
+                    None => Some(self.offset),
+                };
+                if expected.is_some() && start != expected {
+                    return Err(format!(
+                        "The server sent a range starting at {}, expected {}",
+                        start.map_or("*".to_string(), |start| start.to_string()),
+                        expected.unwrap_or_default()
+                    )
+                    .into());
+                }
+                self.range.is_none()
+            }
+            // Several ranges come back as multipart/byteranges, which is saved as it is
+            (StatusCode::PARTIAL_CONTENT, None) if is_byteranges(headers) => false,
+            (StatusCode::PARTIAL_CONTENT, None) => {
+                return Err("The server sent a 206 without a Content-Range".into());
+            }
+            _ if self.range.is_some() => {
+                eprintln!("⚠️  The server ignored the range, saving the whole body");
+                false
+            }
+            _ if self.offset > 0 => {
+                eprintln!("⚠️  The server sent the whole file, starting over");
+                false
+            }
+            _ => false,
+        };
+        let offset = if append { self.offset } else { 0 };
+
+        // Leave a note of which version we're fetching, in case we get interrupted
+        let validators = Validators {
+            etag: header(headers, ETAG),
+            last_modified: header(headers, LAST_MODIFIED),
+        };
+        if self.range.is_none() {
+            fs::write(sidecar(&self.path), serde_json::to_vec(&validators)?)?;
+        }
+
+        let length = match &content_range {
+            Some(ContentRange {
+                total: Some(total), ..
+            }) if append => Some(*total),
+            _ => header(headers, CONTENT_LENGTH)
+                .and_then(|length| length.parse::<u64>().ok())
+                .map(|length| offset + length),
+        };
+        let progress = self.progress_bar(length, offset);
+
+        info!(target: HTTP, path = %self.path.display(), offset, "Saving body");
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(!append)
+            .open(&self.path)
+            .await?;
+        // The offset may be short of the end, anything past it is about to be fetched again
+        if append {
+            file.set_len(offset).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        let mut file = BufWriter::new(file);
+
+        let mut written = 0;
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|err| err as Box<dyn Error>)?;
+            if let Ok(data) = frame.into_data() {
+                file.write_all(&data).await?;
+                written += data.len() as u64;
+                progress.inc(data.len() as u64);
+            }
+        }
+        file.flush().await?;
+        progress.finish_and_clear();
+
+        if self.range.is_none() {
+            fs::remove_file(sidecar(&self.path))?;
+        }
+        match append {
+            true => eprintln!(
+                "Saved {} more bytes to {} ({} in total)",
+                written,
+                self.path.display(),
+                offset + written
+            ),
+            false => eprintln!("Saved {} bytes to {}", written, self.path.display()),
+        }
+        Ok(written)
+    }
+
+    fn progress_bar(&self, length: Option<u64>, position: u64) -> ProgressBar {
+        if !self.progress {
+            return ProgressBar::hidden();
+        }
+
+        // indicatif stays quiet on its own when stderr isn't a terminal
+        let progress = ProgressBar::with_draw_target(length, ProgressDrawTarget::stderr());
+        let template = match length {
+            Some(_) => {
+                "{spinner} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta}"
+            }
+            None => "{spinner} {bytes} {binary_bytes_per_sec}",
+        };
+        if let Ok(style) = ProgressStyle::with_template(template) {
+            progress.set_style(style.progress_chars("=> "));
+        }
+        progress.set_position(position);
+        progress.enable_steady_tick(Duration::from_millis(100));
+        progress
+    }
+}
+
+/// A parsed `Content-Range: bytes start-end/total`, either side may be `*`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ContentRange {
+    range: Option<(u64, u64)>,
+    total: Option<u64>,
+}
+
+impl ContentRange {
+    fn parse(value: &str) -> Option<ContentRange> {
+        let (unit, rest) = value.trim().split_once(' ')?;
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let (range, total) = rest.trim().split_once('/')?;
+
+        let range = match range {
+            "*" => None,
+            range => {
+                let (start, end) = range.split_once('-')?;
+                Some((start.parse().ok()?, end.parse().ok()?))
+            }
+        };
+        let total = match total {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        Some(ContentRange { range, total })
+    }
+}
+
+// Suffix ranges (`-500`) count from the end, so there's no start to check against
+fn range_start(range: &str) -> Option<u64> {
+    range.split_once('-')?.0.trim().parse().ok()
+}
+
+fn is_byteranges(headers: &HeaderMap) -> bool {
+    header(headers, CONTENT_TYPE).is_some_and(|value| value.starts_with("multipart/byteranges"))
+}
+
+fn header(headers: &HeaderMap, name: hyper::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn sidecar(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".resume");
+    PathBuf::from(name)
+}
+
+#[test]
+fn test_content_range() {
+    assert_eq!(
+        ContentRange::parse("bytes 100-199/1000"),
+        Some(ContentRange {
+            range: Some((100, 199)),
+            total: Some(1000)
+        })
+    );
+    assert_eq!(
+        ContentRange::parse("bytes */1000"),
+        Some(ContentRange {
+            range: None,
+            total: Some(1000)
+        })
+    );
+    assert_eq!(ContentRange::parse("items 0-1/2"), None);
+    assert_eq!(range_start("500-999"), Some(500));
+    assert_eq!(range_start("-500"), None);
+    assert_eq!(
+        Download::remote_name("https://example.com/files/a%20b.tar.gz?x=1").ok(),
+        Some(PathBuf::from("a b.tar.gz"))
+    );
+    assert!(Download::remote_name("https://example.com/").is_err());
+}
+
+#[tokio::test]
+async fn test_resume_before_the_end() -> Result<(), Box<dyn Error>> {
+    use http_body_util::Full;
+
+    let path = std::env::temp_dir().join(format!("apigrok-resume-{}", std::process::id()));
+    fs::write(&path, b"0123456789")?;
+    let download = Download::new(path.clone(), false).continue_at(ContinueAt::Offset(4))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes 4-7/8"));
+    let body = Full::new(Bytes::from_static(b"WXYZ"))
+        .map_err(|never| -> Box<dyn Error + Send + Sync> { match never {} });
+    download
+        .save(StatusCode::PARTIAL_CONTENT, &headers, body)
+        .await?;
+    assert_eq!(fs::read(&path)?, b"0123WXYZ");
+
+    fs::remove_file(path)?;
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! HTML form bodies: `multipart/form-data` (RFC 7578) and `application/x-www-form-urlencoded`
+
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use futures_util::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+
+/// One `--form` field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Text {
+        name: String,
+        value: String,
+    },
+    File {
+        name: String,
+        path: PathBuf,
+        filename: String,
+        content_type: String,
+    },
+}
+
+impl Field {
+    /// Parses `name=value`, or a file as `name=@path` or `name@path`, with optional `;type=mime`
+    /// and `;filename=name`
+    ///
+    /// The name runs to the first `=` unless that's inside a file's attributes, so it may contain
+    /// `@`; names holding `=` or `;` can't be given.
+    pub fn parse(value: &str) -> Result<Field, Box<dyn Error>> {
+        let (name, rest) = match value.split_once('=') {
+            Some((name, rest)) if !name.contains(';') => match rest.strip_prefix('@') {
+                Some(file) => (name, file),
+                None => {
+                    return Ok(Field::Text {
+                        name: name.to_string(),
+                        value: rest.to_string(),
+                    });
+                }
+            },
+            _ => value
+                .split_once('@')
+                .ok_or("Expected --form as name=value or name@path")?,
+        };
+        let name = name.to_string();
+
+        let mut attributes = rest.split(';');
+        let path = PathBuf::from(attributes.next().unwrap_or_default());
+        let mut filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut content_type = mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string();
+
+        for attribute in attributes {
+            match attribute.split_once('=') {
+                // It goes into the part's headers as it is
+                Some(("type", value)) if value.chars().any(char::is_control) => {
+                    return Err(format!("Invalid --form type: {:?}", value).into());
+                }
+                Some(("type", value)) => content_type = value.to_string(),
+                Some(("filename", value)) => filename = value.to_string(),
+                _ => return Err(format!("Unknown --form attribute: {}", attribute).into()),
+            }
+        }
+
+        Ok(Field::File {
+            name,
+            path,
+            filename,
+            content_type,
+        })
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Field::Text { name, .. } | Field::File { name, .. } => name,
+        }
+    }
+}
+
+/// What verbose output shows for a form field, instead of the encoded body
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+}
+
+/// Summarizes the fields as they'll be sent
+pub fn parts(fields: &[Field]) -> io::Result<Vec<FormPart>> {
+    fields
+        .iter()
+        .map(|field| {
+            Ok(match field {
+                Field::Text { name, value } => FormPart {
+                    name: name.clone(),
+                    filename: None,
+                    content_type: None,
+                    size: value.len() as u64,
+                },
+                Field::File {
+                    name,
+                    path,
+                    filename,
+                    content_type,
+                } => FormPart {
+                    name: name.clone(),
+                    filename: Some(filename.clone()),
+                    content_type: Some(content_type.clone()),
+                    size: fs::metadata(path)?.len(),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Encodes the fields as a classic form, with files contributing their contents as the value
+pub fn urlencoded(fields: &[Field]) -> io::Result<String> {
+    let mut form = url::form_urlencoded::Serializer::new(String::new());
+    for field in fields {
+        match field {
+            Field::Text { name, value } => form.append_pair(name, value),
+            Field::File { name, path, .. } => form.append_pair(name, &fs::read_to_string(path)?),
+        };
+    }
+    Ok(form.finish())
+}
+
+/// A `multipart/form-data` body that streams files from disk instead of reading them up front
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multipart {
+    boundary: String,
+    fields: Vec<Field>,
+}
+
+// The body is a sequence of literal chunks and files to copy in between them
+enum Segment {
+    Bytes(Bytes),
+    File(PathBuf),
+}
+
+impl Multipart {
+    pub fn new(fields: Vec<Field>) -> Multipart {
+        let mut random = [0u8; 12];
+        rand::rng().fill_bytes(&mut random);
+        let boundary = random
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        Multipart {
+            boundary: format!("apigrok-{}", boundary),
+            fields,
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// The exact body size, so it can go out with a `Content-Length` rather than chunked
+    pub fn content_length(&self) -> io::Result<u64> {
+        self.segments()
+            .iter()
+            .map(|segment| match segment {
+                Segment::Bytes(bytes) => Ok(bytes.len() as u64),
+                Segment::File(path) => Ok(fs::metadata(path)?.len()),
+            })
+            .sum()
+    }
+
+    /// The body, read lazily; each call starts a fresh stream so the body can be sent again
+    pub fn stream(&self) -> BoxStream<'static, io::Result<Bytes>> {
+        stream::iter(self.segments())
+            .flat_map(|segment| match segment {
+                Segment::Bytes(bytes) => stream::once(async move { Ok(bytes) }).boxed(),
+                Segment::File(path) => stream::once(async move { File::open(path).await })
+                    .map_ok(ReaderStream::new)
+                    .try_flatten()
+                    .boxed(),
+            })
+            .boxed()
+    }
+
+    fn segments(&self) -> Vec<Segment> {
+        let mut segments = vec![];
+        for field in &self.fields {
+            let mut head = format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+                self.boundary,
+                escape(field.name())
+            );
+            match field {
+                Field::Text { value, .. } => {
+                    head.push_str("\r\n\r\n");
+                    head.push_str(value);
+                    segments.push(Segment::Bytes(Bytes::from(head)));
+                }
+                Field::File {
+                    path,
+                    filename,
+                    content_type,
+                    ..
+                } => {
+                    head.push_str(&format!(
+                        "; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                        escape(filename),
+                        content_type
+                    ));
+                    segments.push(Segment::Bytes(Bytes::from(head)));
+                    segments.push(Segment::File(path.clone()));
+                }
+            }
+            segments.push(Segment::Bytes(Bytes::from_static(b"\r\n")));
+        }
+        segments.push(Segment::Bytes(Bytes::from(format!(
+            "--{}--\r\n",
+            self.boundary
+        ))));
+        segments
+    }
+}
+
+// Quotes and line breaks in names are percent-encoded, as the HTML spec does
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[test]
+fn test_multipart_body() -> Result<(), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("apigrok-form-{}.txt", std::process::id()));
+    fs::write(&path, "hello")?;
+
+    let multipart = Multipart {
+        boundary: "b".to_string(),
+        fields: vec![
+            Field::parse("title=Report")?,
+            Field::parse(&format!("doc@{};type=text/plain", path.display()))?,
+        ],
+    };
+    let filename = path.file_name().unwrap().to_string_lossy();
+
+    let body = tokio::runtime::Runtime::new()?.block_on(multipart.stream().try_fold(
+        vec![],
+        |mut body, chunk| async move {
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        },
+    ))?;
+    assert_eq!(
+        String::from_utf8(body.clone())?,
+        format!(
+            "--b\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nReport\r\n\
+         --b\r\nContent-Disposition: form-data; name=\"doc\"; filename=\"{}\"\r\n\
+         Content-Type: text/plain\r\n\r\nhello\r\n--b--\r\n",
+            filename
+        )
+    );
+    assert_eq!(multipart.content_length()?, body.len() as u64);
+
+    // Names may hold `@`, types may not hold line breaks
+    assert_eq!(
+        Field::parse("user@example.com=yes")?,
+        Field::Text {
+            name: "user@example.com".to_string(),
+            value: "yes".to_string()
+        }
+    );
+    assert!(matches!(
+        Field::parse(&format!("to@x=@{}", path.display()))?,
+        Field::File { name, .. } if name == "to@x"
+    ));
+    assert!(Field::parse("doc@a.txt;type=text/plain\r\nX-Injected: 1").is_err());
+
+    fs::remove_file(path)?;
+    Ok(())
+}
@@ -1,18 +1,57 @@
+mod auth;
+// The reusable client library isn't wired into the CLI yet
+#[allow(dead_code)]
 mod clients;
 mod color;
+mod cookies;
+mod diagnostics;
+mod download;
+mod forms;
+mod output;
 mod protocols;
+mod render;
 
+use crate::auth::jwt::{self, Jwt, JwtCommand};
+use crate::auth::oauth2::OAuth2Options;
+use crate::auth::sigv4::{SigV4, SignatureLocation};
+use crate::auth::{ApiKey, ApiKeyLocation, Auth, AuthType};
+use crate::color::Token;
 use crate::color::request_output;
 use crate::color::response_output;
+use crate::cookies::CookieJar;
+use crate::diagnostics::{AUTH, HTTP};
+use crate::download::{ContinueAt, Download};
+use crate::forms::Field;
 use crate::protocols::ApiRequest;
-use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use base64::{Engine, engine::general_purpose};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::{Shell, generate};
 use hyper::Method;
-use protocols::{ApiProtocol, ApiResponse, Protocol};
+use hyper::header::{ACCEPT, COOKIE, HeaderName, HeaderValue, RANGE};
+use output::OutputFormat;
+use protocols::graphql::{self, introspection};
+use protocols::grpc::schema::Schema;
+use protocols::grpc::stream::{self, Source};
+use protocols::grpc::{self, Reply, Wire, health, reflection};
+use protocols::http::Http2Settings;
+use protocols::sse::{Session, StopAt};
+use protocols::websockets::{self, Handshake, Outgoing, Transcript, WebSocket};
+use protocols::{ApiCall, ApiCallBuilder, ApiProtocol, ApiResponse};
+use regex::Regex;
+use render::RenderOptions;
+use render::image::ImagePreview;
 use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::Debug;
-use std::io;
+use std::io::{self, IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+// Not among hyper's well-known headers
+const LAST_EVENT_ID: HeaderName = HeaderName::from_static("last-event-id");
 
 #[derive(Parser)]
 #[command(name = "apigrok")]
@@ -39,6 +78,284 @@ struct Cli {
     /// Specifies which verbose sections should be included
     #[arg(short('d'), long, value_enum, default_values = [ "all"])]
     verbose_detail: Vec<VerboseDetail>,
+
+    /// Output format for the exchange
+    #[arg(short('o'), long, value_enum, default_value = "text")]
+    output: OutputFormat,
+
+    /// Print the body as it arrives, flushing each chunk, instead of rendering it at the end
+    #[arg(long)]
+    stream: bool,
+
+    /// Number of spaces to indent structured bodies with
+    #[arg(long, value_name = "SPACES", default_value_t = 2)]
+    indent: usize,
+
+    /// Print JSON object keys in sorted order instead of the order received
+    #[arg(long)]
+    sort_keys: bool,
+
+    /// How to preview image bodies inline in the terminal
+    #[arg(long, value_enum, default_value = "auto")]
+    image_preview: ImagePreview,
+
+    /// Number of bytes of a binary body to hexdump, 0 for everything
+    #[arg(long, value_name = "BYTES", default_value_t = 512)]
+    hexdump_limit: usize,
+
+    /// Stream the response body to this file instead of printing it
+    #[arg(long, value_name = "FILE")]
+    output_file: Option<PathBuf>,
+
+    /// Save the response body under the file name from the URL
+    #[arg(short('O'), long, conflicts_with = "output_file")]
+    remote_name: bool,
+
+    /// Resume a download at this byte offset, or `-` to continue where the file ends
+    #[arg(short('C'), long, value_name = "OFFSET|-", conflicts_with = "range")]
+    continue_at: Option<String>,
+
+    /// Fetch only these bytes, e.g. `0-499`, `500-` or `-500`
+    #[arg(short('r'), long, value_name = "RANGE", allow_hyphen_values = true)]
+    range: Option<String>,
+
+    /// Write every diagnostic event to this file as JSON lines
+    #[arg(long, value_name = "FILE")]
+    trace_file: Option<PathBuf>,
+
+    /// Credentials to send, `user:password` or a token for bearer auth
+    #[arg(long, value_name = "CREDENTIALS")]
+    auth: Option<String>,
+
+    /// How to send the `--auth` credentials
+    #[arg(long, value_enum, default_value = "basic")]
+    auth_type: AuthType,
+
+    /// A static API key to send, as `name=value`
+    #[arg(long, value_name = "NAME=VALUE")]
+    api_key: Option<String>,
+
+    /// Where to send the `--api-key`
+    #[arg(long, value_enum, default_value = "header")]
+    api_key_in: ApiKeyLocation,
+
+    /// Sign requests with AWS Signature V4 for the given `service:region`
+    #[arg(long, value_name = "SERVICE:REGION")]
+    aws_sigv4: Option<String>,
+
+    /// Where to put the AWS signature
+    #[arg(long, value_enum, default_value = "header")]
+    aws_sigv4_in: SignatureLocation,
+
+    /// A form field to send, `name=value` or a file as `name=@path;type=mime;filename=name`,
+    /// `name@path` for short
+    #[arg(short('F'), long, value_name = "FIELD")]
+    form: Vec<String>,
+
+    /// Send the `--form` fields urlencoded instead of as multipart/form-data
+    #[arg(long)]
+    form_urlencoded: bool,
+
+    /// Cookies to send, inline as `name=value; name2=value2` or read from a cookie file
+    #[arg(long, value_name = "DATA|FILE")]
+    cookie: Option<String>,
+
+    /// Cookie file to load before the request and save received cookies to afterwards
+    #[arg(long, value_name = "FILE")]
+    cookie_jar: Option<PathBuf>,
+
+    /// Follow redirects
+    #[arg(short('L'), long)]
+    location: bool,
+
+    /// The most redirects to follow with `--location`
+    #[arg(long, value_name = "NUM", default_value_t = 10)]
+    max_redirects: usize,
+
+    #[command(flatten)]
+    oauth2: OAuth2Options,
+}
+
+impl Cli {
+    // Starts a call with the options shared by every request
+    async fn call(
+        &self,
+        method: Method,
+        url: &str,
+        cookie_jar: &Arc<Mutex<CookieJar>>,
+    ) -> Result<ApiCallBuilder, Box<dyn Error>> {
+        let mut builder = ApiCall::builder(method, url)
+            .cookie_jar(Arc::clone(cookie_jar))
+            // Structured output formats need the whole body
+            .stream(self.stream && self.output == OutputFormat::Text);
+        if self.location {
+            builder = builder.follow_redirects(self.max_redirects);
+        }
+        if !self.form.is_empty() {
+            let fields = self
+                .form
+                .iter()
+                .map(|field| Field::parse(field))
+                .collect::<Result<_, _>>()?;
+            builder = builder.form(fields, self.form_urlencoded);
+        }
+        if let Some(download) = self.download(url)? {
+            builder = builder.download(download);
+        } else if let Some(range) = &self.range {
+            builder = builder.header(RANGE, format!("bytes={}", range).parse::<HeaderValue>()?);
+        }
+        if let Some(cookie) = self.cookie.as_ref().filter(|cookie| cookie.contains('=')) {
+            builder = builder.header(COOKIE, HeaderValue::from_str(cookie)?);
+        }
+
+        let auth = match &self.auth {
+            Some(auth) => Some(Auth::parse(auth, self.auth_type)?),
+            None => None,
+        };
+        if self.oauth2.oauth2.is_some() {
+            builder = builder.auth(self.oauth2.authenticate(auth.as_ref()).await?);
+        } else if let Some(auth) = auth {
+            builder = builder.auth(auth);
+        }
+        if let Some(api_key) = &self.api_key {
+            builder = builder.api_key(ApiKey::parse(api_key, self.api_key_in)?);
+        }
+        if let Some(aws_sigv4) = &self.aws_sigv4 {
+            builder = builder.aws_sigv4(SigV4::parse(aws_sigv4, self.aws_sigv4_in)?);
+        }
+        Ok(builder)
+    }
+
+    // Where the body goes when it's saved rather than printed
+    fn download(&self, url: &str) -> Result<Option<Download>, Box<dyn Error>> {
+        let path = match (&self.output_file, self.remote_name) {
+            (Some(path), _) => path.clone(),
+            (None, true) => Download::remote_name(url)?,
+            (None, false) if self.continue_at.is_some() => {
+                return Err("--continue-at needs --output-file or --remote-name".into());
+            }
+            (None, false) => return Ok(None),
+        };
+
+        let mut download = Download::new(path, self.verbose != Verbosity::Quiet);
+        if let Some(continue_at) = &self.continue_at {
+            download = download.continue_at(ContinueAt::parse(continue_at)?)?;
+        }
+        if let Some(range) = &self.range {
+            download = download.range(range);
+        }
+        Ok(Some(download))
+    }
+
+    // Starts a gRPC call, with the metadata given on the command line
+    async fn grpc_call(
+        &self,
+        url: &str,
+        headers: &[String],
+        cookie_jar: &Arc<Mutex<CookieJar>>,
+    ) -> Result<ApiCallBuilder, Box<dyn Error>> {
+        let mut builder = self
+            .call(Method::POST, url, cookie_jar)
+            .await?
+            // The status comes in the trailers, after the whole body
+            .stream(false);
+        for header in headers {
+            let (name, value) = parse_header(header)?;
+            builder = builder.header(name, value);
+        }
+        Ok(builder)
+    }
+
+    // The server's services, from the .proto files given or from the server itself
+    async fn grpc_schema(
+        &self,
+        url: &str,
+        options: &GrpcSchema,
+        symbol: Option<&str>,
+        cookie_jar: &Arc<Mutex<CookieJar>>,
+    ) -> Result<Schema, Box<dyn Error>> {
+        if !options.protos.is_empty() {
+            return Schema::load(&options.protos, &options.import_paths);
+        }
+
+        let connect =
+            |url: String| async move { self.grpc_call(&url, &options.headers, cookie_jar).await };
+        let client = options.client();
+        reflection::schema(
+            &client,
+            options.wire(),
+            url,
+            connect,
+            options.refresh,
+            symbol,
+        )
+        .await
+    }
+
+    // Sends a GraphQL query and reads the reply, printing the exchange instead when the server
+    // didn't answer in GraphQL
+    async fn graphql(
+        &self,
+        url: &str,
+        query: &graphql::Query,
+        endpoint: &GraphqlEndpoint,
+        cookie_jar: &Arc<Mutex<CookieJar>>,
+        presentation: &Presentation,
+    ) -> Result<(ApiRequest, ApiResponse, graphql::Reply), Box<dyn Error>> {
+        let mut builder = match endpoint.get {
+            true => self.call(Method::GET, &query.url(url)?, cookie_jar).await?,
+            false => self
+                .call(Method::POST, url, cookie_jar)
+                .await?
+                .body(graphql::CONTENT_TYPE, query.body()?),
+        }
+        // The reply is parsed as a whole
+        .stream(false)
+        .header(ACCEPT, HeaderValue::from_static(graphql::ACCEPT));
+        for header in &endpoint.headers {
+            let (name, value) = parse_header(header)?;
+            builder = builder.header(name, value);
+        }
+
+        let client = protocols::http::HttpClient {
+            version: protocols::http::HttpVersion::Http1,
+        };
+        let (request, mut response) = client.execute(builder.build()?).await?;
+        match graphql::Reply::read(&response) {
+            Ok(reply) => Ok((request, response, reply)),
+            Err(error) => {
+                render_exchange(&request, &mut response, presentation).await?;
+                Err(error)
+            }
+        }
+    }
+
+    // The server's schema, from running the introspection query
+    async fn graphql_schema(
+        &self,
+        url: &str,
+        endpoint: &GraphqlEndpoint,
+        cookie_jar: &Arc<Mutex<CookieJar>>,
+        presentation: &Presentation,
+    ) -> Result<introspection::Schema, Box<dyn Error>> {
+        let query = introspection::query();
+        let (_, _, reply) = self
+            .graphql(url, &query, endpoint, cookie_jar, presentation)
+            .await?;
+        introspection::Schema::read(reply)
+    }
+
+    // Starts from the cookie file and jar when there are any, so sessions carry across runs
+    fn cookie_jar(&self) -> Result<CookieJar, Box<dyn Error>> {
+        let mut jar = CookieJar::default();
+        if let Some(path) = self.cookie_jar.as_ref().filter(|path| path.exists()) {
+            jar.extend(CookieJar::load(path)?);
+        }
+        if let Some(cookie) = self.cookie.as_ref().filter(|cookie| !cookie.contains('=')) {
+            jar.extend(CookieJar::load(Path::new(cookie))?);
+        }
+        Ok(jar)
+    }
 }
 
 #[derive(Subcommand)]
@@ -65,18 +382,272 @@ enum Commands {
         url: String,
     },
 
-    /// Perform a gRPC request
-    Grpc {
-        #[arg(value_enum)]
-        method: Method,
+    /// Listen to a Server-Sent Events stream, reconnecting when it drops
+    Sse {
+        url: String,
+
+        /// Stop after this many events
+        #[arg(long, value_name = "NUM")]
+        max_events: Option<usize>,
 
+        /// Stop after the first event whose data matches
+        #[arg(long, value_name = "REGEX")]
+        until: Option<Regex>,
+    },
+
+    /// Open a WebSocket, send messages and print what comes back
+    Ws {
         url: String,
+
+        /// Subprotocol to offer, in order of preference
+        #[arg(long = "subprotocol", value_name = "NAME")]
+        subprotocols: Vec<String>,
+
+        /// Extra handshake header, as `Name: value`
+        #[arg(short('H'), long = "header", value_name = "HEADER")]
+        headers: Vec<String>,
+
+        /// Message to send once connected
+        #[arg(short('m'), long = "message", value_name = "TEXT")]
+        messages: Vec<String>,
+
+        /// Send the contents of this file as one message
+        #[arg(long, value_name = "FILE")]
+        file: Option<PathBuf>,
+
+        /// Send each line read from stdin as a message
+        #[arg(long)]
+        stdin: bool,
+
+        /// Send messages as binary frames rather than text
+        #[arg(long)]
+        binary: bool,
+
+        /// Close after this many seconds without traffic once everything is sent
+        #[arg(long, value_name = "SECONDS")]
+        wait: Option<f64>,
+
+        /// Offer permessage-deflate compression
+        #[arg(long)]
+        deflate: bool,
+
+        /// Try WebSockets over HTTP/2 (RFC 8441) first, falling back to an HTTP/1.1 Upgrade
+        #[arg(long)]
+        http2: bool,
+
+        /// Type messages at a prompt, with history and /commands
+        #[arg(short('i'), long, conflicts_with_all = ["messages", "file", "stdin", "wait"])]
+        interactive: bool,
+    },
+
+    /// Call a gRPC method with a JSON request, or list and describe what a server offers
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Grpc {
+        #[command(subcommand)]
+        command: Option<GrpcCommand>,
+
+        /// The server, e.g. `http://localhost:50051`
+        #[arg(required = true)]
+        url: Option<String>,
+
+        /// The method to call, as `package.Service/Method`
+        #[arg(required = true)]
+        method: Option<String>,
+
+        /// The request message as JSON, `@file` to read it from a file or `@-` from stdin
+        ///
+        /// Client-streaming methods send every JSON document given, reading stdin line by line.
+        #[arg(short('d'), long, value_name = "JSON", default_value = "{}")]
+        data: String,
+
+        /// Type the messages of a client-streaming call at a prompt
+        #[arg(short('i'), long, conflicts_with = "data")]
+        interactive: bool,
+
+        /// Call a unary method without a schema: -d gives the request's protobuf bytes as
+        /// `@file` or `@-`, and the response is decoded by guessing
+        #[arg(long, conflicts_with = "interactive")]
+        raw: bool,
+
+        /// HTTP/2 flow-control window for each stream, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = 65_535)]
+        window_size: u32,
+
+        /// HTTP/2 flow-control window for the whole connection, in bytes
+        #[arg(long, value_name = "BYTES", default_value_t = 1_048_576)]
+        connection_window_size: u32,
+
+        /// Let HTTP/2 grow the windows to fit the connection instead
+        #[arg(long)]
+        adaptive_window: bool,
+
+        #[command(flatten)]
+        schema: GrpcSchema,
+    },
+
+    /// Run a GraphQL query, or explore a server's schema through introspection
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Graphql {
+        #[command(subcommand)]
+        command: Option<GraphqlCommand>,
+
+        /// The endpoint, e.g. `https://example.com/graphql`
+        #[arg(required = true)]
+        url: Option<String>,
+
+        /// The query, inline, as `@file.graphql` or as `@-` to read it from stdin
+        #[arg(required = true)]
+        query: Option<String>,
+
+        /// The variables as a JSON object, or `@file` to read them from a file
+        #[arg(long, value_name = "JSON")]
+        variables: Option<String>,
+
+        /// Which operation to run, when the query holds more than one
+        #[arg(long, value_name = "NAME")]
+        operation_name: Option<String>,
+
+        #[command(flatten)]
+        endpoint: GraphqlEndpoint,
+    },
+
+    /// Decode, verify or mint JSON Web Tokens
+    Jwt {
+        #[command(subcommand)]
+        command: JwtCommand,
     },
 
     /// Generate autocompletion scripts
     Completion { shell: Shell },
 }
 
+#[derive(Subcommand)]
+enum GrpcCommand {
+    /// List the services a server offers, or the methods of one of them
+    List {
+        url: String,
+
+        service: Option<String>,
+
+        #[command(flatten)]
+        schema: GrpcSchema,
+    },
+
+    /// Show how a service, method, message or enum is declared
+    Describe {
+        url: String,
+
+        symbol: String,
+
+        #[command(flatten)]
+        schema: GrpcSchema,
+    },
+
+    /// Ask whether the server, or one of its services, is serving
+    ///
+    /// Exits with 0 when serving, 2 when not, 3 for a service the server doesn't know, 4 when
+    /// it can't tell and 1 when the check itself fails.
+    Health {
+        url: String,
+
+        /// The service to ask about, rather than the server as a whole
+        service: Option<String>,
+
+        /// Keep printing the status each time it changes, until the server ends the call
+        #[arg(long)]
+        watch: bool,
+
+        #[command(flatten)]
+        schema: GrpcSchema,
+    },
+}
+
+#[derive(Subcommand)]
+enum GraphqlCommand {
+    /// Print the server's schema as SDL
+    Schema {
+        url: String,
+
+        #[command(flatten)]
+        endpoint: GraphqlEndpoint,
+    },
+
+    /// List the fields of a type, the values of an enum or the members of a union
+    Fields {
+        url: String,
+
+        #[arg(value_name = "TYPE")]
+        type_name: String,
+
+        #[command(flatten)]
+        endpoint: GraphqlEndpoint,
+    },
+}
+
+/// How GraphQL requests are sent
+#[derive(Args)]
+struct GraphqlEndpoint {
+    /// Send the query in the URL with GET rather than POSTing it as JSON; servers only run
+    /// queries this way, not mutations
+    #[arg(long)]
+    get: bool,
+
+    /// Extra request headers, as `Name: value`
+    #[arg(short('H'), long = "header", value_name = "HEADER")]
+    headers: Vec<String>,
+}
+
+/// Where gRPC service descriptions come from, how calls reach the server and the metadata
+/// sent along
+#[derive(Args)]
+struct GrpcSchema {
+    /// A .proto file describing the service, repeat for more; without any, the server is asked
+    /// through reflection
+    #[arg(long = "proto", value_name = "FILE")]
+    protos: Vec<PathBuf>,
+
+    /// Where to look for imported .proto files, defaulting to each file's own directory
+    #[arg(short('I'), long = "import-path", value_name = "DIR")]
+    import_paths: Vec<PathBuf>,
+
+    /// Ask the server for its descriptors again rather than using the cached ones
+    #[arg(long)]
+    refresh: bool,
+
+    /// Extra request metadata, as `Name: value`
+    #[arg(short('H'), long = "header", value_name = "HEADER")]
+    headers: Vec<String>,
+
+    /// Speak gRPC-Web, as browsers do through a proxy such as Envoy, in binary or base64 text
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "binary"
+    )]
+    grpc_web: Option<grpc::web::Format>,
+
+    /// Send gRPC-Web over HTTP/2 rather than HTTP/1.1
+    #[arg(long, requires = "grpc_web")]
+    http2: bool,
+}
+
+impl GrpcSchema {
+    fn wire(&self) -> Wire {
+        self.grpc_web.map_or(Wire::Grpc, Wire::Web)
+    }
+
+    // gRPC proper needs HTTP/2, gRPC-Web goes over HTTP/1.1 unless asked otherwise
+    fn client(&self) -> protocols::http::HttpClient {
+        let version = match self.grpc_web.is_none() || self.http2 {
+            true => protocols::http::HttpVersion::Http2,
+            false => protocols::http::HttpVersion::Http1,
+        };
+        protocols::http::HttpClient { version }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 enum Verbosity {
     Quiet,
@@ -95,42 +666,468 @@ enum VerboseDetail {
 
     /// Include response details appropriate for the current level of verbosity
     ResponseDetails,
+
+    /// Include TCP connection diagnostics (addresses, socket options)
+    Connection,
+
+    /// Include TLS handshake diagnostics (negotiated protocol, peer certificates)
+    Tls,
+
+    /// Include authentication diagnostics (decoded JWTs, token fetches, request signing)
+    Auth,
+
+    /// Include WebSocket frames (header bits, lengths, close codes, protocol violations)
+    Frames,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
     let cli = Cli::parse();
+    let interactive = io::stdout().is_terminal();
+    let presentation = Presentation {
+        output: cli.output,
+        verbosity: cli.verbose,
+        verbose_detail: HashSet::from_iter(cli.verbose_detail.clone()),
+        render: RenderOptions {
+            indent: cli.indent,
+            sort_keys: cli.sort_keys,
+            color: interactive,
+            image_preview: cli.image_preview.protocol().filter(|_| interactive),
+            hexdump_limit: cli.hexdump_limit,
+        },
+    };
+
+    diagnostics::init(
+        presentation.verbosity,
+        &presentation.verbose_detail,
+        cli.trace_file.as_deref(),
+    )?;
 
-    match cli.command {
+    let cookie_jar = Arc::new(Mutex::new(cli.cookie_jar()?));
+    let mut exit_code = ExitCode::SUCCESS;
+
+    match &cli.command {
         Some(Commands::Http { method, url, h2c }) => {
             // http/1.x call
 
             let client: Box<dyn ApiProtocol> = Box::new(protocols::http::HttpClient {
                 version: protocols::http::HttpVersion::Http1,
             });
-            let (request, response) = client.execute(method, &url, h2c).await?;
+            let (request, mut response) = client
+                .execute(
+                    cli.call(method.clone(), url, &cookie_jar)
+                        .await?
+                        .h2c(*h2c)
+                        .build()?,
+                )
+                .await?;
 
-            let _ = render_response(
-                &request,
-                &response,
-                cli.verbose,
-                HashSet::from_iter(cli.verbose_detail),
-            )?;
+            render_exchange(&request, &mut response, &presentation).await?;
         }
 
         Some(Commands::Http2 { method, url }) => {
-            // TODO: http/2 call
-            println!("Performing http2 {:?} to {}", method, url);
+            // http/2 call, negotiated through ALPN or with prior knowledge over cleartext
+
+            let client: Box<dyn ApiProtocol> = Box::new(protocols::http::HttpClient {
+                version: protocols::http::HttpVersion::Http2,
+            });
+            let (request, mut response) = client
+                .execute(cli.call(method.clone(), url, &cookie_jar).await?.build()?)
+                .await?;
+
+            render_exchange(&request, &mut response, &presentation).await?;
+        }
+
+        Some(Commands::Sse {
+            url,
+            max_events,
+            until,
+        }) => {
+            let client = protocols::http::HttpClient {
+                version: protocols::http::HttpVersion::Http1,
+            };
+            let stop_at = StopAt {
+                max_events: *max_events,
+                until: until.clone(),
+            };
+            let mut session = Session::new(stop_at, presentation.output != OutputFormat::Text);
+            let cli = &cli;
+            let cookie_jar = &cookie_jar;
+
+            session
+                .run(
+                    &client,
+                    |last_event_id| async move {
+                        let mut builder = cli
+                            .call(Method::GET, url, cookie_jar)
+                            .await?
+                            .header(ACCEPT, HeaderValue::from_static("text/event-stream"))
+                            .stream(true);
+                        if let Some(id) = last_event_id {
+                            builder = builder.header(LAST_EVENT_ID, HeaderValue::from_str(&id)?);
+                        }
+                        builder.build()
+                    },
+                    &presentation.render,
+                )
+                .await?;
+        }
+
+        Some(Commands::Ws {
+            url,
+            subprotocols,
+            headers,
+            messages,
+            file,
+            stdin,
+            binary,
+            wait,
+            deflate,
+            http2,
+            interactive,
+        }) => {
+            let mut builder = cli.call(Method::GET, url, &cookie_jar).await?;
+            for header in headers {
+                let (name, value) = parse_header(header)?;
+                builder = builder.header(name, value);
+            }
+            let call = builder.build()?;
+
+            let handshake = Handshake {
+                subprotocols: subprotocols.clone(),
+                deflate: *deflate,
+                http2: *http2,
+            };
+            let socket = WebSocket::connect(&call, &handshake).await?;
+            eprintln!(
+                "* Connected to {} over {}{}{}",
+                auth::mask_url(call.url.as_str()),
+                socket.transport,
+                socket
+                    .subprotocol
+                    .as_ref()
+                    .map(|subprotocol| format!(" using {}", subprotocol))
+                    .unwrap_or_default(),
+                socket
+                    .deflate
+                    .map(|params| format!(" with {}", params))
+                    .unwrap_or_default()
+            );
+
+            let transcript = Transcript::new(
+                presentation.output != OutputFormat::Text,
+                presentation.render,
+            );
+            if *interactive {
+                websockets::console::run(socket, transcript).await?;
+            } else {
+                let outgoing = Outgoing {
+                    messages: messages.clone(),
+                    file: file.clone(),
+                    stdin: *stdin,
+                    binary: *binary,
+                };
+                websockets::run(
+                    socket,
+                    outgoing,
+                    wait.map(Duration::from_secs_f64),
+                    transcript,
+                )
+                .await?;
+            }
         }
 
-        Some(Commands::Grpc { method, url }) => {
-            // TODO: grpc call
-            println!("Performing gRPC {:?} to {}", method, url);
+        Some(Commands::Grpc {
+            command:
+                Some(GrpcCommand::List {
+                    url,
+                    service,
+                    schema,
+                }),
+            ..
+        }) => {
+            let found = cli
+                .grpc_schema(url, schema, service.as_deref(), &cookie_jar)
+                .await?;
+            match service {
+                Some(service) => {
+                    let service = found
+                        .services()
+                        .find(|candidate| candidate.full_name() == service)
+                        .ok_or_else(|| format!("No service {}", service))?;
+                    for method in service.methods() {
+                        println!("{}", method.full_name());
+                    }
+                }
+                None => {
+                    for service in found.services() {
+                        println!("{}", service.full_name());
+                    }
+                }
+            }
+        }
+
+        Some(Commands::Grpc {
+            command:
+                Some(GrpcCommand::Describe {
+                    url,
+                    symbol,
+                    schema,
+                }),
+            ..
+        }) => {
+            let found = cli
+                .grpc_schema(url, schema, Some(symbol), &cookie_jar)
+                .await?;
+            print!("{}", found.describe(symbol)?);
+        }
+
+        Some(Commands::Grpc {
+            command:
+                Some(GrpcCommand::Health {
+                    url,
+                    service,
+                    watch,
+                    schema,
+                }),
+            ..
+        }) => {
+            let method = health::method(*watch)?;
+            let service = service.as_deref().unwrap_or_default();
+            let json = !matches!(presentation.output, OutputFormat::Text);
+            let color = presentation.render.color;
+            let builder = cli
+                .grpc_call(&grpc::url(url, &method), &schema.headers, &cookie_jar)
+                .await?;
+            let builder =
+                grpc::request(builder, schema.wire(), &method, &[health::request(service)])?;
+            let client = schema.client();
+
+            let mut serving = "UNKNOWN".to_string();
+            let reply = if *watch {
+                let call = builder.stream(true).build()?;
+                health::watch(&client, call, &method, |status| {
+                    serving = status.to_string();
+                    health::render(service, status, json, color, io::stdout().lock())
+                })
+                .await?
+            } else {
+                let (_, response) = client.execute(builder.build()?).await?;
+                let reply = Reply::read(&method, &response)?;
+                if let Some(received) = reply.messages.first() {
+                    serving = health::serving(&received.message).to_string();
+                    health::render(service, &serving, json, color, io::stdout().lock())?;
+                }
+                reply
+            };
+            match reply.status.code {
+                0 => {}
+                // How Check answers for a service it doesn't know
+                5 if !*watch => {
+                    serving = "SERVICE_UNKNOWN".to_string();
+                    health::render(service, &serving, json, color, io::stdout().lock())?;
+                }
+                _ => return Err(format!("The health check failed with {}", reply.status).into()),
+            }
+
+            exit_code = health::exit_code(&serving).into();
+        }
+
+        Some(Commands::Grpc {
+            command: None,
+            url,
+            method,
+            data,
+            interactive,
+            raw,
+            window_size,
+            connection_window_size,
+            adaptive_window,
+            schema,
+        }) => {
+            let (Some(url), Some(method)) = (url, method) else {
+                return Err("gRPC calls need a URL and a method".into());
+            };
+            let method = match raw {
+                true => grpc::schema::raw(method)?,
+                false => cli
+                    .grpc_schema(url, schema, Some(method), &cookie_jar)
+                    .await?
+                    .method(method)?,
+            };
+            if *interactive && !method.is_client_streaming() {
+                return Err(format!(
+                    "{} takes a single message, not a prompt",
+                    method.full_name()
+                )
+                .into());
+            }
+            let wire = schema.wire();
+            let source = match data.strip_prefix('@') {
+                _ if *interactive => Source::Interactive,
+                // Raw requests go as their bytes in base64, the JSON form of bytes
+                Some(path) if *raw => {
+                    let bytes = match path {
+                        "-" => {
+                            let mut bytes = vec![];
+                            io::stdin().read_to_end(&mut bytes)?;
+                            bytes
+                        }
+                        path => std::fs::read(path)?,
+                    };
+                    let base64 = general_purpose::STANDARD.encode(bytes);
+                    Source::Messages(vec![serde_json::to_string(&base64)?])
+                }
+                None if *raw && data != "{}" => {
+                    return Err("Without a schema, give the request's bytes as -d @FILE".into());
+                }
+                Some("-") if method.is_client_streaming() && wire == Wire::Grpc => Source::Stdin,
+                Some("-") => Source::Messages(vec![io::read_to_string(io::stdin())?]),
+                Some(path) => Source::Messages(vec![std::fs::read_to_string(path)?]),
+                None => Source::Messages(vec![data.clone()]),
+            };
+            let source = match source {
+                Source::Messages(texts) if method.is_client_streaming() => {
+                    Source::Messages(stream::messages(&texts.concat())?)
+                }
+                source => source,
+            };
+
+            let builder = cli
+                .grpc_call(&grpc::url(url, &method), &schema.headers, &cookie_jar)
+                .await?
+                .http2(Http2Settings {
+                    stream_window: *window_size,
+                    connection_window: *connection_window_size,
+                    adaptive_window: *adaptive_window,
+                });
+            let client: Box<dyn ApiProtocol> = Box::new(schema.client());
+
+            let reply = match source {
+                Source::Messages(messages)
+                    if !method.is_client_streaming() && !method.is_server_streaming() =>
+                {
+                    let call = grpc::request(builder, wire, &method, &messages)?.build()?;
+                    let (request, mut response) = client.execute(call).await?;
+                    let reply = Reply::read(&method, &response)?;
+                    render_grpc(&request, &mut response, &reply, &presentation).await?;
+                    reply
+                }
+                source => {
+                    let text = matches!(presentation.output, OutputFormat::Text);
+                    let mut printer = stream::Printer::new(!text, presentation.render);
+                    printer.metadata = !headers_shown(&presentation);
+                    let head = async |request: &ApiRequest, response: &mut ApiResponse| match text {
+                        true => {
+                            render_response(&request.masked(), response, &[], &presentation).await
+                        }
+                        false => Ok(()),
+                    };
+                    stream::run(
+                        client.as_ref(),
+                        builder,
+                        wire,
+                        &method,
+                        source,
+                        printer,
+                        head,
+                    )
+                    .await?
+                }
+            };
+
+            if !reply.status.is_ok() {
+                return Err(format!("The call failed with {}", reply.status).into());
+            }
+        }
+
+        Some(Commands::Graphql {
+            command: None,
+            url,
+            query,
+            variables,
+            operation_name,
+            endpoint,
+        }) => {
+            let (Some(url), Some(query)) = (url, query) else {
+                return Err("GraphQL calls need a URL and a query".into());
+            };
+            let query =
+                graphql::Query::load(query, variables.as_deref(), operation_name.as_deref())?;
+            let (request, mut response, reply) = cli
+                .graphql(url, &query, endpoint, &cookie_jar, &presentation)
+                .await?;
+            render_graphql(&request, &mut response, &reply, &query.query, &presentation).await?;
+
+            match reply.errors.len() {
+                0 => {}
+                1 => return Err("The server reported an error".into()),
+                count => return Err(format!("The server reported {} errors", count).into()),
+            }
+        }
+
+        Some(Commands::Graphql {
+            command: Some(GraphqlCommand::Schema { url, endpoint }),
+            ..
+        }) => {
+            let schema = cli
+                .graphql_schema(url, endpoint, &cookie_jar, &presentation)
+                .await?;
+            match presentation.output {
+                OutputFormat::Text => print!("{}", schema.sdl()),
+                OutputFormat::Yaml => serde_yaml::to_writer(io::stdout().lock(), &schema)?,
+                _ => {
+                    serde_json::to_writer_pretty(io::stdout().lock(), &schema)?;
+                    println!();
+                }
+            }
+        }
+
+        Some(Commands::Graphql {
+            command:
+                Some(GraphqlCommand::Fields {
+                    url,
+                    type_name,
+                    endpoint,
+                }),
+            ..
+        }) => {
+            let schema = cli
+                .graphql_schema(url, endpoint, &cookie_jar, &presentation)
+                .await?;
+            let found = schema.get(type_name)?;
+            match presentation.output {
+                OutputFormat::Text => {
+                    for member in found.members() {
+                        // The first line of the description is enough to go on
+                        match member.description.and_then(|text| text.lines().next()) {
+                            Some(text) => {
+                                let comment = format!("# {}", text);
+                                let comment = match presentation.render.color {
+                                    true => color::style(Token::Comment).paint(comment).to_string(),
+                                    false => comment,
+                                };
+                                println!("{}  {}", member.line, comment);
+                            }
+                            None => println!("{}", member.line),
+                        }
+                    }
+                }
+                OutputFormat::Yaml => serde_yaml::to_writer(io::stdout().lock(), found)?,
+                _ => {
+                    serde_json::to_writer_pretty(io::stdout().lock(), found)?;
+                    println!();
+                }
+            }
+        }
+
+        Some(Commands::Jwt { command }) => {
+            jwt::run(command.clone())?;
         }
 
         Some(Commands::Completion { shell }) => {
             let cmd = &mut Cli::command();
-            generate(shell, cmd, cmd.get_name().to_string(), &mut io::stdout());
+            generate(*shell, cmd, cmd.get_name().to_string(), &mut io::stdout());
         }
 
         None => {
@@ -146,45 +1143,176 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             // H -->|Success| I[Use HTTP/3]
             // H -->|Fail| C
 
-            if let Some(url) = cli.url {
-                // Default: GET via HTTP/1.1
+            if let Some(url) = &cli.url {
+                // Default: GET via HTTP/1.1, or POST when there's a form to submit
+                let method = match cli.form.is_empty() {
+                    true => Method::GET,
+                    false => Method::POST,
+                };
                 let client: Box<dyn ApiProtocol> = Box::new(protocols::http::HttpClient {
                     version: protocols::http::HttpVersion::Http1,
                 });
-                let (request, response) = client.execute(Method::GET, &url, false).await?;
+                let (request, mut response) = client
+                    .execute(cli.call(method, url, &cookie_jar).await?.build()?)
+                    .await?;
 
-                let _ = render_response(
-                    &request,
-                    &response,
-                    cli.verbose,
-                    HashSet::from_iter(cli.verbose_detail),
-                );
+                render_exchange(&request, &mut response, &presentation).await?;
             } else {
                 eprintln!("No command or URL provided. Try `--help`.");
             }
         }
     }
 
-    Ok(())
+    if let Some(path) = &cli.cookie_jar {
+        cookie_jar
+            .lock()
+            .map_err(|_| "Cookie jar poisoned")?
+            .save(path)?;
+    }
+
+    Ok(exit_code)
 }
 
-fn render_response(
-    request: &ApiRequest,
-    response: &ApiResponse,
+// Headers given on the command line as `Name: value`
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), Box<dyn Error>> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or("Expected --header as `Name: value`")?;
+    Ok((
+        HeaderName::from_bytes(name.trim().as_bytes())?,
+        HeaderValue::from_str(value.trim())?,
+    ))
+}
+
+// Everything that decides how an exchange gets printed
+struct Presentation {
+    output: OutputFormat,
     verbosity: Verbosity,
     verbose_detail: HashSet<VerboseDetail>,
+    render: RenderOptions,
+}
+
+async fn render_exchange(
+    request: &ApiRequest,
+    response: &mut ApiResponse,
+    presentation: &Presentation,
 ) -> Result<(), Box<dyn Error>> {
+    // Tokens have to be picked out before the credentials are masked
+    let tokens = jwt::collect(request, response);
+
+    // Never echo credentials back, whichever way the exchange is printed
+    let request = &request.masked();
+
+    match presentation.output {
+        OutputFormat::Text => render_response(request, response, &tokens, presentation).await,
+        // Event streams print event by event, here as JSON lines
+        _ if response.is_event_stream() => {
+            let mut session = Session::new(StopAt::default(), true);
+            session.consume(response, &presentation.render).await?;
+            Ok(())
+        }
+        output => output::render(output, request, response),
+    }
+}
+
+// Whether the verbose output already lists the response headers, custom metadata included
+fn headers_shown(presentation: &Presentation) -> bool {
+    let detail = &presentation.verbose_detail;
+    matches!(
+        presentation.verbosity,
+        Verbosity::Debug | Verbosity::Verbose
+    ) && (detail.contains(&VerboseDetail::All) || detail.contains(&VerboseDetail::ResponseDetails))
+}
+
+// The exchange as usual, with the body shown as the messages it carried
+async fn render_grpc(
+    request: &ApiRequest,
+    response: &mut ApiResponse,
+    reply: &Reply,
+    presentation: &Presentation,
+) -> Result<(), Box<dyn Error>> {
+    let request = &request.masked();
+    match presentation.output {
+        OutputFormat::Text => {
+            // Everything but the body, which the reply renders as messages
+            let body = response.body.take();
+            render_response(request, response, &[], presentation).await?;
+            response.body = body;
+
+            reply.render(
+                &presentation.render,
+                !headers_shown(presentation),
+                io::stdout().lock(),
+            )
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout().lock(), reply)?;
+            println!();
+            Ok(())
+        }
+        OutputFormat::Yaml => Ok(serde_yaml::to_writer(io::stdout().lock(), reply)?),
+        output => output::render(output, request, response),
+    }
+}
+
+// The exchange as usual, with the body shown as the data and the errors it carried
+async fn render_graphql(
+    request: &ApiRequest,
+    response: &mut ApiResponse,
+    reply: &graphql::Reply,
+    query: &str,
+    presentation: &Presentation,
+) -> Result<(), Box<dyn Error>> {
+    let request = &request.masked();
+    match presentation.output {
+        OutputFormat::Text => {
+            let body = response.body.take();
+            render_response(request, response, &[], presentation).await?;
+            response.body = body;
+
+            reply.render(query, &presentation.render, io::stdout().lock())
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(io::stdout().lock(), reply)?;
+            println!();
+            Ok(())
+        }
+        OutputFormat::Yaml => Ok(serde_yaml::to_writer(io::stdout().lock(), reply)?),
+        output => output::render(output, request, response),
+    }
+}
+
+async fn render_response(
+    request: &ApiRequest,
+    response: &mut ApiResponse,
+    tokens: &[(String, Jwt)],
+    presentation: &Presentation,
+) -> Result<(), Box<dyn Error>> {
+    let verbosity = presentation.verbosity;
+    let verbose_detail = &presentation.verbose_detail;
+
     if matches!(verbosity, Verbosity::Debug | Verbosity::Verbose) {
         if verbose_detail.contains(&VerboseDetail::All)
             | verbose_detail.contains(&VerboseDetail::RequestDetails)
         {
             request_output!({
-                println!("> {} {} {}", request.method, request.path, request.version);
+                eprintln!("> {} {} {}", request.method, request.path, request.version);
 
                 if let Some(header_vec) = &request.headers {
                     for (name, value) in header_vec {
-                        println!("{} {}: {}", ">", name, value);
+                        eprintln!("> {}: {}", name, value);
+                    }
+                }
+
+                for part in request.form.iter().flatten() {
+                    let mut description = format!("name=\"{}\"", part.name);
+                    if let Some(filename) = &part.filename {
+                        description.push_str(&format!("; filename=\"{}\"", filename));
                     }
+                    if let Some(content_type) = &part.content_type {
+                        description.push_str(&format!("; type={}", content_type));
+                    }
+                    eprintln!("> [form] {}; {} bytes", description, part.size);
                 }
             });
         }
@@ -192,32 +1320,60 @@ fn render_response(
         if verbose_detail.contains(&VerboseDetail::All)
             | verbose_detail.contains(&VerboseDetail::ResponseDetails)
         {
+            let host = url::Url::parse(&response.path)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| "unknown".to_string());
+            let ip = response
+                .ip
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            info!(target: HTTP, "Connected to {} ({})", host, ip);
+            info!(target: HTTP, "HTTP Version: {}", response.version);
+            info!(target: HTTP, "Request took: {:?}", response.duration);
+
             response_output!({
-                // TODO: Show resolved IP (requires DNS lookup)
-                //let host = response..url().host_str().unwrap_or("unknown");
-                let ip = response
-                    .ip
-                    .map(|addr| addr.to_string())
-                    .unwrap_or_else(|| "unknown".to_string());
-
-                println!("* Connected to {} ({})", "unknown", ip);
-                println!("* HTTP Version: {}", response.version);
-                println!("* Request took: {:?}", response.duration);
-
-                let status = response.status.unwrap_or_else(|| 0);
-                println!("< {} {} {}", response.path, response.version, status);
+                let status = response.status.unwrap_or(0);
+                eprintln!(
+                    "< {} {} {}",
+                    auth::mask_url(&response.path),
+                    response.version,
+                    status
+                );
                 if let Some(header_vec) = &response.headers {
                     for (name, value) in header_vec {
-                        println!("{} {}: {}", "<", name, value);
+                        eprintln!("< {}: {}", name, value);
                     }
                 }
 
-                println!("<");
+                eprintln!("<");
             });
         }
+
+        if verbose_detail.contains(&VerboseDetail::All)
+            | verbose_detail.contains(&VerboseDetail::Auth)
+        {
+            for (source, token) in tokens {
+                info!(target: AUTH, "JWT in {}", source);
+                for line in token.describe() {
+                    info!(target: AUTH, "  {}", line);
+                }
+            }
+        }
     }
 
-    response.render_body();
+    if response.is_event_stream() {
+        let mut session = Session::new(StopAt::default(), false);
+        session.consume(response, &presentation.render).await?;
+    } else if response.stream.is_some() {
+        response
+            .render_stream(verbosity == Verbosity::Debug)
+            .await?;
+    } else if response.body.is_some() {
+        response.render_body(&presentation.render);
+    } else {
+        // Downloads went straight to disk, there's no body left to show
+    }
 
     Ok(())
 }
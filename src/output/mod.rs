@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::io::{Write, stdout};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::{Engine, engine::general_purpose};
+use clap::ValueEnum;
+use hyper::StatusCode;
+use serde::Serialize;
+
+use crate::auth;
+use crate::protocols::{ApiRequest, ApiResponse, Protocol, TlsInfo};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-friendly, colored output
+    #[default]
+    Text,
+
+    /// The full exchange as a JSON document
+    Json,
+
+    /// The full exchange as a YAML document
+    Yaml,
+
+    /// A compact, aligned summary of the exchange
+    Table,
+}
+
+/// Writes the exchange to stdout in one of the machine-readable formats.
+///
+/// `OutputFormat::Text` is rendered by the caller, since it depends on verbosity settings these
+/// formats deliberately ignore.
+pub fn render(
+    format: OutputFormat,
+    request: &ApiRequest,
+    response: &ApiResponse,
+) -> Result<(), Box<dyn Error>> {
+    let exchange = Exchange::new(request, response);
+    let mut stdout = stdout().lock();
+
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &exchange)?;
+            writeln!(stdout)?;
+        }
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut stdout, &exchange)?,
+        OutputFormat::Table => write!(stdout, "{}", table(&exchange))?,
+        OutputFormat::Text => unreachable!("text output is rendered by the caller"),
+    }
+
+    Ok(())
+}
+
+// A serializable view of the request/response pair, with the parts that don't serialize nicely
+// on their own (durations, raw bodies) converted into something scripts can consume.
+#[derive(Serialize)]
+struct Exchange<'a> {
+    request: &'a ApiRequest,
+    response: ResponseView<'a>,
+}
+
+#[derive(Serialize)]
+struct ResponseView<'a> {
+    url: String,
+    protocol: &'a Protocol,
+    version: &'a str,
+    status: Option<u16>,
+    remote: Option<SocketAddr>,
+    headers: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trailers: Option<Vec<(String, String)>>,
+    timings: TimingsView,
+    tls: Option<&'a TlsInfo>,
+    body: Option<Body>,
+}
+
+// All timings in milliseconds
+#[derive(Serialize)]
+struct TimingsView {
+    dns_ms: f64,
+    connect_ms: f64,
+    tls_ms: Option<f64>,
+    first_byte_ms: f64,
+    total_ms: f64,
+}
+
+// Text bodies are decoded using the response charset, anything else is base64-encoded
+#[derive(Serialize)]
+struct Body {
+    encoding: BodyEncoding,
+    size: usize,
+    content: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum BodyEncoding {
+    Text,
+    Base64,
+}
+
+impl<'a> Exchange<'a> {
+    fn new(request: &'a ApiRequest, response: &'a ApiResponse) -> Self {
+        let body = response
+            .body
+            .as_ref()
+            .map(|data| match response.body_text() {
+                Some(text) => Body {
+                    encoding: BodyEncoding::Text,
+                    size: data.len(),
+                    content: text,
+                },
+                None => Body {
+                    encoding: BodyEncoding::Base64,
+                    size: data.len(),
+                    content: general_purpose::STANDARD.encode(data),
+                },
+            });
+
+        let timings = &response.timings;
+        Exchange {
+            request,
+            response: ResponseView {
+                url: auth::mask_url(&response.path),
+                protocol: &response.protocol,
+                version: &response.version,
+                status: response.status,
+                remote: response.ip,
+                headers: masked(response.headers.as_deref().unwrap_or_default()),
+                trailers: response.trailers.as_deref().map(masked),
+                timings: TimingsView {
+                    dns_ms: millis(timings.dns),
+                    connect_ms: millis(timings.connect),
+                    tls_ms: timings.tls.map(millis),
+                    first_byte_ms: millis(timings.first_byte),
+                    total_ms: millis(timings.total),
+                },
+                tls: response.tls.as_ref(),
+                body,
+            },
+        }
+    }
+}
+
+// Set-Cookie and the like are hidden the same way as in the request
+fn masked(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| (name.clone(), auth::mask_header(name, value)))
+        .collect()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn table(exchange: &Exchange) -> String {
+    let request = exchange.request;
+    let response = &exchange.response;
+
+    let mut rows: Vec<(&str, String)> = vec![
+        ("Request", format!("{} {}", request.method, request.path)),
+        (
+            "Status",
+            match response.status {
+                Some(code) => {
+                    let reason = StatusCode::from_u16(code)
+                        .ok()
+                        .and_then(|status| status.canonical_reason())
+                        .unwrap_or_default();
+                    format!("{} {}", code, reason).trim_end().to_string()
+                }
+                None => "-".to_string(),
+            },
+        ),
+        ("Version", response.version.to_string()),
+        (
+            "Remote",
+            response
+                .remote
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        ),
+    ];
+
+    if let Some(tls) = response.tls {
+        let alpn = tls.alpn.as_deref().unwrap_or("-");
+        rows.push((
+            "TLS",
+            format!("{} {} (ALPN {})", tls.version, tls.cipher_suite, alpn),
+        ));
+    }
+
+    let timings = &response.timings;
+    rows.push(("DNS", format!("{:.1} ms", timings.dns_ms)));
+    rows.push(("Connect", format!("{:.1} ms", timings.connect_ms)));
+    if let Some(tls_ms) = timings.tls_ms {
+        rows.push(("TLS handshake", format!("{:.1} ms", tls_ms)));
+    }
+    rows.push(("First byte", format!("{:.1} ms", timings.first_byte_ms)));
+    rows.push(("Total", format!("{:.1} ms", timings.total_ms)));
+
+    let content_type = response
+        .headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str())
+        .unwrap_or("-");
+    rows.push(("Content-Type", content_type.to_string()));
+    rows.push(("Headers", response.headers.len().to_string()));
+    rows.push((
+        "Body",
+        match &response.body {
+            Some(Body {
+                encoding: BodyEncoding::Text,
+                size,
+                ..
+            }) => format!("{} bytes (text)", size),
+            Some(Body { size, .. }) => format!("{} bytes (binary)", size),
+            None => "-".to_string(),
+        },
+    ));
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    rows.iter()
+        .map(|(key, value)| format!("{:<width$}  {}\n", key, value, width = width))
+        .collect()
+}
+
+#[test]
+fn test_exchange_body_encoding() -> Result<(), Box<dyn Error>> {
+    let request = ApiRequest {
+        headers: None,
+        method: "GET".to_string(),
+        path: "http://localhost/".to_string(),
+        version: "HTTP/1.1".to_string(),
+        form: None,
+    };
+    let mut response = ApiResponse {
+        path: "http://localhost/".to_string(),
+        protocol: Protocol::Http1,
+        status: Some(200),
+        headers: Some(vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            (
+                "set-cookie".to_string(),
+                "session=abc; HttpOnly".to_string(),
+            ),
+        ]),
+        body: Some(br#"{"ok":true}"#.to_vec()),
+        trailers: None,
+        stream: None,
+        version: "HTTP/1.1".to_string(),
+        ip: None,
+        duration: Duration::from_millis(5),
+        timings: Default::default(),
+        tls: None,
+    };
+
+    let json = serde_json::to_value(Exchange::new(&request, &response))?;
+    assert_eq!(json["response"]["body"]["encoding"], "text");
+    assert_eq!(json["response"]["body"]["content"], r#"{"ok":true}"#);
+    assert_eq!(json["response"]["headers"][1][1], "****");
+
+    response.headers = Some(vec![("content-type".to_string(), "image/png".to_string())]);
+    response.body = Some(vec![0x89, b'P', b'N', b'G']);
+
+    let json = serde_json::to_value(Exchange::new(&request, &response))?;
+    assert_eq!(json["response"]["body"]["encoding"], "base64");
+    assert_eq!(json["response"]["body"]["content"], "iVBORw==");
+
+    Ok(())
+}
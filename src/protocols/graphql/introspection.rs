@@ -0,0 +1,469 @@
+//! The schema as the server describes it through introspection, printed back as SDL
+
+use std::error::Error;
+use std::fmt::{self, Display, Write};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Query, Reply};
+
+// Deep enough for a `[[Type!]!]!`, and without the fields newer servers added
+const QUERY: &str = r#"
+query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    mutationType { name }
+    subscriptionType { name }
+    types { ...FullType }
+    directives {
+      name
+      description
+      locations
+      args { ...InputValue }
+    }
+  }
+}
+
+fragment FullType on __Type {
+  kind
+  name
+  description
+  fields(includeDeprecated: true) {
+    name
+    description
+    args { ...InputValue }
+    type { ...TypeRef }
+    isDeprecated
+    deprecationReason
+  }
+  inputFields { ...InputValue }
+  interfaces { ...TypeRef }
+  enumValues(includeDeprecated: true) {
+    name
+    description
+    isDeprecated
+    deprecationReason
+  }
+  possibleTypes { ...TypeRef }
+}
+
+fragment InputValue on __InputValue {
+  name
+  description
+  type { ...TypeRef }
+  defaultValue
+}
+
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType {
+    kind
+    name
+    ofType {
+      kind
+      name
+      ofType {
+        kind
+        name
+        ofType {
+          kind
+          name
+          ofType {
+            kind
+            name
+            ofType { kind name ofType { kind name } }
+          }
+        }
+      }
+    }
+  }
+}
+"#;
+
+// Part of every schema, so left out of the SDL
+const BUILT_IN_SCALARS: [&str; 5] = ["String", "Int", "Float", "Boolean", "ID"];
+const BUILT_IN_DIRECTIVES: [&str; 5] = ["skip", "include", "deprecated", "specifiedBy", "oneOf"];
+
+// What `@deprecated` means without a reason
+const DEFAULT_DEPRECATION: &str = "No longer supported";
+
+/// The introspection query
+pub fn query() -> Query {
+    Query {
+        query: QUERY.to_string(),
+        variables: None,
+        operation_name: Some("IntrospectionQuery".to_string()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schema {
+    pub query_type: Option<Named>,
+    pub mutation_type: Option<Named>,
+    pub subscription_type: Option<Named>,
+    pub types: Vec<Type>,
+    #[serde(default)]
+    pub directives: Vec<Directive>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Named {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Type {
+    pub kind: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub fields: Option<Vec<Field>>,
+    pub input_fields: Option<Vec<InputValue>>,
+    pub interfaces: Option<Vec<TypeRef>>,
+    pub enum_values: Option<Vec<EnumValue>>,
+    pub possible_types: Option<Vec<TypeRef>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Field {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub args: Vec<InputValue>,
+    #[serde(rename = "type")]
+    pub kind: TypeRef,
+    #[serde(default)]
+    pub is_deprecated: bool,
+    pub deprecation_reason: Option<String>,
+}
+
+/// An argument or input field
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InputValue {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: TypeRef,
+    /// Already written as a GraphQL value
+    pub default_value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnumValue {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub is_deprecated: bool,
+    pub deprecation_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Directive {
+    pub name: String,
+    pub description: Option<String>,
+    pub locations: Vec<String>,
+    #[serde(default)]
+    pub args: Vec<InputValue>,
+}
+
+/// A reference to a type, wrapped in any lists and non-nulls
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypeRef {
+    pub kind: String,
+    pub name: Option<String>,
+    pub of_type: Option<Box<TypeRef>>,
+}
+
+impl Display for TypeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.kind.as_str(), &self.of_type) {
+            ("NON_NULL", Some(inner)) => write!(f, "{}!", inner),
+            ("LIST", Some(inner)) => write!(f, "[{}]", inner),
+            _ => f.write_str(self.name.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+impl Schema {
+    /// Takes the schema out of the introspection query's reply
+    pub fn read(reply: Reply) -> Result<Schema, Box<dyn Error>> {
+        if let Some(error) = reply.errors.first() {
+            return Err(format!("Introspection failed: {}", error.message).into());
+        }
+        let schema = reply
+            .data
+            .and_then(|mut data| data.get_mut("__schema").map(serde_json::Value::take))
+            .ok_or("The server didn't describe its schema")?;
+        Ok(serde_json::from_value(schema)?)
+    }
+
+    /// Looks a type up by name
+    pub fn get(&self, name: &str) -> Result<&Type, Box<dyn Error>> {
+        self.types
+            .iter()
+            .find(|candidate| candidate.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("No type {}", name).into())
+    }
+
+    /// The schema as SDL, leaving out what every schema has
+    pub fn sdl(&self) -> String {
+        let mut blocks = vec![];
+
+        // Only needed when the root types aren't named as usual
+        let roots = [
+            ("query", &self.query_type, "Query"),
+            ("mutation", &self.mutation_type, "Mutation"),
+            ("subscription", &self.subscription_type, "Subscription"),
+        ];
+        if roots
+            .iter()
+            .any(|(_, root, usual)| root.as_ref().is_some_and(|root| root.name != *usual))
+        {
+            let mut block = "schema {\n".to_string();
+            for (operation, root, _) in roots {
+                if let Some(root) = root {
+                    let _ = writeln!(block, "  {}: {}", operation, root.name);
+                }
+            }
+            block.push('}');
+            blocks.push(block);
+        }
+
+        for directive in &self.directives {
+            if BUILT_IN_DIRECTIVES.contains(&directive.name.as_str()) {
+                continue;
+            }
+            let mut block = description(directive.description.as_deref(), "");
+            let _ = write!(
+                block,
+                "directive @{}{} on {}",
+                directive.name,
+                arguments(&directive.args, ""),
+                directive.locations.join(" | ")
+            );
+            blocks.push(block);
+        }
+
+        for kind in &self.types {
+            let name = kind.name.as_deref().unwrap_or_default();
+            if name.starts_with("__") || BUILT_IN_SCALARS.contains(&name) {
+                continue;
+            }
+            blocks.push(kind.sdl());
+        }
+        blocks.join("\n\n") + "\n"
+    }
+}
+
+impl Type {
+    /// The type's definition as SDL
+    pub fn sdl(&self) -> String {
+        let name = self.name.as_deref().unwrap_or_default();
+        let mut block = description(self.description.as_deref(), "");
+        match self.kind.as_str() {
+            "SCALAR" => {
+                let _ = write!(block, "scalar {}", name);
+            }
+            "UNION" => {
+                let members: Vec<String> = self
+                    .possible_types
+                    .iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect();
+                let _ = write!(block, "union {} = {}", name, members.join(" | "));
+            }
+            kind => {
+                let keyword = match kind {
+                    "INTERFACE" => "interface",
+                    "ENUM" => "enum",
+                    "INPUT_OBJECT" => "input",
+                    _ => "type",
+                };
+                let _ = write!(block, "{} {}", keyword, name);
+                let interfaces: Vec<String> = self
+                    .interfaces
+                    .iter()
+                    .flatten()
+                    .map(ToString::to_string)
+                    .collect();
+                if !interfaces.is_empty() {
+                    let _ = write!(block, " implements {}", interfaces.join(" & "));
+                }
+                block.push_str(" {\n");
+                for member in self.members() {
+                    block.push_str(&description(member.description, "  "));
+                    let _ = writeln!(block, "  {}", member.line);
+                }
+                block.push('}');
+            }
+        }
+        block
+    }
+
+    /// What the type is made of: fields, input fields, enum values or union members, one per
+    /// line and each with its description
+    pub fn members(&self) -> Vec<Member<'_>> {
+        let mut members = vec![];
+        for field in self.fields.iter().flatten() {
+            let mut line = format!(
+                "{}{}: {}",
+                field.name,
+                arguments(&field.args, "  "),
+                field.kind
+            );
+            if field.is_deprecated {
+                line.push_str(&deprecated(field.deprecation_reason.as_deref()));
+            }
+            members.push(Member {
+                line,
+                description: field.description.as_deref(),
+            });
+        }
+        for input in self.input_fields.iter().flatten() {
+            members.push(Member {
+                line: input_value(input),
+                description: input.description.as_deref(),
+            });
+        }
+        for value in self.enum_values.iter().flatten() {
+            let mut line = value.name.clone();
+            if value.is_deprecated {
+                line.push_str(&deprecated(value.deprecation_reason.as_deref()));
+            }
+            members.push(Member {
+                line,
+                description: value.description.as_deref(),
+            });
+        }
+        if self.kind == "UNION" {
+            for member in self.possible_types.iter().flatten() {
+                members.push(Member {
+                    line: member.to_string(),
+                    description: None,
+                });
+            }
+        }
+        members
+    }
+}
+
+/// One line of a type's definition
+pub struct Member<'a> {
+    pub line: String,
+    pub description: Option<&'a str>,
+}
+
+fn input_value(input: &InputValue) -> String {
+    match &input.default_value {
+        Some(default) => format!("{}: {} = {}", input.name, input.kind, default),
+        None => format!("{}: {}", input.name, input.kind),
+    }
+}
+
+// Arguments go on one line, unless they need room for their descriptions
+fn arguments(args: &[InputValue], indent: &str) -> String {
+    if args.is_empty() {
+        return String::new();
+    }
+    if args.iter().all(|arg| arg.description.is_none()) {
+        let args: Vec<String> = args.iter().map(input_value).collect();
+        return format!("({})", args.join(", "));
+    }
+    let inner = format!("{}  ", indent);
+    let mut text = "(\n".to_string();
+    for arg in args {
+        text.push_str(&description(arg.description.as_deref(), &inner));
+        let _ = writeln!(text, "{}{}", inner, input_value(arg));
+    }
+    text + indent + ")"
+}
+
+fn deprecated(reason: Option<&str>) -> String {
+    match reason {
+        None | Some(DEFAULT_DEPRECATION) => " @deprecated".to_string(),
+        Some(reason) => format!(" @deprecated(reason: {})", string(reason)),
+    }
+}
+
+// A description goes above what it describes, as a block string when it runs over lines
+fn description(text: Option<&str>, indent: &str) -> String {
+    match text.filter(|text| !text.is_empty()) {
+        None => String::new(),
+        Some(text) if text.contains('\n') => {
+            let mut block = format!("{}\"\"\"\n", indent);
+            for line in text.replace("\"\"\"", "\\\"\"\"").lines() {
+                let _ = writeln!(block, "{}{}", indent, line);
+            }
+            block + indent + "\"\"\"\n"
+        }
+        Some(text) => format!("{}{}\n", indent, string(text)),
+    }
+}
+
+// JSON's escapes are a subset of GraphQL's
+fn string(text: &str) -> String {
+    serde_json::Value::String(text.to_string()).to_string()
+}
+
+#[test]
+fn test_schema_sdl() -> Result<(), Box<dyn Error>> {
+    let scalar = |name: &str| serde_json::json!({"kind": "SCALAR", "name": name});
+    let non_null = |inner| serde_json::json!({"kind": "NON_NULL", "ofType": inner});
+    let reply: Reply = serde_json::from_value(serde_json::json!({"data": {"__schema": {
+        "queryType": {"name": "Root"},
+        "types": [
+            {"kind": "OBJECT", "name": "Root", "description": "Where\nit starts", "fields": [
+                {"name": "search", "type": {"kind": "LIST", "ofType": non_null(scalar("ID"))},
+                 "args": [{"name": "text", "description": "Words", "type": non_null(scalar("String"))},
+                          {"name": "first", "type": scalar("Int"), "defaultValue": "10"}]},
+                {"name": "old", "type": scalar("String"), "isDeprecated": true}
+            ], "interfaces": []},
+            {"kind": "ENUM", "name": "Unit", "enumValues": [
+                {"name": "FOOT", "isDeprecated": true, "deprecationReason": "Metric only"}
+            ]},
+            {"kind": "UNION", "name": "Result", "possibleTypes": [
+                {"kind": "OBJECT", "name": "Root"}, {"kind": "OBJECT", "name": "Other"}
+            ]},
+            scalar("String"),
+            {"kind": "OBJECT", "name": "__Type", "fields": []}
+        ],
+        "directives": [{"name": "skip", "locations": ["FIELD"]}]
+    }}}))?;
+    let schema = Schema::read(reply)?;
+    assert_eq!(
+        schema.sdl(),
+        r#"schema {
+  query: Root
+}
+
+"""
+Where
+it starts
+"""
+type Root {
+  search(
+    "Words"
+    text: String!
+    first: Int = 10
+  ): [ID!]
+  old: String @deprecated
+}
+
+enum Unit {
+  FOOT @deprecated(reason: "Metric only")
+}
+
+union Result = Root | Other
+"#
+    );
+    assert!(schema.get("Nope").is_err());
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//! GraphQL over HTTP: a query with its variables and operation name, POSTed as JSON or sent in
+//! the URL with GET, answered by `data` and `errors`
+
+pub mod introspection;
+
+use std::error::Error;
+use std::io::{self, Write};
+
+use ansi_term::Color::Red;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::ApiResponse;
+use crate::render::{self, RenderOptions};
+
+pub const CONTENT_TYPE: &str = "application/json";
+
+// Servers following GraphQL over HTTP answer with the first, older ones with plain JSON
+pub const ACCEPT: &str = "application/graphql-response+json, application/json";
+
+/// What to run: a document, with the variables for it and which of its operations to pick
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Query {
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+}
+
+impl Query {
+    /// Reads the query and variables, each given inline, as `@file` or as `@-` for stdin
+    pub fn load(
+        query: &str,
+        variables: Option<&str>,
+        operation_name: Option<&str>,
+    ) -> Result<Query, Box<dyn Error>> {
+        let variables = match variables {
+            Some(variables) => match serde_json::from_str(&read(variables)?)? {
+                variables @ Value::Object(_) => Some(variables),
+                _ => return Err("The variables have to be a JSON object".into()),
+            },
+            None => None,
+        };
+        Ok(Query {
+            query: read(query)?,
+            variables,
+            operation_name: operation_name.map(str::to_string),
+        })
+    }
+
+    /// The request body, for POST
+    pub fn body(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    /// The URL with the query in its query string, for GET
+    pub fn url(&self, url: &str) -> Result<String, Box<dyn Error>> {
+        let mut url = url::Url::parse(url)?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs.append_pair("query", &self.query);
+            if let Some(variables) = &self.variables {
+                pairs.append_pair("variables", &variables.to_string());
+            }
+            if let Some(operation_name) = &self.operation_name {
+                pairs.append_pair("operationName", operation_name);
+            }
+        }
+        Ok(url.to_string())
+    }
+}
+
+fn read(argument: &str) -> Result<String, Box<dyn Error>> {
+    match argument.strip_prefix('@') {
+        Some("-") => Ok(io::read_to_string(io::stdin())?),
+        Some(path) => Ok(std::fs::read_to_string(path)?),
+        None => Ok(argument.to_string()),
+    }
+}
+
+/// The server's answer: the data that could be resolved, and what went wrong
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reply {
+    /// Missing when the query never ran, null when it failed as a whole
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<QueryError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Value>,
+}
+
+/// One error, pointing into the query by location and into the data by path
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueryError {
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<Location>,
+    /// Field names and list indices down to the field that failed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Value>,
+}
+
+/// A position in the query, counting lines and columns from 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Reply {
+    /// Parses the response body, which has to be a GraphQL response whatever the HTTP status
+    pub fn read(response: &ApiResponse) -> Result<Reply, Box<dyn Error>> {
+        let body = response.body.as_deref().unwrap_or_default();
+        serde_json::from_slice::<Reply>(body)
+            .ok()
+            .filter(|reply| reply.data.is_some() || !reply.errors.is_empty())
+            .ok_or_else(|| {
+                format!(
+                    "The server didn't answer with a GraphQL response (HTTP {})",
+                    response.status.unwrap_or(0)
+                )
+                .into()
+            })
+    }
+
+    /// Prints the data, then each error with the part of the query it points at
+    pub fn render<W: Write>(
+        &self,
+        query: &str,
+        options: &RenderOptions,
+        mut out: W,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(data) = self.data.as_ref().filter(|data| !data.is_null()) {
+            render::json::render(&data.to_string(), options, &mut out)?;
+        }
+        for (number, error) in self.errors.iter().enumerate() {
+            let line = format!("* error {}: {}", number + 1, error.message);
+            match options.color {
+                true => writeln!(out, "{}", Red.paint(line))?,
+                false => writeln!(out, "{}", line)?,
+            }
+            if !error.path.is_empty() {
+                writeln!(out, "  at {}", path(&error.path))?;
+            }
+            for location in &error.locations {
+                write!(out, "{}", excerpt(query, *location))?;
+            }
+            if let Some(extensions) = &error.extensions {
+                writeln!(out, "  extensions: {}", extensions)?;
+            }
+        }
+        if let Some(extensions) = &self.extensions {
+            writeln!(out, "* extensions")?;
+            render::json::render(&extensions.to_string(), options, &mut out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// The path as it would be written in code, e.g. `hero.friends[1].name`
+pub fn path(segments: &[Value]) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        match segment {
+            Value::Number(index) => path.push_str(&format!("[{}]", index)),
+            Value::String(field) if path.is_empty() => path.push_str(field),
+            other => path.push_str(&format!(".{}", other.as_str().unwrap_or_default())),
+        }
+    }
+    path
+}
+
+/// The line of the query a location points at, with a caret under the column
+pub fn excerpt(query: &str, location: Location) -> String {
+    let Some(text) = query.lines().nth(location.line.saturating_sub(1)) else {
+        return format!("  --> line {}, column {}\n", location.line, location.column);
+    };
+    let number = location.line.to_string();
+    let gutter = " ".repeat(number.len());
+    // Tabs stay tabs so the caret lines up however they're shown
+    let indent: String = text
+        .chars()
+        .take(location.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!(
+        "  {}--> line {}, column {}\n  {} | {}\n  {} | {}^\n",
+        gutter, location.line, location.column, number, text, gutter, indent
+    )
+}
+
+#[test]
+fn test_reply_errors() -> Result<(), Box<dyn Error>> {
+    let query = "query {\n  hero {\n\tnmae\n  }\n}\n";
+    let reply: Reply = serde_json::from_str(
+        r#"{"errors": [{"message": "Cannot query field 'nmae' on type 'Character'.",
+            "locations": [{"line": 3, "column": 2}], "path": ["hero", "friends", 1, "name"],
+            "extensions": {"code": "GRAPHQL_VALIDATION_FAILED"}}]}"#,
+    )?;
+    let mut out = vec![];
+    reply.render(query, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "* error 1: Cannot query field 'nmae' on type 'Character'.\n\
+         \x20 at hero.friends[1].name\n\
+         \x20  --> line 3, column 2\n\
+         \x20 3 | \tnmae\n\
+         \x20   | \t^\n\
+         \x20 extensions: {\"code\":\"GRAPHQL_VALIDATION_FAILED\"}\n"
+    );
+
+    let query = Query {
+        query: "{ hero { name } }".to_string(),
+        variables: Some(serde_json::json!({"episode": 5})),
+        operation_name: None,
+    };
+    assert_eq!(
+        query.url("http://localhost/graphql?x=1")?,
+        "http://localhost/graphql?x=1&query=%7B+hero+%7B+name+%7D+%7D&variables=%7B%22episode%22%3A5%7D"
+    );
+    assert_eq!(
+        String::from_utf8(query.body()?)?,
+        r#"{"query":"{ hero { name } }","variables":{"episode":5}}"#
+    );
+    Ok(())
+}
@@ -0,0 +1,140 @@
+//! Rich error details: the `google.rpc.Status` servers may send in `grpc-status-details-bin`
+
+use std::error::Error;
+
+use base64::{Engine, engine::general_purpose};
+use prost_reflect::prost_types::Any;
+use prost_reflect::{DescriptorPool, DynamicMessage};
+use serde_json::{Value, json};
+
+pub const GRPC_STATUS_DETAILS: &str = "grpc-status-details-bin";
+
+// The status and the standard detail types, as google/rpc declares them
+const PROTO: &str = r#"
+syntax = "proto3";
+package google.rpc;
+
+import "google/protobuf/any.proto";
+import "google/protobuf/duration.proto";
+
+message Status {
+  int32 code = 1;
+  string message = 2;
+  repeated google.protobuf.Any details = 3;
+}
+
+message ErrorInfo {
+  string reason = 1;
+  string domain = 2;
+  map<string, string> metadata = 3;
+}
+
+message RetryInfo {
+  google.protobuf.Duration retry_delay = 1;
+}
+
+message DebugInfo {
+  repeated string stack_entries = 1;
+  string detail = 2;
+}
+
+message QuotaFailure {
+  message Violation {
+    string subject = 1;
+    string description = 2;
+  }
+  repeated Violation violations = 1;
+}
+
+message BadRequest {
+  message FieldViolation {
+    string field = 1;
+    string description = 2;
+    string reason = 3;
+  }
+  repeated FieldViolation field_violations = 1;
+}
+"#;
+
+fn pool() -> Result<DescriptorPool, Box<dyn Error>> {
+    let file = protox::file::File::from_source("google/rpc/status.proto", PROTO)?;
+    let mut pool = DescriptorPool::global();
+    pool.add_file_descriptor_proto(file.file_descriptor_proto().clone())?;
+    Ok(pool)
+}
+
+/// Decodes the header's value into its details, each as JSON tagged with its `@type`
+///
+/// Detail types other than the standard ones are kept as hex.
+pub fn decode(value: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+    let bytes = general_purpose::STANDARD_NO_PAD.decode(value.trim().trim_end_matches('='))?;
+    let pool = pool()?;
+    let status = pool
+        .get_message_by_name("google.rpc.Status")
+        .ok_or("google.rpc.Status is missing")?;
+    let status = DynamicMessage::decode(status, bytes.as_slice())?;
+
+    let mut details = vec![];
+    let anys = status
+        .get_field_by_name("details")
+        .and_then(|field| field.as_list().map(<[_]>::to_vec))
+        .unwrap_or_default();
+    for any in anys.iter().filter_map(|any| any.as_message()) {
+        let any: Any = any.transcode_to()?;
+        let name = any.type_url.rsplit('/').next().unwrap_or_default();
+        let mut detail = match pool.get_message_by_name(name) {
+            Some(descriptor) => {
+                serde_json::to_value(DynamicMessage::decode(descriptor, any.value.as_slice())?)?
+            }
+            None => json!({"value": hex(&any.value)}),
+        };
+        if let Value::Object(fields) = &mut detail {
+            fields.insert("@type".to_string(), Value::String(name.to_string()));
+        }
+        details.push(detail);
+    }
+    Ok(details)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn test_status_details() -> Result<(), Box<dyn Error>> {
+    use prost_reflect::prost::Message;
+
+    // Encoded the way servers send it
+    let pool = pool()?;
+    let status = DynamicMessage::deserialize(
+        pool.get_message_by_name("google.rpc.Status").unwrap(),
+        json!({
+            "code": 3,
+            "message": "invalid",
+            "details": [
+                {
+                    "@type": "type.googleapis.com/google.rpc.BadRequest",
+                    "fieldViolations": [{"field": "name", "description": "required"}]
+                },
+                {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "1.500s"}
+            ]
+        }),
+    )?;
+    let header = general_purpose::STANDARD_NO_PAD.encode(status.encode_to_vec());
+    assert_eq!(
+        decode(&header)?,
+        [
+            json!({
+                "@type": "google.rpc.BadRequest",
+                "fieldViolations": [{"field": "name", "description": "required"}]
+            }),
+            json!({"@type": "google.rpc.RetryInfo", "retryDelay": "1.500s"})
+        ]
+    );
+    assert!(decode("not base64!").is_err());
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! Health checking through `grpc.health.v1`, once or by watching for changes
+
+use std::error::Error;
+use std::io::Write;
+
+use ansi_term::Color::{Green, Red};
+use prost_reflect::MethodDescriptor;
+use serde_json::{Value, json};
+
+use super::schema::Schema;
+use super::{Reply, stream};
+use crate::protocols::{ApiCall, ApiProtocol};
+
+const PROTO: &str = r#"
+syntax = "proto3";
+package grpc.health.v1;
+
+message HealthCheckRequest {
+  string service = 1;
+}
+
+message HealthCheckResponse {
+  enum ServingStatus {
+    UNKNOWN = 0;
+    SERVING = 1;
+    NOT_SERVING = 2;
+    SERVICE_UNKNOWN = 3;
+  }
+  ServingStatus status = 1;
+}
+
+service Health {
+  rpc Check(HealthCheckRequest) returns (HealthCheckResponse);
+  rpc Watch(HealthCheckRequest) returns (stream HealthCheckResponse);
+}
+"#;
+
+/// `Check`, or `Watch` to hear about every change
+pub fn method(watch: bool) -> Result<MethodDescriptor, Box<dyn Error>> {
+    let file = protox::file::File::from_source("grpc/health/v1/health.proto", PROTO)?;
+    let schema = Schema::from_files(vec![file.file_descriptor_proto().clone()])?;
+    let name = if watch { "Watch" } else { "Check" };
+    schema.method(&format!("grpc.health.v1.Health/{}", name))
+}
+
+/// The request for a service, or for the whole server when it's empty
+pub fn request(service: &str) -> String {
+    json!({ "service": service }).to_string()
+}
+
+/// The serving status a response names, which JSON leaves out when it's UNKNOWN
+pub fn serving(message: &Value) -> &str {
+    message["status"].as_str().unwrap_or("UNKNOWN")
+}
+
+/// What the command exits with: 0 when serving, 2 when not, 3 for a service the server doesn't
+/// know and 4 when it can't tell; a failed check exits with 1
+pub fn exit_code(serving: &str) -> u8 {
+    match serving {
+        "SERVING" => 0,
+        "NOT_SERVING" => 2,
+        "SERVICE_UNKNOWN" => 3,
+        _ => 4,
+    }
+}
+
+/// Prints the status as a line, or a JSON object per line
+pub fn render<W: Write>(
+    service: &str,
+    serving: &str,
+    json: bool,
+    color: bool,
+    mut out: W,
+) -> Result<(), Box<dyn Error>> {
+    if json {
+        writeln!(out, "{}", json!({ "service": service, "status": serving }))?;
+    } else {
+        let line = match service {
+            "" => serving.to_string(),
+            service => format!("{}: {}", service, serving),
+        };
+        let line = match (color, serving == "SERVING") {
+            (false, _) => line,
+            (true, true) => Green.paint(line).to_string(),
+            (true, false) => Red.paint(line).to_string(),
+        };
+        writeln!(out, "{}", line)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Calls `Watch`, handing each status to `changed` as it arrives, until the server ends the call
+pub async fn watch(
+    client: &dyn ApiProtocol,
+    call: ApiCall,
+    method: &MethodDescriptor,
+    mut changed: impl FnMut(&str) -> Result<(), Box<dyn Error>>,
+) -> Result<Reply, Box<dyn Error>> {
+    let (_, mut response) = client.execute(call).await?;
+    let headers = response.headers.clone().unwrap_or_default();
+    let body = response
+        .stream
+        .take()
+        .ok_or("The response body wasn't streamed")?;
+    stream::read(method, body, &headers, response.status, |received| {
+        changed(serving(&received.message))
+    })
+    .await
+}
+
+#[test]
+fn test_health_messages() -> Result<(), Box<dyn Error>> {
+    assert!(method(true)?.is_server_streaming());
+    let check = method(false)?;
+    let bytes = super::schema::encode(check.input(), &request("demo.Greeter"))?;
+    assert_eq!(bytes, b"\x0a\x0cdemo.Greeter");
+
+    assert_eq!(
+        serving(&super::schema::decode(check.output(), b"\x08\x02")?),
+        "NOT_SERVING"
+    );
+    assert_eq!(
+        serving(&super::schema::decode(check.output(), b"")?),
+        "UNKNOWN"
+    );
+    assert_eq!(exit_code("SERVING"), 0);
+    assert_eq!(exit_code("SERVICE_UNKNOWN"), 3);
+    Ok(())
+}
@@ -0,0 +1,544 @@
+//! gRPC over HTTP/2: length-prefixed protobuf messages, with the outcome in trailers
+
+pub mod details;
+pub mod health;
+pub mod reflection;
+pub mod schema;
+pub mod stream;
+pub mod web;
+
+use std::error::Error;
+use std::io::{Read, Write};
+
+use ansi_term::Color::{Green, Red};
+use base64::{Engine, engine::general_purpose};
+use flate2::read::GzDecoder;
+use hyper::header::{HeaderName, HeaderValue, TE};
+use percent_encoding::percent_decode_str;
+use prost_reflect::MethodDescriptor;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use super::{ApiCallBuilder, ApiResponse};
+use crate::diagnostics::HTTP;
+use crate::render::{self, RenderOptions};
+
+pub const CONTENT_TYPE: &str = "application/grpc";
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+const GRPC_ENCODING: &str = "grpc-encoding";
+const GRPC_ACCEPT_ENCODING: HeaderName = HeaderName::from_static("grpc-accept-encoding");
+
+// Headers that belong to HTTP or to gRPC itself rather than to the application
+const RESERVED: [&str; 7] = [
+    "content-type",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "date",
+    "server",
+    "te",
+];
+
+// A compressed flag and a 32-bit big-endian length come before every message
+const PREFIX: usize = 5;
+
+/// One length-prefixed message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub compressed: bool,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PREFIX + self.payload.len());
+        out.push(self.compressed as u8);
+        out.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.payload);
+        out
+    }
+
+    /// Parses a frame off the front of the buffer, returning it and how many bytes it took
+    pub fn decode(buffer: &[u8]) -> Option<(Frame, usize)> {
+        let prefix = buffer.get(..PREFIX)?;
+        let length = u32::from_be_bytes([prefix[1], prefix[2], prefix[3], prefix[4]]) as usize;
+        let payload = buffer.get(PREFIX..PREFIX + length)?;
+        Some((
+            Frame {
+                compressed: prefix[0] & 1 == 1,
+                payload: payload.to_vec(),
+            },
+            PREFIX + length,
+        ))
+    }
+}
+
+/// How calls travel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Wire {
+    /// gRPC proper, over HTTP/2 with the status in the trailers
+    #[default]
+    Grpc,
+    /// gRPC-Web, over either HTTP version with the status at the end of the body
+    Web(web::Format),
+}
+
+/// Where the method lives: the service's full name and the method's, under the base URL
+pub fn url(base: &str, method: &MethodDescriptor) -> String {
+    format!(
+        "{}/{}/{}",
+        base.trim_end_matches('/'),
+        method.parent_service().full_name(),
+        method.name()
+    )
+}
+
+/// Adds the headers every call needs
+pub fn headers(builder: ApiCallBuilder, wire: Wire) -> ApiCallBuilder {
+    let builder = builder.header(GRPC_ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+    match wire {
+        // Proxies that don't pass trailers along would lose the status
+        Wire::Grpc => builder.header(TE, HeaderValue::from_static("trailers")),
+        Wire::Web(format) => web::headers(builder, format),
+    }
+}
+
+/// Turns a JSON message into a length-prefixed protobuf one of the method's input type
+pub fn frame(method: &MethodDescriptor, json: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let frame = Frame {
+        compressed: false,
+        payload: schema::encode(method.input(), json)?,
+    };
+    info!(
+        target: HTTP,
+        message = method.input().full_name(),
+        length = frame.payload.len(),
+        compressed = false,
+        "Sending a length-prefixed message"
+    );
+    Ok(frame.encode())
+}
+
+/// Turns the JSON messages into the framed protobuf body, with the headers gRPC requires
+pub fn request(
+    builder: ApiCallBuilder,
+    wire: Wire,
+    method: &MethodDescriptor,
+    messages: &[String],
+) -> Result<ApiCallBuilder, Box<dyn Error>> {
+    let mut body = vec![];
+    for json in messages {
+        body.extend(frame(method, json)?);
+    }
+    Ok(match wire {
+        Wire::Grpc => headers(builder, wire).body(CONTENT_TYPE, body),
+        Wire::Web(format) => {
+            headers(builder, wire).body(format.content_type(), web::body(format, body))
+        }
+    })
+}
+
+/// Splits a response body into messages as it arrives, whatever the chunk boundaries
+pub struct Decoder {
+    format: Option<web::Format>,
+    // Base64 not decoded yet, as gRPC-Web text
+    text: Vec<u8>,
+    buffer: Vec<u8>,
+    /// Trailers sent inside the body, as gRPC-Web does
+    pub trailers: Vec<(String, String)>,
+}
+
+impl Decoder {
+    /// A decoder for the body the response headers announce
+    pub fn new(headers: &[(String, String)]) -> Decoder {
+        Decoder {
+            format: find(headers, "content-type").and_then(web::Format::of),
+            text: vec![],
+            buffer: vec![],
+            trailers: vec![],
+        }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            Some(web::Format::Text) => {
+                self.text.extend_from_slice(chunk);
+                self.buffer.extend(web::decode_text(&mut self.text)?);
+            }
+            _ => self.buffer.extend_from_slice(chunk),
+        }
+        Ok(())
+    }
+
+    /// The next complete message, if there's one yet
+    pub fn message(&mut self) -> Option<Frame> {
+        loop {
+            let (frame, used) = Frame::decode(&self.buffer)?;
+            let flags = self.buffer[0];
+            self.buffer.drain(..used);
+            if self.format.is_some() && flags & web::TRAILERS != 0 {
+                self.trailers.extend(web::trailers(&frame.payload));
+                continue;
+            }
+            return Some(frame);
+        }
+    }
+
+    /// Fails if the body ended with part of a message still to come
+    pub fn finish(&self) -> Result<(), Box<dyn Error>> {
+        match self.buffer.len() + self.text.len() {
+            0 => Ok(()),
+            left => Err(format!(
+                "The response ends in the middle of a message, {} bytes left over",
+                left
+            )
+            .into()),
+        }
+    }
+}
+
+/// The outcome of a call, from `grpc-status` and `grpc-message`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub code: u32,
+    pub name: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// What `grpc-status-details-bin` holds, each detail tagged with its `@type`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<serde_json::Value>,
+}
+
+impl Status {
+    pub fn new(code: u32, message: Option<String>) -> Status {
+        Status {
+            code,
+            name: code_name(code),
+            message,
+            details: vec![],
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.code == 0
+    }
+
+    // What a response without grpc-status means, going by its HTTP status
+    fn from_http(status: u16) -> Status {
+        let code = match status {
+            400 => 13,
+            401 => 16,
+            403 => 7,
+            404 => 12,
+            429 | 502 | 503 | 504 => 14,
+            _ => 2,
+        };
+        Status::new(
+            code,
+            Some(format!("HTTP status {} without grpc-status", status)),
+        )
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.code, self.name)?;
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of a status code, as the spec spells it
+pub fn code_name(code: u32) -> &'static str {
+    match code {
+        0 => "OK",
+        1 => "CANCELLED",
+        2 => "UNKNOWN",
+        3 => "INVALID_ARGUMENT",
+        4 => "DEADLINE_EXCEEDED",
+        5 => "NOT_FOUND",
+        6 => "ALREADY_EXISTS",
+        7 => "PERMISSION_DENIED",
+        8 => "RESOURCE_EXHAUSTED",
+        9 => "FAILED_PRECONDITION",
+        10 => "ABORTED",
+        11 => "OUT_OF_RANGE",
+        12 => "UNIMPLEMENTED",
+        13 => "INTERNAL",
+        14 => "UNAVAILABLE",
+        15 => "DATA_LOSS",
+        16 => "UNAUTHENTICATED",
+        _ => "(unknown code)",
+    }
+}
+
+/// A message as it came off the wire, and what it decoded to
+#[derive(Debug, Serialize)]
+pub struct Received {
+    pub compressed: bool,
+    /// The payload length from the prefix, before any decompression
+    pub length: usize,
+    pub message: serde_json::Value,
+    /// Time since the previous message, or since the call started, when streaming
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<f64>,
+    /// The payload itself, kept for a method called without a schema
+    #[serde(skip)]
+    pub raw: Option<Vec<u8>>,
+}
+
+impl Received {
+    /// Decompresses the frame if need be and decodes it as the method's output type
+    pub fn decode(
+        method: &MethodDescriptor,
+        encoding: &str,
+        frame: &Frame,
+    ) -> Result<Received, Box<dyn Error>> {
+        let payload = match (frame.compressed, encoding) {
+            (false, _) => frame.payload.clone(),
+            (true, "gzip") => {
+                let mut inflated = vec![];
+                GzDecoder::new(frame.payload.as_slice()).read_to_end(&mut inflated)?;
+                inflated
+            }
+            (true, other) => {
+                return Err(format!("Can't decompress a message sent with {}", other).into());
+            }
+        };
+        let message = schema::decode(method.output(), &payload)?;
+        Ok(Received {
+            compressed: frame.compressed,
+            length: frame.payload.len(),
+            message,
+            elapsed_ms: None,
+            raw: schema::is_raw(&method.output()).then_some(payload),
+        })
+    }
+
+    /// Prints the message's framing, then the message itself
+    pub fn render<W: Write>(
+        &self,
+        number: usize,
+        options: &RenderOptions,
+        mut out: W,
+    ) -> Result<(), Box<dyn Error>> {
+        let compression = if self.compressed {
+            "gzip"
+        } else {
+            "uncompressed"
+        };
+        let mut line = format!(
+            "* message {}: {} bytes, {}",
+            number, self.length, compression
+        );
+        if let Some(elapsed) = self.elapsed_ms {
+            line.push_str(&format!(" (+{:.1} ms)", elapsed));
+        }
+        writeln!(out, "{}", line)?;
+        match &self.raw {
+            Some(payload) => render::protobuf::render(payload, options, &mut out)?,
+            None => {
+                let json = serde_json::to_string(&self.message)?;
+                render::json::render(&json, options, &mut out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything the server sent back for a call
+#[derive(Debug, Serialize)]
+pub struct Reply {
+    pub status: Status,
+    /// Custom metadata from the headers
+    pub metadata: Vec<(String, String)>,
+    pub messages: Vec<Received>,
+    /// Custom metadata from the trailers
+    pub trailers: Vec<(String, String)>,
+}
+
+impl Reply {
+    /// The status and metadata, without any messages yet
+    pub fn new(
+        headers: &[(String, String)],
+        trailers: &[(String, String)],
+        http_status: Option<u16>,
+    ) -> Reply {
+        // A call that fails straight away sends its status in the headers, "Trailers-Only"
+        let status = match status(trailers).or_else(|| status(headers)) {
+            Some(status) => status,
+            None => Status::from_http(http_status.unwrap_or_default()),
+        };
+        Reply {
+            status,
+            metadata: custom(headers),
+            messages: vec![],
+            trailers: custom(trailers),
+        }
+    }
+
+    /// Unframes and decodes the response body, and picks the status out of the trailers,
+    /// whether they came after the body or, with gRPC-Web, at the end of it
+    pub fn read(
+        method: &MethodDescriptor,
+        response: &ApiResponse,
+    ) -> Result<Reply, Box<dyn Error>> {
+        let headers = response.headers.as_deref().unwrap_or_default();
+        let mut decoder = Decoder::new(headers);
+        decoder.push(response.body.as_deref().unwrap_or_default())?;
+        let mut frames = vec![];
+        while let Some(frame) = decoder.message() {
+            frames.push(frame);
+        }
+        decoder.finish()?;
+
+        let mut trailers = response.trailers.clone().unwrap_or_default();
+        trailers.extend(decoder.trailers);
+        let mut reply = Reply::new(headers, &trailers, response.status);
+        let encoding = encoding(headers);
+        for frame in frames {
+            reply
+                .messages
+                .push(Received::decode(method, encoding, &frame)?);
+        }
+        Ok(reply)
+    }
+
+    /// Prints each message with its framing, then the status, with the header metadata first
+    /// unless the response headers were already shown
+    pub fn render<W: Write>(
+        &self,
+        options: &RenderOptions,
+        metadata: bool,
+        mut out: W,
+    ) -> Result<(), Box<dyn Error>> {
+        if metadata {
+            self.render_metadata(&mut out)?;
+        }
+        for (number, received) in self.messages.iter().enumerate() {
+            received.render(number + 1, options, &mut out)?;
+        }
+        self.render_status(options, out)
+    }
+
+    /// Prints the custom metadata from the headers
+    pub fn render_metadata<W: Write>(&self, mut out: W) -> Result<(), Box<dyn Error>> {
+        for (name, value) in &self.metadata {
+            writeln!(out, "< {}: {}", name, value)?;
+        }
+        Ok(())
+    }
+
+    /// Prints the custom metadata from the trailers, then the status
+    pub fn render_status<W: Write>(
+        &self,
+        options: &RenderOptions,
+        mut out: W,
+    ) -> Result<(), Box<dyn Error>> {
+        for (name, value) in &self.trailers {
+            writeln!(out, "< {}: {}", name, value)?;
+        }
+
+        let status = format!("* grpc-status: {}", self.status);
+        let status = match (options.color, self.status.is_ok()) {
+            (false, _) => status,
+            (true, true) => Green.paint(status).to_string(),
+            (true, false) => Red.paint(status).to_string(),
+        };
+        writeln!(out, "{}", status)?;
+
+        // The rest of the detail is the JSON under its type
+        for detail in &self.status.details {
+            let mut detail = detail.clone();
+            let kind = detail
+                .as_object_mut()
+                .and_then(|fields| fields.remove("@type"))
+                .unwrap_or_default();
+            writeln!(out, "* {}", kind.as_str().unwrap_or("detail"))?;
+            render::json::render(&detail.to_string(), options, &mut out)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// How the server compresses its messages, from `grpc-encoding`
+pub fn encoding(headers: &[(String, String)]) -> &str {
+    find(headers, GRPC_ENCODING).unwrap_or("identity")
+}
+
+fn find<'a>(pairs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    pairs
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn status(pairs: &[(String, String)]) -> Option<Status> {
+    let code = find(pairs, GRPC_STATUS)?.trim().parse().ok()?;
+    // The message is percent-encoded so it survives as a header value
+    let message = find(pairs, GRPC_MESSAGE)
+        .map(|message| percent_decode_str(message).decode_utf8_lossy().into_owned());
+    let mut status = Status::new(code, message);
+    if let Some(value) = find(pairs, details::GRPC_STATUS_DETAILS) {
+        match details::decode(value) {
+            Ok(details) => status.details = details,
+            Err(err) => warn!(target: HTTP, error = %err, "Can't decode the status details"),
+        }
+    }
+    Some(status)
+}
+
+// The application's own metadata, with `-bin` values decoded and shown as hex
+fn custom(pairs: &[(String, String)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .filter(|(name, _)| !name.starts_with("grpc-") && !RESERVED.contains(&name.as_str()))
+        .map(|(name, value)| {
+            let value = match name.ends_with("-bin") {
+                true => {
+                    match general_purpose::STANDARD_NO_PAD.decode(value.trim_end_matches('=')) {
+                        Ok(bytes) => bytes
+                            .iter()
+                            .map(|byte| format!("{:02x}", byte))
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        Err(_) => value.clone(),
+                    }
+                }
+                false => value.clone(),
+            };
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+#[test]
+fn test_framing_and_status() {
+    let frame = Frame {
+        compressed: false,
+        payload: b"\x0a\x03Ada".to_vec(),
+    };
+    let mut wire = frame.encode();
+    assert_eq!(wire[..5], [0, 0, 0, 0, 5]);
+    wire.extend_from_slice(&[1, 0, 0]);
+    assert_eq!(Frame::decode(&wire), Some((frame, 10)));
+    assert_eq!(Frame::decode(&wire[10..]), None);
+
+    let trailers = vec![
+        ("grpc-status".to_string(), "5".to_string()),
+        ("grpc-message".to_string(), "no%20such%20user".to_string()),
+        ("x-trace-bin".to_string(), "AQL/".to_string()),
+    ];
+    let status = status(&trailers).unwrap();
+    assert_eq!(status.to_string(), "5 NOT_FOUND: no such user");
+    assert_eq!(
+        custom(&trailers),
+        [("x-trace-bin".to_string(), "01 02 ff".to_string())]
+    );
+    assert_eq!(Status::from_http(503).name, "UNAVAILABLE");
+}
@@ -0,0 +1,337 @@
+//! Server reflection: fetching a server's service descriptions from the server itself
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use base64::{Engine, engine::general_purpose};
+use prost_reflect::prost::Message;
+use prost_reflect::prost_types::FileDescriptorProto;
+use prost_reflect::{DescriptorPool, MethodDescriptor};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+use url::Url;
+
+use super::schema::Schema;
+use super::{Reply, Wire, request};
+use crate::diagnostics::HTTP;
+use crate::protocols::{ApiCallBuilder, ApiProtocol};
+
+// Newest first, v1alpha is what older servers still register
+const VERSIONS: [&str; 2] = ["v1", "v1alpha"];
+
+// Cached descriptors older than this are fetched again, services come and go
+const CACHE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+// Both versions are the same messages under a different package
+const PROTO: &str = r#"
+syntax = "proto3";
+package grpc.reflection.VERSION;
+
+service ServerReflection {
+  rpc ServerReflectionInfo(stream ServerReflectionRequest) returns (stream ServerReflectionResponse);
+}
+
+message ServerReflectionRequest {
+  string host = 1;
+  oneof message_request {
+    string file_by_filename = 3;
+    string file_containing_symbol = 4;
+    ExtensionRequest file_containing_extension = 5;
+    string all_extension_numbers_of_type = 6;
+    string list_services = 7;
+  }
+}
+
+message ExtensionRequest {
+  string containing_type = 1;
+  int32 extension_number = 2;
+}
+
+message ServerReflectionResponse {
+  string valid_host = 1;
+  ServerReflectionRequest original_request = 2;
+  oneof message_response {
+    FileDescriptorResponse file_descriptor_response = 4;
+    ExtensionNumberResponse all_extension_numbers_response = 5;
+    ListServiceResponse list_services_response = 6;
+    ErrorResponse error_response = 7;
+  }
+}
+
+message FileDescriptorResponse {
+  repeated bytes file_descriptor_proto = 1;
+}
+
+message ExtensionNumberResponse {
+  string base_type_name = 1;
+  repeated int32 extension_number = 2;
+}
+
+message ListServiceResponse {
+  repeated ServiceResponse service = 1;
+}
+
+message ServiceResponse {
+  string name = 1;
+}
+
+message ErrorResponse {
+  int32 error_code = 1;
+  string error_message = 2;
+}
+"#;
+
+/// The schema of the server at `base`, from the cache when it's fresh and has `symbol`, or else
+/// fetched
+///
+/// `connect` starts a call to the given URL with whatever auth and metadata the user asked for.
+pub async fn schema<F, Fut>(
+    client: &dyn ApiProtocol,
+    wire: Wire,
+    base: &str,
+    connect: F,
+    refresh: bool,
+    symbol: Option<&str>,
+) -> Result<Schema, Box<dyn Error>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<ApiCallBuilder, Box<dyn Error>>>,
+{
+    if !refresh
+        && let Some(schema) = load(base)
+        && symbol.is_none_or(|symbol| schema.contains(symbol))
+    {
+        info!(target: HTTP, target_url = base, "Using cached descriptors");
+        return Ok(schema);
+    }
+
+    let schema = fetch(client, wire, base, connect).await?;
+    if let Err(err) = save(base, &schema) {
+        warn!(target: HTTP, error = %err, "Can't cache the descriptors");
+    }
+    Ok(schema)
+}
+
+// Lists the services, then asks for the files defining them and everything those import
+async fn fetch<F, Fut>(
+    client: &dyn ApiProtocol,
+    wire: Wire,
+    base: &str,
+    connect: F,
+) -> Result<Schema, Box<dyn Error>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<ApiCallBuilder, Box<dyn Error>>>,
+{
+    for (attempt, version) in VERSIONS.iter().enumerate() {
+        let method = method(version)?;
+        let reply = ask(
+            client,
+            wire,
+            base,
+            &connect,
+            &method,
+            vec![json!({"listServices": ""})],
+        )
+        .await?;
+
+        // Servers without this version answer UNIMPLEMENTED
+        if reply.status.code == 12 && attempt + 1 < VERSIONS.len() {
+            info!(target: HTTP, version, "Server reflection unavailable, trying an older version");
+            continue;
+        }
+        if !reply.status.is_ok() {
+            return Err(format!("Server reflection failed with {}", reply.status).into());
+        }
+        info!(target: HTTP, version, "Using server reflection");
+
+        let services: Vec<String> = responses(&reply, "listServicesResponse")
+            .flat_map(|list| list["service"].as_array().cloned().unwrap_or_default())
+            .filter_map(|service| service["name"].as_str().map(str::to_string))
+            .collect();
+
+        let mut files = BTreeMap::new();
+        let mut asked = BTreeSet::new();
+        let mut requests: Vec<Value> = services
+            .iter()
+            .map(|service| json!({"fileContainingSymbol": service}))
+            .collect();
+        while !requests.is_empty() {
+            let reply = ask(client, wire, base, &connect, &method, requests).await?;
+            if !reply.status.is_ok() {
+                return Err(format!("Server reflection failed with {}", reply.status).into());
+            }
+            for file in file_descriptors(&reply)? {
+                files.insert(file.name().to_string(), file);
+            }
+
+            // Imports the server didn't send along have to be asked for by name
+            let missing: BTreeSet<String> = files
+                .values()
+                .flat_map(|file| file.dependency.iter())
+                .filter(|name| !files.contains_key(*name))
+                .cloned()
+                .collect();
+            requests = vec![];
+            for name in missing {
+                if asked.insert(name.clone()) {
+                    requests.push(json!({"fileByFilename": name}));
+                } else if let Some(file) = DescriptorPool::global().get_file_by_name(&name) {
+                    // Some servers leave out the well-known types, which we have anyway
+                    files.insert(name, file.file_descriptor_proto().clone());
+                } else {
+                    return Err(format!("The server didn't send {}", name).into());
+                }
+            }
+        }
+
+        return Schema::from_files(files.into_values().collect());
+    }
+    unreachable!("the last version's reply is always returned")
+}
+
+fn method(version: &str) -> Result<MethodDescriptor, Box<dyn Error>> {
+    let proto = PROTO.replace("VERSION", version);
+    let file = protox::file::File::from_source("reflection.proto", &proto)?;
+    let schema = Schema::from_files(vec![file.file_descriptor_proto().clone()])?;
+    schema.method(&format!(
+        "grpc.reflection.{}.ServerReflection/ServerReflectionInfo",
+        version
+    ))
+}
+
+// Sends all the requests on one stream, the server answers each in turn
+async fn ask<F, Fut>(
+    client: &dyn ApiProtocol,
+    wire: Wire,
+    base: &str,
+    connect: &F,
+    method: &MethodDescriptor,
+    requests: Vec<Value>,
+) -> Result<Reply, Box<dyn Error>>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<ApiCallBuilder, Box<dyn Error>>>,
+{
+    let messages: Vec<String> = requests.iter().map(Value::to_string).collect();
+    let builder = connect(super::url(base, method)).await?.stream(false);
+    let call = request(builder, wire, method, &messages)?.build()?;
+    let (_, response) = client.execute(call).await?;
+    let reply = Reply::read(method, &response)?;
+
+    // Usually recoverable, e.g. a well-known type the server doesn't serve
+    for error in responses(&reply, "errorResponse") {
+        info!(
+            target: HTTP,
+            code = %error["errorCode"],
+            message = %error["errorMessage"],
+            "Server reflection error"
+        );
+    }
+    Ok(reply)
+}
+
+// The responses of one kind, e.g. `listServicesResponse`
+fn responses<'a>(reply: &'a Reply, kind: &'a str) -> impl Iterator<Item = &'a Value> {
+    reply
+        .messages
+        .iter()
+        .filter_map(move |received| received.message.get(kind))
+}
+
+fn file_descriptors(reply: &Reply) -> Result<Vec<FileDescriptorProto>, Box<dyn Error>> {
+    let mut files = vec![];
+    for response in responses(reply, "fileDescriptorResponse") {
+        let encoded = response["fileDescriptorProto"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for file in encoded.iter().filter_map(Value::as_str) {
+            let bytes = general_purpose::STANDARD.decode(file)?;
+            files.push(FileDescriptorProto::decode(bytes.as_slice())?);
+        }
+    }
+    Ok(files)
+}
+
+// One cache file per server, named after its scheme, host and port. Services behind one gateway
+// differ only by path, which goes in hashed.
+fn cache_path(base: &str) -> Option<PathBuf> {
+    let url = Url::parse(base).ok()?;
+    let mut name = format!(
+        "{}_{}_{}",
+        url.scheme(),
+        url.host_str()?,
+        url.port_or_known_default()?
+    );
+    let path = url.path().trim_matches('/');
+    if !path.is_empty() {
+        let hash = Sha256::digest(path.as_bytes());
+        name.push('_');
+        name.extend(hash[..8].iter().map(|b| format!("{:02x}", b)));
+    }
+    name.push_str(".pb");
+    Some(dirs::cache_dir()?.join("apigrok").join("grpc").join(name))
+}
+
+fn load(base: &str) -> Option<Schema> {
+    let path = cache_path(base)?;
+    let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+    if age > CACHE_LIFETIME {
+        return None;
+    }
+    Schema::from_bytes(&fs::read(path).ok()?).ok()
+}
+
+fn save(base: &str, schema: &Schema) -> Result<(), Box<dyn Error>> {
+    let Some(path) = cache_path(base) else {
+        return Ok(());
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, schema.to_bytes())?;
+    Ok(())
+}
+
+#[test]
+fn test_reflection_messages() -> Result<(), Box<dyn Error>> {
+    for version in VERSIONS {
+        let method = method(version)?;
+        assert!(method.is_client_streaming() && method.is_server_streaming());
+        assert_eq!(
+            super::url("http://localhost:50051/", &method),
+            format!(
+                "http://localhost:50051/grpc.reflection.{}.ServerReflection/ServerReflectionInfo",
+                version
+            )
+        );
+    }
+
+    // Without a cache directory there's no path at all
+    let name =
+        |base| cache_path(base).and_then(|path| path.file_name().map(|name| name.to_os_string()));
+    if name("https://api.example.com").is_some() {
+        assert_eq!(
+            name("https://api.example.com").unwrap(),
+            "https_api.example.com_443.pb"
+        );
+        assert_eq!(
+            name("https://api.example.com/"),
+            name("https://api.example.com")
+        );
+        assert_ne!(
+            name("http://api.example.com:443"),
+            name("https://api.example.com")
+        );
+        assert_ne!(
+            name("https://api.example.com/users"),
+            name("https://api.example.com/orders")
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,380 @@
+//! Service descriptions, and converting messages between JSON and protobuf with them
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use base64::{Engine, engine::general_purpose};
+use prost_reflect::prost::Message;
+use prost_reflect::prost_types::FileDescriptorProto;
+use prost_reflect::{
+    Cardinality, DescriptorPool, DynamicMessage, EnumDescriptor, FieldDescriptor, Kind,
+    MessageDescriptor, MethodDescriptor, ServiceDescriptor,
+};
+
+use crate::render::protobuf;
+
+/// Every service and message type the loaded files describe
+#[derive(Debug, Clone)]
+pub struct Schema {
+    pool: DescriptorPool,
+}
+
+impl Schema {
+    /// Compiles `.proto` files, resolving imports from the given directories
+    ///
+    /// Without import paths, each file's own directory is used. The well-known types
+    /// (`google/protobuf/*.proto`) are always available.
+    pub fn load(files: &[PathBuf], import_paths: &[PathBuf]) -> Result<Schema, Box<dyn Error>> {
+        let import_paths = match import_paths {
+            [] => files
+                .iter()
+                .map(|file| match file.parent() {
+                    Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                    _ => PathBuf::from("."),
+                })
+                .collect(),
+            paths => paths.to_vec(),
+        };
+
+        let mut compiler = protox::Compiler::new(import_paths)?;
+        compiler.include_imports(true).open_files(files)?;
+        Ok(Schema {
+            pool: compiler.descriptor_pool(),
+        })
+    }
+
+    /// Builds the schema from file descriptors, which may come in any order
+    pub fn from_files(files: Vec<FileDescriptorProto>) -> Result<Schema, Box<dyn Error>> {
+        let mut pool = DescriptorPool::new();
+        pool.add_file_descriptor_protos(files)?;
+        Ok(Schema { pool })
+    }
+
+    /// Reads a schema back from a serialized `FileDescriptorSet`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Schema, Box<dyn Error>> {
+        Ok(Schema {
+            pool: DescriptorPool::decode(bytes)?,
+        })
+    }
+
+    /// The schema as a serialized `FileDescriptorSet`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.pool.encode_to_vec()
+    }
+
+    pub fn services(&self) -> impl Iterator<Item = ServiceDescriptor> + '_ {
+        self.pool.services()
+    }
+
+    /// Whether a service, method, message or enum of this name is described
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.describe(symbol).is_ok()
+    }
+
+    /// Prints a service, method, message or enum the way a .proto file would declare it
+    pub fn describe(&self, symbol: &str) -> Result<String, Box<dyn Error>> {
+        let symbol = symbol.trim_start_matches('.');
+        let mut out = String::new();
+        if let Some(service) = self.pool.get_service_by_name(symbol) {
+            out.push_str(&format!("service {} {{\n", service.full_name()));
+            for method in service.methods() {
+                out.push_str(&format!("  {}\n", rpc(&method)));
+            }
+            out.push_str("}\n");
+        } else if let Some(message) = self.pool.get_message_by_name(symbol) {
+            describe_message(&message, 0, &mut out);
+        } else if let Some(enumeration) = self.pool.get_enum_by_name(symbol) {
+            describe_enum(&enumeration, 0, &mut out);
+        } else {
+            let method = self.method(symbol)?;
+            out.push_str(&format!("{}\n\n", rpc(&method)));
+            describe_message(&method.input(), 0, &mut out);
+            if method.output() != method.input() {
+                out.push('\n');
+                describe_message(&method.output(), 0, &mut out);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Looks a method up as `package.Service/Method` or `package.Service.Method`
+    pub fn method(&self, name: &str) -> Result<MethodDescriptor, Box<dyn Error>> {
+        let (service, method) = split(name)?;
+
+        let service = self.pool.get_service_by_name(service).ok_or_else(|| {
+            let known: Vec<_> = self
+                .pool
+                .services()
+                .map(|service| service.full_name().to_string())
+                .collect();
+            format!(
+                "No service {} in the loaded files, they define: {}",
+                service,
+                known.join(", ")
+            )
+        })?;
+        service
+            .methods()
+            .find(|candidate| candidate.name() == method)
+            .ok_or_else(|| format!("{} has no method {}", service.full_name(), method).into())
+    }
+}
+
+/// A unary method known only by its name, whose messages go as bytes in and come back decoded
+/// without a schema
+pub fn raw(name: &str) -> Result<MethodDescriptor, Box<dyn Error>> {
+    let (service, method) = split(name)?;
+    let (package, service) = match service.rsplit_once('.') {
+        Some((package, service)) => (format!("package {};", package), service),
+        None => (String::new(), service),
+    };
+    let message = protox::file::File::from_source(
+        "apigrok/raw.proto",
+        "syntax = \"proto3\"; package apigrok.raw; message Message {}",
+    )?;
+    let rpc = format!(
+        "syntax = \"proto3\"; {} import \"apigrok/raw.proto\";\n\
+         service {} {{ rpc {}({RAW}) returns ({RAW}); }}",
+        package, service, method
+    );
+    let rpc = protox::file::File::from_source("raw_service.proto", &rpc)?;
+    let schema = Schema::from_files(vec![
+        message.file_descriptor_proto().clone(),
+        rpc.file_descriptor_proto().clone(),
+    ])?;
+    schema.method(name)
+}
+
+// The stand-in for both of a raw method's message types
+const RAW: &str = "apigrok.raw.Message";
+
+/// Whether messages of this type are the bytes of a raw method
+pub fn is_raw(descriptor: &MessageDescriptor) -> bool {
+    descriptor.full_name() == RAW
+}
+
+fn split(name: &str) -> Result<(&str, &str), Box<dyn Error>> {
+    let name = name.trim_start_matches('/');
+    name.rsplit_once('/')
+        .or_else(|| name.rsplit_once('.'))
+        .ok_or_else(|| format!("Expected package.Service/Method, not {}", name).into())
+}
+
+fn rpc(method: &MethodDescriptor) -> String {
+    let stream = |yes: bool| if yes { "stream " } else { "" };
+    format!(
+        "rpc {}({}{}) returns ({}{});",
+        method.name(),
+        stream(method.is_client_streaming()),
+        method.input().full_name(),
+        stream(method.is_server_streaming()),
+        method.output().full_name()
+    )
+}
+
+// Nested types are declared inside their parent, as they would be in the .proto file
+fn describe_message(message: &MessageDescriptor, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    let name = match depth {
+        0 => message.full_name(),
+        _ => message.name(),
+    };
+    out.push_str(&format!("{}message {} {{\n", indent, name));
+
+    let mut oneofs_done = vec![];
+    for field in message.fields() {
+        match field
+            .containing_oneof()
+            .filter(|oneof| !oneof.is_synthetic())
+        {
+            Some(oneof) if oneofs_done.contains(&oneof) => {}
+            Some(oneof) => {
+                out.push_str(&format!("{}  oneof {} {{\n", indent, oneof.name()));
+                for field in oneof.fields() {
+                    out.push_str(&format!("{}    {}\n", indent, declare(&field)));
+                }
+                out.push_str(&format!("{}  }}\n", indent));
+                oneofs_done.push(oneof);
+            }
+            None => out.push_str(&format!("{}  {}\n", indent, declare(&field))),
+        }
+    }
+    for nested in message
+        .child_messages()
+        .filter(|nested| !nested.is_map_entry())
+    {
+        describe_message(&nested, depth + 1, out);
+    }
+    for nested in message.child_enums() {
+        describe_enum(&nested, depth + 1, out);
+    }
+    out.push_str(&format!("{}}}\n", indent));
+}
+
+fn describe_enum(enumeration: &EnumDescriptor, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    let name = match depth {
+        0 => enumeration.full_name(),
+        _ => enumeration.name(),
+    };
+    out.push_str(&format!("{}enum {} {{\n", indent, name));
+    for value in enumeration.values() {
+        out.push_str(&format!(
+            "{}  {} = {};\n",
+            indent,
+            value.name(),
+            value.number()
+        ));
+    }
+    out.push_str(&format!("{}}}\n", indent));
+}
+
+fn declare(field: &FieldDescriptor) -> String {
+    let label = if field.is_map() {
+        ""
+    } else if field.field_descriptor_proto().proto3_optional() {
+        "optional "
+    } else {
+        match field.cardinality() {
+            Cardinality::Repeated => "repeated ",
+            Cardinality::Required => "required ",
+            Cardinality::Optional => "",
+        }
+    };
+    let kind = match field.kind() {
+        Kind::Message(entry) if field.is_map() => format!(
+            "map<{}, {}>",
+            type_name(&entry.map_entry_key_field().kind()),
+            type_name(&entry.map_entry_value_field().kind())
+        ),
+        kind => type_name(&kind),
+    };
+    format!("{}{} {} = {};", label, kind, field.name(), field.number())
+}
+
+fn type_name(kind: &Kind) -> String {
+    match kind {
+        Kind::Double => "double",
+        Kind::Float => "float",
+        Kind::Int32 => "int32",
+        Kind::Int64 => "int64",
+        Kind::Uint32 => "uint32",
+        Kind::Uint64 => "uint64",
+        Kind::Sint32 => "sint32",
+        Kind::Sint64 => "sint64",
+        Kind::Fixed32 => "fixed32",
+        Kind::Fixed64 => "fixed64",
+        Kind::Sfixed32 => "sfixed32",
+        Kind::Sfixed64 => "sfixed64",
+        Kind::Bool => "bool",
+        Kind::String => "string",
+        Kind::Bytes => "bytes",
+        Kind::Message(message) => return message.full_name().to_string(),
+        Kind::Enum(enumeration) => return enumeration.full_name().to_string(),
+    }
+    .to_string()
+}
+
+/// Turns a JSON document into a protobuf message of the given type
+///
+/// A raw message is given as a JSON string of base64, the way JSON carries bytes, or as `{}`.
+pub fn encode(descriptor: MessageDescriptor, json: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if is_raw(&descriptor) {
+        return match serde_json::from_str(json)? {
+            serde_json::Value::String(base64) => Ok(general_purpose::STANDARD.decode(base64)?),
+            _ => Ok(vec![]),
+        };
+    }
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let message =
+        DynamicMessage::deserialize(descriptor.clone(), &mut deserializer).map_err(|err| {
+            format!(
+                "The request isn't a valid {}: {}",
+                descriptor.full_name(),
+                err
+            )
+        })?;
+    deserializer.end()?;
+    Ok(message.encode_to_vec())
+}
+
+/// Turns a protobuf message of the given type back into JSON, by field number for a raw one
+///
+/// Raw bytes that don't walk as a message come back as base64, so the call still gets to its
+/// status.
+pub fn decode(
+    descriptor: MessageDescriptor,
+    bytes: &[u8],
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    if is_raw(&descriptor) {
+        return Ok(match protobuf::decode(bytes) {
+            Some(fields) => protobuf::to_json(&fields),
+            None => general_purpose::STANDARD.encode(bytes).into(),
+        });
+    }
+    let message = DynamicMessage::decode(descriptor.clone(), bytes).map_err(|err| {
+        format!(
+            "The response isn't a valid {}: {}",
+            descriptor.full_name(),
+            err
+        )
+    })?;
+    Ok(serde_json::to_value(&message)?)
+}
+
+#[test]
+fn test_json_round_trip() -> Result<(), Box<dyn Error>> {
+    let dir = std::env::temp_dir().join(format!("apigrok-proto-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join("greeter.proto");
+    std::fs::write(
+        &file,
+        r#"syntax = "proto3";
+        package demo;
+        import "google/protobuf/timestamp.proto";
+        message HelloRequest { string name = 1; repeated int32 lucky_numbers = 2; }
+        message HelloReply { string message = 1; google.protobuf.Timestamp at = 2; }
+        service Greeter { rpc SayHello(HelloRequest) returns (HelloReply); }"#,
+    )?;
+
+    let schema = Schema::load(&[file], &[])?;
+    let method = schema.method("demo.Greeter/SayHello")?;
+    assert_eq!(schema.method("demo.Greeter.SayHello")?, method);
+    assert!(schema.method("demo.Greeter/Nope").is_err());
+
+    let bytes = encode(
+        method.input(),
+        r#"{"name": "Ada", "luckyNumbers": [7, 42]}"#,
+    )?;
+    assert_eq!(bytes, [0x0a, 3, b'A', b'd', b'a', 0x12, 2, 7, 42]);
+    assert_eq!(
+        decode(method.input(), &bytes)?,
+        serde_json::json!({"name": "Ada", "luckyNumbers": [7, 42]})
+    );
+    assert!(encode(method.input(), r#"{"nom": "Ada"}"#).is_err());
+
+    let schema = Schema::from_bytes(&schema.to_bytes())?;
+    assert_eq!(
+        schema.describe("demo.Greeter")?,
+        "service demo.Greeter {\n  rpc SayHello(demo.HelloRequest) returns (demo.HelloReply);\n}\n"
+    );
+    assert!(
+        schema
+            .describe("demo.HelloRequest")?
+            .contains("  repeated int32 lucky_numbers = 2;\n")
+    );
+    assert!(!schema.contains("demo.Nope"));
+
+    // Without a schema, bytes go in as base64 and come back keyed by field number
+    let method = raw("/demo.Greeter/SayHello")?;
+    assert!(is_raw(&method.output()));
+    assert_eq!(encode(method.input(), r#""CgNBZGE=""#)?, b"\x0a\x03Ada");
+    assert_eq!(
+        decode(method.output(), b"\x0a\x03Ada")?,
+        serde_json::json!({"1": "Ada"})
+    );
+    assert_eq!(decode(method.output(), b"\x0c")?, "DA==");
+
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
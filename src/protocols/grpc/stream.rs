@@ -0,0 +1,299 @@
+//! Streaming calls: messages sent as they're produced and printed as they arrive
+
+use std::error::Error;
+use std::io::{Write, stdout};
+use std::time::Instant;
+
+use futures_util::{StreamExt, stream};
+use hyper::body::Bytes;
+use prost_reflect::MethodDescriptor;
+use rustyline_async::{Readline, ReadlineEvent, SharedWriter};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{Notify, mpsc};
+
+use super::{CONTENT_TYPE, Decoder, Received, Reply, Wire, encoding, frame, headers, request};
+use crate::protocols::{
+    ApiCall, ApiCallBuilder, ApiProtocol, ApiRequest, ApiResponse, BodyStream, OutgoingStream,
+};
+use crate::render::RenderOptions;
+
+// Messages framed but not yet sent, before reading ahead waits
+const QUEUE: usize = 16;
+
+/// Where the messages to send come from
+pub enum Source {
+    /// JSON documents known up front
+    Messages(Vec<String>),
+    /// Newline-delimited JSON from stdin, each line sent as soon as it's read
+    Stdin,
+    /// Lines typed at a prompt
+    Interactive,
+}
+
+/// Splits text into the JSON documents in it, whether one per line or pretty-printed
+pub fn messages(text: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    serde_json::Deserializer::from_str(text)
+        .into_iter::<Value>()
+        .map(|message| Ok(message?.to_string()))
+        .collect()
+}
+
+/// Frames messages as the request body, which ends when this is dropped
+pub struct Sender {
+    method: MethodDescriptor,
+    queue: mpsc::Sender<Bytes>,
+}
+
+impl Sender {
+    /// Sends a message, returning false once the call is over and nothing more can go
+    pub async fn send(&self, json: &str) -> Result<bool, Box<dyn Error>> {
+        let framed = frame(&self.method, json)?;
+        Ok(self.queue.send(Bytes::from(framed)).await.is_ok())
+    }
+}
+
+/// Prints messages as they arrive, as colored text or as JSON lines
+pub struct Printer {
+    json: bool,
+    options: RenderOptions,
+    /// Whether to print the custom metadata from the headers
+    pub metadata: bool,
+    pub out: Box<dyn Write + Send>,
+}
+
+impl Printer {
+    pub fn new(json: bool, options: RenderOptions) -> Printer {
+        Printer {
+            json,
+            options,
+            metadata: true,
+            out: Box::new(stdout()),
+        }
+    }
+
+    fn message(&mut self, number: usize, received: &Received) -> Result<(), Box<dyn Error>> {
+        if self.json {
+            writeln!(self.out, "{}", serde_json::to_string(received)?)?;
+        } else {
+            received.render(number, &self.options, &mut self.out)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+
+    // The status goes last, as a JSON line of its own without messages
+    fn status(&mut self, reply: &Reply) -> Result<(), Box<dyn Error>> {
+        if self.json {
+            writeln!(self.out, "{}", serde_json::to_string(reply)?)?;
+            self.out.flush()?;
+            Ok(())
+        } else {
+            reply.render_status(&self.options, &mut self.out)
+        }
+    }
+}
+
+/// Performs a streaming call, sending from the source while printing what comes back
+///
+/// `head` gets the exchange once the response headers are in, before any message.
+pub async fn run(
+    client: &dyn ApiProtocol,
+    builder: ApiCallBuilder,
+    wire: Wire,
+    method: &MethodDescriptor,
+    source: Source,
+    mut printer: Printer,
+    head: impl AsyncFnOnce(&ApiRequest, &mut ApiResponse) -> Result<(), Box<dyn Error>>,
+) -> Result<Reply, Box<dyn Error>> {
+    let (queue, mut pending) = mpsc::channel::<Bytes>(QUEUE);
+    let sender = Sender {
+        method: method.clone(),
+        queue,
+    };
+    let (builder, source) = match (wire, source) {
+        (Wire::Grpc, source) => {
+            let chunks =
+                stream::poll_fn(move |cx| pending.poll_recv(cx).map(|chunk| chunk.map(Ok)));
+            let builder = headers(builder, wire)
+                .body_stream(CONTENT_TYPE, OutgoingStream::new(chunks.boxed()));
+            (builder, source)
+        }
+        // gRPC-Web sends the request in one piece, only the response streams
+        (Wire::Web(_), Source::Messages(messages)) => (
+            request(builder, wire, method, &messages)?,
+            Source::Messages(vec![]),
+        ),
+        (Wire::Web(_), _) => {
+            return Err(
+                "gRPC-Web sends the whole request at once, give the messages with -d".into(),
+            );
+        }
+    };
+    let call = builder.stream(true).build()?;
+
+    // The prompt has to be up before the call, servers may wait for a message to answer
+    let mut prompt = None;
+    if let Source::Interactive = source {
+        let (readline, out) = Readline::new("grpc> ".to_string())?;
+        printer.out = Box::new(out.clone());
+        prompt = Some((readline, out));
+    }
+
+    let ended = Notify::new();
+    let receiving = async {
+        let reply = receive(client, call, method, &mut printer, head).await;
+        ended.notify_one();
+        reply
+    };
+    let sending = async {
+        match (source, prompt) {
+            (Source::Interactive, Some((readline, out))) => {
+                interact(readline, out, sender, &ended).await
+            }
+            (Source::Stdin, _) => {
+                let mut lines = BufReader::new(tokio::io::stdin()).lines();
+                while let Some(line) = lines.next_line().await? {
+                    if !line.trim().is_empty() && !sender.send(&line).await? {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            (Source::Messages(messages), _) => {
+                for message in messages {
+                    if !sender.send(&message).await? {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            (Source::Interactive, None) => unreachable!("the prompt is set up above"),
+        }
+    };
+
+    let ((), reply) = tokio::try_join!(sending, receiving)?;
+    Ok(reply)
+}
+
+// Performs the call and prints the messages as they arrive
+async fn receive(
+    client: &dyn ApiProtocol,
+    call: ApiCall,
+    method: &MethodDescriptor,
+    printer: &mut Printer,
+    head: impl AsyncFnOnce(&ApiRequest, &mut ApiResponse) -> Result<(), Box<dyn Error>>,
+) -> Result<Reply, Box<dyn Error>> {
+    let started = Instant::now();
+    let (request, mut response) = client.execute(call).await?;
+    let body = response.stream.take();
+    head(&request, &mut response).await?;
+
+    let headers = response.headers.clone().unwrap_or_default();
+    let body = body.ok_or("The response body wasn't streamed")?;
+    if printer.metadata && !printer.json {
+        Reply::new(&headers, &[], response.status).render_metadata(&mut printer.out)?;
+    }
+
+    let (mut count, mut last) = (0, started);
+    let reply = read(method, body, &headers, response.status, |mut received| {
+        received.elapsed_ms = Some(last.elapsed().as_secs_f64() * 1000.0);
+        last = Instant::now();
+        count += 1;
+        printer.message(count, &received)
+    })
+    .await?;
+    printer.status(&reply)?;
+    Ok(reply)
+}
+
+/// Decodes messages out of a streamed body as they arrive, handing each to `each`, then
+/// returns the status and metadata once the body is over
+pub async fn read(
+    method: &MethodDescriptor,
+    body: BodyStream,
+    headers: &[(String, String)],
+    http_status: Option<u16>,
+    mut each: impl FnMut(Received) -> Result<(), Box<dyn Error>>,
+) -> Result<Reply, Box<dyn Error>> {
+    let BodyStream {
+        mut chunks,
+        trailers,
+    } = body;
+    let encoding = encoding(headers);
+    let mut decoder = Decoder::new(headers);
+    while let Some(chunk) = chunks.next().await {
+        decoder.push(&chunk.map_err(|err| err as Box<dyn Error>)?)?;
+        while let Some(frame) = decoder.message() {
+            each(Received::decode(method, encoding, &frame)?)?;
+        }
+    }
+    decoder.finish()?;
+
+    let mut trailers = trailers
+        .lock()
+        .map_err(|_| "Trailers poisoned")?
+        .take()
+        .unwrap_or_default();
+    trailers.extend(decoder.trailers);
+    Ok(Reply::new(headers, &trailers, http_status))
+}
+
+// Sends each line typed as a message, until Ctrl-D, then keeps the output flowing until the end
+async fn interact(
+    mut readline: Readline,
+    mut out: SharedWriter,
+    sender: Sender,
+    ended: &Notify,
+) -> Result<(), Box<dyn Error>> {
+    writeln!(
+        out,
+        "* Type a JSON message per line, Ctrl-D when you're done sending"
+    )?;
+    let mut sender = Some(sender);
+    loop {
+        tokio::select! {
+            _ = ended.notified() => break,
+            event = readline.readline() => match (event?, &sender) {
+                (ReadlineEvent::Line(line), _) if line.trim().is_empty() => {}
+                (ReadlineEvent::Line(line), Some(open)) => {
+                    readline.add_history_entry(line.clone());
+                    match open.send(&line).await {
+                        Ok(true) => {}
+                        Ok(false) => sender = None,
+                        // A typo shouldn't end the call
+                        Err(err) => writeln!(out, "* {}", err)?,
+                    }
+                }
+                (ReadlineEvent::Line(_), None) => {
+                    writeln!(out, "* Done sending, waiting for the server to end the call")?;
+                }
+                // The first Ctrl-D or Ctrl-C ends our side, a second one gives up waiting
+                (ReadlineEvent::Eof | ReadlineEvent::Interrupted, Some(_)) => {
+                    sender = None;
+                    writeln!(out, "* Done sending, Ctrl-D again to stop waiting")?;
+                }
+                (ReadlineEvent::Eof | ReadlineEvent::Interrupted, None) => {
+                    readline.flush()?;
+                    return Err("Cancelled before the server ended the call".into());
+                }
+            }
+        }
+    }
+    readline.flush()?;
+    Ok(())
+}
+
+#[test]
+fn test_split_messages() {
+    let text = "{\"name\": \"a\"}\n{\"name\": \"b\"}\n\n{\n  \"name\": \"c\"\n}\n";
+    assert_eq!(
+        messages(text).unwrap(),
+        [
+            r#"{"name":"a"}"#.to_string(),
+            r#"{"name":"b"}"#.to_string(),
+            r#"{"name":"c"}"#.to_string()
+        ]
+    );
+    assert!(messages("{\"name\": ").is_err());
+}
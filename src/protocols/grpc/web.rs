@@ -0,0 +1,134 @@
+//! gRPC-Web: gRPC as browsers speak it, with the trailers moved into the body and an optional
+//! base64 text encoding, usually translated to gRPC by a proxy such as Envoy
+
+use std::error::Error;
+
+use base64::{Engine, engine::general_purpose};
+use clap::ValueEnum;
+use hyper::header::{ACCEPT, HeaderName, HeaderValue};
+
+use crate::protocols::ApiCallBuilder;
+
+const X_GRPC_WEB: HeaderName = HeaderName::from_static("x-grpc-web");
+
+// Set in the flags byte of the frame that carries the trailers
+pub const TRAILERS: u8 = 0x80;
+
+/// How the body is encoded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Length-prefixed protobuf, as in gRPC
+    #[default]
+    Binary,
+    /// The same, base64-encoded, for clients that can't handle binary bodies
+    Text,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Binary => "application/grpc-web+proto",
+            Format::Text => "application/grpc-web-text+proto",
+        }
+    }
+
+    /// The format of a response, from its content type
+    pub fn of(content_type: &str) -> Option<Format> {
+        let content_type = content_type.trim().to_ascii_lowercase();
+        if content_type.starts_with("application/grpc-web-text") {
+            Some(Format::Text)
+        } else if content_type.starts_with("application/grpc-web") {
+            Some(Format::Binary)
+        } else {
+            None
+        }
+    }
+}
+
+/// Adds the headers gRPC-Web calls carry, in place of gRPC's
+pub fn headers(builder: ApiCallBuilder, format: Format) -> ApiCallBuilder {
+    builder
+        .header(X_GRPC_WEB, HeaderValue::from_static("1"))
+        .header(ACCEPT, HeaderValue::from_static(format.content_type()))
+}
+
+/// Encodes the framed messages as the request body
+pub fn body(format: Format, frames: Vec<u8>) -> Vec<u8> {
+    match format {
+        Format::Binary => frames,
+        Format::Text => general_purpose::STANDARD.encode(frames).into_bytes(),
+    }
+}
+
+/// Decodes as much base64 text as is complete, leaving the rest for the next chunk
+///
+/// Servers may encode each chunk on its own, so padding can turn up mid-body.
+pub fn decode_text(text: &mut Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    text.retain(|byte| !byte.is_ascii_whitespace());
+    let mut decoded = vec![];
+    loop {
+        let complete = text.len() / 4 * 4;
+        let end = match text[..complete].iter().position(|byte| *byte == b'=') {
+            Some(padding) => (padding / 4 + 1) * 4,
+            None => complete,
+        };
+        if end == 0 {
+            return Ok(decoded);
+        }
+        decoded.extend(general_purpose::STANDARD.decode(&text[..end])?);
+        text.drain(..end);
+    }
+}
+
+/// Parses the trailers frame's payload, which is laid out like an HTTP/1.1 header block
+pub fn trailers(payload: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(payload)
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+#[test]
+fn test_web_body() {
+    let message = super::Frame {
+        compressed: false,
+        payload: b"\x0a\x01A".to_vec(),
+    }
+    .encode();
+    let block = b"grpc-status:0\r\nGrpc-Message: ok\r\n";
+    let mut trailers = vec![TRAILERS, 0, 0, 0, block.len() as u8];
+    trailers.extend_from_slice(block);
+
+    // Encoded chunk by chunk, so padding turns up mid-body, then split at odd places
+    let text = [
+        general_purpose::STANDARD.encode(&message),
+        general_purpose::STANDARD.encode(&trailers),
+    ]
+    .concat();
+    let headers = [(
+        "content-type".to_string(),
+        Format::Text.content_type().to_string(),
+    )];
+    let mut decoder = super::Decoder::new(&headers);
+    let mut frames = vec![];
+    for chunk in text.as_bytes().chunks(7) {
+        decoder.push(chunk).unwrap();
+        frames.extend(std::iter::from_fn(|| decoder.message()));
+    }
+    decoder.finish().unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].payload, b"\x0a\x01A");
+    assert_eq!(
+        decoder.trailers,
+        [
+            ("grpc-status".to_string(), "0".to_string()),
+            ("grpc-message".to_string(), "ok".to_string())
+        ]
+    );
+    assert_eq!(
+        Format::of("application/grpc-web+proto"),
+        Some(Format::Binary)
+    );
+    assert_eq!(Format::of("application/grpc"), None);
+}
@@ -1,28 +1,57 @@
 use super::*;
 use std::error::Error;
-use std::net::{IpAddr, Ipv4Addr};
-use std::sync::Arc;
-use std::time::Duration;
-use std::vec;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use h2::client::{self};
-use http_body_util::Empty;
-use hyper::body::Bytes;
-use hyper::client::conn::http2;
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Body, Frame};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::{http1, http2};
 use hyper::rt::{Read, Write};
-use hyper::{HeaderMap, Request, Version, header};
+use hyper::upgrade::Upgraded;
+use hyper::{HeaderMap, Request, Response, StatusCode, Version, header};
 
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use rustls::pki_types::ServerName;
 use rustls_native_certs::load_native_certs;
-use tokio::net::TcpStream;
+use tokio::net::{TcpStream, lookup_host};
 use tokio_rustls::{TlsConnector, rustls::ClientConfig};
-use url::Url;
+use tracing::{debug, error, info, warn};
+use url::{Position, Url};
+
+use crate::auth::{ApiKeyLocation, Auth, digest};
+use crate::cookies;
+use crate::diagnostics::{CONNECTION, HTTP, TLS};
+use futures_util::{StreamExt, TryStreamExt, future, stream};
 
 pub struct HttpClient {
     pub version: HttpVersion,
 }
 
+/// How much HTTP/2 lets the server send before we've read it
+#[derive(Debug, Clone, Copy)]
+pub struct Http2Settings {
+    pub stream_window: u32,
+    pub connection_window: u32,
+    /// Grow the windows to fit the measured bandwidth-delay product instead
+    pub adaptive_window: bool,
+}
+
+impl Default for Http2Settings {
+    fn default() -> Self {
+        Http2Settings {
+            stream_window: 65_535,
+            connection_window: 1_048_576,
+            adaptive_window: false,
+        }
+    }
+}
+
+// HTTP/3 needs a QUIC transport, which we don't have yet
+#[allow(dead_code)]
 pub enum HttpVersion {
     Http1,
     Http2,
@@ -30,217 +59,608 @@ pub enum HttpVersion {
 }
 
 // Defines a trait combination that applies equally to the TokioIo<TlsStream<TcpStream>> and TokioIo<TcpStream>
-trait Streamable: Read + Write + Unpin + Send {}
+pub(super) trait Streamable: Read + Write + Unpin + Send {}
 
 // Defines a generic implementation that'll get built when we tell the compiler that we want a
 // dyn pointer to Streamable for any type that implements all those traits (normal-ish I think?)
 impl<T> Streamable for T where T: Read + Write + Unpin + Send {}
 
+// Request bodies are either in memory or streamed from disk, so they're boxed to a single type
+pub(super) type OutgoingBody = UnsyncBoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+// Built afresh for every send, since a Digest challenge or a 307/308 redirect sends the body again
+fn outgoing(body: &RequestBody) -> Result<OutgoingBody, Box<dyn Error>> {
+    Ok(match body {
+        RequestBody::Empty => empty(),
+        RequestBody::Bytes(bytes) => Full::new(Bytes::from(bytes.clone()))
+            .map_err(|never| match never {})
+            .boxed_unsync(),
+        RequestBody::Multipart(multipart) => StreamBody::new(
+            multipart
+                .stream()
+                .map_ok(Frame::data)
+                .map_err(|err| err.into()),
+        )
+        .boxed_unsync(),
+        RequestBody::Stream(stream) => match stream.take() {
+            Some(chunks) => StreamBody::new(chunks.map_ok(Frame::data)).boxed_unsync(),
+            None => {
+                return Err(
+                    "A streamed body can't be sent again for a Digest challenge or redirect".into(),
+                );
+            }
+        },
+    })
+}
+
+pub(super) fn empty() -> OutgoingBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
+
+// Response bodies are read as they arrive, so they can go to disk instead of memory
+type IncomingBody = UnsyncBoxBody<Bytes, Box<dyn Error + Send + Sync>>;
+
+// What we got back from the server, independently of which HTTP version carried it
+struct RawResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: IncomingBody,
+    first_byte: Instant,
+}
+
 #[async_trait]
 impl ApiProtocol for HttpClient {
     async fn execute(
         &self,
-        method: Method,
-        url: &str,
-        h2c: bool,
+        mut call: ApiCall,
     ) -> Result<(ApiRequest, ApiResponse), Box<dyn Error>> {
-        let parsed_url = Url::parse(url)?;
-        let scheme = parsed_url.scheme();
-        let host = parsed_url.host_str().ok_or("Invalid host")?;
-        let domain = parsed_url.domain().ok_or("Invalid domain")?.to_string();
-        let port = parsed_url
-            .port_or_known_default()
-            .unwrap_or_else(|| if scheme == "https" { 443 } else { 80 });
+        let mut redirects = 0;
+        // Once a redirect crosses to another site, SameSite cookies stay behind
+        let mut cross_site = false;
 
-        // TODO: use our own client, can't use blocking due to async main
-        // BlockingClient::new(domain, port, config)
+        let (headers, exchange) = loop {
+            let (headers, exchange) = self.send(&mut call, cross_site).await?;
 
-        // 1. Open TCP connection
-        let tcp = TcpStream::connect((domain.clone(), port)).await?;
-        println!("Connected to {}:{} via {}", host, port, scheme);
-        println!("{:?}", domain);
+            if let Some(jar) = &call.cookie_jar {
+                jar.lock()
+                    .map_err(|_| "Cookie jar poisoned")?
+                    .store_response(&call.url, &exchange.raw.headers);
+            }
 
-        println!("Local address: {}", tcp.local_addr()?);
-        println!("Peer address: {}", tcp.peer_addr()?);
-        println!("Socket TTL: {}", tcp.ttl()?);
-        println!("Nodelay setting: {}", tcp.nodelay()?);
+            let location = exchange
+                .raw
+                .headers
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok());
+            let (true, Some(location)) = (exchange.raw.status.is_redirection(), location) else {
+                break (headers, exchange);
+            };
+            if redirects >= call.max_redirects {
+                if call.max_redirects > 0 {
+                    warn!(target: HTTP, max = call.max_redirects, "Too many redirects, stopping");
+                }
+                break (headers, exchange);
+            }
 
-        if h2c {
-            http1_shizzle_with_upgrade(method, url, parsed_url, tcp).await?;
-        } else {
-            http1_shizzle(method, url, parsed_url, tcp).await?;
-        }
-        // let io: Option<Box<dyn Streamable>> = match scheme {
-        //     "https" => wrap_stream_with_tls(tcp, &domain).await?,
-        //     "http" => {
-        //         let tokio_io = TokioIo::new(tcp);
-        //         Some(Box::new(tokio_io))
-        //     }
-        //     _ => {
-        //         println!("Unsupported scheme: {}", scheme);
-        //         None
-        //     }
-        // };
-
-        // if let Some(io) = io {
-        //     process_stream(&parsed_url, scheme, domain, io).await?;
-        // }
+            let next = call.url.join(location)?;
+            info!(target: HTTP, status = %exchange.raw.status, location = %next, "Following redirect");
+            cross_site |= !cookies::same_site(&call.url, &next);
+            redirect(&mut call, exchange.raw.status, next);
+            redirects += 1;
+        };
+
+        let Exchange {
+            raw,
+            tls,
+            mut timings,
+            addr,
+            started,
+        } = exchange;
+
+        let mut stream = None;
+        let mut trailers = None;
+        let body = match &call.download {
+            Some(download) if download.wants(raw.status) => {
+                download.save(raw.status, &raw.headers, raw.body).await?;
+                None
+            }
+            // Event streams never end on their own, so they're always read as they arrive
+            _ if call.stream || sse::is_event_stream(&raw.headers) => {
+                let trailers = Arc::new(Mutex::new(None));
+                let slot = Arc::clone(&trailers);
+                let chunks = http_body_util::BodyStream::new(raw.body)
+                    .try_filter_map(move |frame| {
+                        let data = match frame.into_data() {
+                            Ok(data) => Some(data),
+                            Err(frame) => {
+                                if let (Ok(received), Ok(mut slot)) =
+                                    (frame.into_trailers(), slot.lock())
+                                {
+                                    *slot = Some(header_pairs(&received));
+                                }
+                                None
+                            }
+                        };
+                        future::ready(Ok(data))
+                    })
+                    .boxed();
+                stream = Some(BodyStream { chunks, trailers });
+                None
+            }
+            _ => {
+                let body = raw
+                    .body
+                    .collect()
+                    .await
+                    .map_err(|err| err as Box<dyn Error>)?;
+                trailers = body.trailers().map(header_pairs);
+                Some(body.to_bytes().to_vec())
+            }
+        };
+        timings.total = started.elapsed();
 
         Ok((
             ApiRequest {
-                headers: Some(vec![]),
-                method: "GET".to_string(),
-                path: url.to_string(),
-                version: format!("{}", version_to_string(Version::HTTP_11)),
+                headers: Some(header_pairs(&headers)),
+                method: call.method.to_string(),
+                path: call.url.to_string(),
+                form: call.form.clone(),
+                version: version_to_string(match raw.version {
+                    Version::HTTP_2 => Version::HTTP_2,
+                    _ => Version::HTTP_11,
+                }),
             },
             ApiResponse {
-                path: url.to_string(),
-                protocol: match self.version {
-                    HttpVersion::Http1 => Protocol::Http1,
-                    HttpVersion::Http2 => Protocol::Http2,
-                    HttpVersion::Http3 => Protocol::Http3,
+                path: call.url.to_string(),
+                protocol: match raw.version {
+                    Version::HTTP_2 => Protocol::Http2,
+                    Version::HTTP_3 => Protocol::Http3,
+                    _ => Protocol::Http1,
                 },
-                status: Some(200),
-                headers: Some(vec![]),
-                body: Some(vec![]),
-                version: format!("{}", version_to_string(Version::HTTP_11)),
-                ip: Some(SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                    8080,
-                )),
-                duration: Duration::new(3, 0),
+                status: Some(raw.status.as_u16()),
+                headers: Some(header_pairs(&raw.headers)),
+                body,
+                trailers,
+                stream,
+                version: version_to_string(raw.version),
+                ip: Some(addr),
+                duration: timings.total,
+                timings,
+                tls,
             },
         ))
     }
 }
 
+// One round trip on its own connection
+struct Exchange {
+    raw: RawResponse,
+    tls: Option<TlsInfo>,
+    timings: Timings,
+    addr: SocketAddr,
+    started: Instant,
+}
+
+impl HttpClient {
+    // Sends the call once, answering a Digest challenge if there is one
+    async fn send(
+        &self,
+        call: &mut ApiCall,
+        cross_site: bool,
+    ) -> Result<(HeaderMap, Exchange), Box<dyn Error>> {
+        let mut headers = default_headers(&call.url)?;
+        headers.extend(call.headers.clone());
+
+        if let Some(jar) = &call.cookie_jar {
+            let jar = jar.lock().map_err(|_| "Cookie jar poisoned")?;
+            if let Some(cookies) = jar.header_for(&call.url, &call.method, cross_site) {
+                // Keep any cookies given inline alongside the jar's
+                let value = match headers.get(header::COOKIE) {
+                    Some(inline) => format!("{}; {}", inline.to_str()?, cookies),
+                    None => cookies,
+                };
+                headers.insert(header::COOKIE, value.parse()?);
+            }
+        }
+
+        // Signing has to come last, it covers the final headers and body
+        if let Some(sigv4) = &call.aws_sigv4 {
+            sigv4
+                .sign(&call.method, &mut call.url, &mut headers, &call.body)
+                .await?;
+        }
+
+        let mut exchange = self.exchange(call, &headers).await?;
+
+        // Digest needs the server's nonce, so answer the challenge on a fresh connection
+        if let Some(Auth::Digest { user, password }) = &call.auth
+            && exchange.raw.status == StatusCode::UNAUTHORIZED
+        {
+            let challenge = exchange
+                .raw
+                .headers
+                .get_all(header::WWW_AUTHENTICATE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .find_map(digest::Challenge::parse);
+
+            match challenge {
+                Some(challenge) => {
+                    info!(target: HTTP, realm = %challenge.realm, "Answering Digest challenge");
+                    let uri = &call.url[Position::BeforePath..Position::AfterQuery];
+                    // auth-int hashes the body, a multipart one is read again for it
+                    let multipart = match &call.body {
+                        RequestBody::Multipart(multipart)
+                            if !challenge.qop.iter().any(|qop| qop == "auth") =>
+                        {
+                            let chunks = multipart.stream().try_collect::<Vec<_>>().await?;
+                            Some(chunks.concat())
+                        }
+                        _ => None,
+                    };
+                    let authorization = digest::respond(
+                        &challenge,
+                        user,
+                        password,
+                        call.method.as_str(),
+                        uri,
+                        multipart.as_deref().or(call.body.bytes()),
+                    )?;
+                    headers.insert(header::AUTHORIZATION, authorization.parse()?);
+                    exchange = self.exchange(call, &headers).await?;
+                }
+                None => warn!(target: HTTP, "Got a 401 without a Digest challenge"),
+            }
+        }
+
+        Ok((headers, exchange))
+    }
+
+    async fn exchange(
+        &self,
+        call: &ApiCall,
+        headers: &HeaderMap,
+    ) -> Result<Exchange, Box<dyn Error>> {
+        let started = Instant::now();
+
+        let url = &call.url;
+        let method = call.method.clone();
+        let body = outgoing(&call.body)?;
+        let scheme = url.scheme();
+        let host = url.host_str().ok_or("Invalid host")?.to_string();
+        let port = url
+            .port_or_known_default()
+            .unwrap_or_else(|| if scheme == "https" { 443 } else { 80 });
+
+        // TODO: use our own client, can't use blocking due to async main
+        // BlockingClient::new(domain, port, config)
+
+        // 1. Resolve and open TCP connection
+        let addr = lookup_host((host.as_str(), port))
+            .await?
+            .next()
+            .ok_or("Host did not resolve to any address")?;
+        let resolved = Instant::now();
+
+        let tcp = TcpStream::connect(addr).await?;
+        let connected = Instant::now();
+        info!(target: CONNECTION, %host, port, scheme, "Connected");
+        debug!(
+            target: CONNECTION,
+            local_addr = %tcp.local_addr()?,
+            peer_addr = %tcp.peer_addr()?,
+            ttl = tcp.ttl()?,
+            nodelay = tcp.nodelay()?,
+            "Socket options"
+        );
+
+        // 2. Negotiate TLS when needed, then speak whichever HTTP version we ended up with
+        let (raw, tls, handshaken) = match scheme {
+            "https" => {
+                let alpn = match self.version {
+                    HttpVersion::Http1 => vec![b"http/1.1".to_vec()],
+                    _ => vec![b"h2".to_vec(), b"http/1.1".to_vec()], // Prefer HTTP/2
+                };
+                let (io, tls) = wrap_stream_with_tls(tcp, &host, alpn).await?;
+                let handshaken = Instant::now();
+
+                let raw = if tls.alpn.as_deref() == Some("h2") {
+                    process_stream(method, url, headers, body, io, &call.http2).await?
+                } else {
+                    http1_shizzle(method, url, headers, body, io).await?
+                };
+                (raw, Some(tls), Some(handshaken))
+            }
+            "http" => {
+                let io = Box::new(TokioIo::new(tcp));
+                let raw = match self.version {
+                    _ if call.h2c => {
+                        http1_shizzle_with_upgrade(method, url, headers, body, io).await?
+                    }
+                    // Cleartext HTTP/2 with prior knowledge
+                    HttpVersion::Http2 => {
+                        process_stream(method, url, headers, body, io, &call.http2).await?
+                    }
+                    _ => http1_shizzle(method, url, headers, body, io).await?,
+                };
+                (raw, None, None)
+            }
+            _ => return Err(format!("Unsupported scheme: {}", scheme).into()),
+        };
+        let finished = Instant::now();
+
+        let timings = Timings {
+            dns: resolved - started,
+            connect: connected - resolved,
+            tls: handshaken.map(|at| at - connected),
+            first_byte: raw.first_byte - started,
+            total: finished - started,
+        };
+
+        Ok(Exchange {
+            raw,
+            tls,
+            timings,
+            addr,
+            started,
+        })
+    }
+}
+
+// Points the call at the redirect target, the way browsers and curl do
+fn redirect(call: &mut ApiCall, status: StatusCode, next: Url) {
+    // 303 always switches to GET, and 301/302 do for POST for historical reasons
+    let to_get = status == StatusCode::SEE_OTHER
+        || (matches!(status, StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND)
+            && call.method == Method::POST);
+    if to_get && call.method != Method::HEAD {
+        call.method = Method::GET;
+        call.body = RequestBody::Empty;
+        call.form = None;
+        call.headers.remove(header::CONTENT_TYPE);
+    }
+
+    // Credentials are only meant for the origin they were given for
+    if next.origin() != call.url.origin() {
+        call.headers.remove(header::AUTHORIZATION);
+        call.headers.remove(header::COOKIE);
+        // A key in the query string stays behind with the old URL, one in a header has to go
+        if let Some(api_key) = call.api_key.take()
+            && api_key.location == ApiKeyLocation::Header
+        {
+            call.headers.remove(api_key.name.as_str());
+        }
+        call.auth = None;
+        call.aws_sigv4 = None;
+    }
+    call.url = next;
+}
+
+pub(super) fn default_headers(parsed_url: &Url) -> Result<HeaderMap, Box<dyn Error>> {
+    let host = parsed_url.host_str().ok_or("Invalid host")?;
+    let authority = match parsed_url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::HOST, authority.parse()?);
+    headers.insert(
+        header::USER_AGENT,
+        format!("apigrok/{}", env!("CARGO_PKG_VERSION")).parse()?,
+    );
+    headers.insert(header::ACCEPT, "*/*".parse()?);
+    Ok(headers)
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+// HTTP/1.x wants the origin-form (path and query) as the request target
+pub(super) fn origin_form(parsed_url: &Url) -> &str {
+    &parsed_url[Position::BeforePath..Position::AfterQuery]
+}
+
+fn raw_response(response: Response<Incoming>) -> RawResponse {
+    let first_byte = Instant::now();
+    let (parts, body) = response.into_parts();
+
+    RawResponse {
+        status: parts.status,
+        version: parts.version,
+        headers: parts.headers,
+        body: body.map_err(|err| err.into()).boxed_unsync(),
+        first_byte,
+    }
+}
+
 async fn http1_shizzle(
     method: Method,
-    url: &str,
-    parsed_url: Url,
-    tcp: TcpStream,
-) -> Result<(), Box<dyn Error>> {
-    let io = TokioIo::new(tcp);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, Empty<Bytes>>(io).await?;
+    parsed_url: &Url,
+    headers: &HeaderMap,
+    body: OutgoingBody,
+    io: Box<dyn Streamable>,
+) -> Result<RawResponse, Box<dyn Error>> {
+    let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, OutgoingBody>(io).await?;
 
     tokio::task::spawn(async move {
         if let Err(err) = conn.await {
-            println!("Connection failed: {:?}", err);
+            error!(target: CONNECTION, error = ?err, "Connection failed");
         }
     });
 
-    let req: Request<Empty<Bytes>> = Request::builder()
-        .uri(url)
-        .header(header::HOST, parsed_url.domain().unwrap_or_default())
-        .header(hyper::header::USER_AGENT, "apigrok/0.1.0")
-        .header(hyper::header::ACCEPT, "*/*")
-        .header(hyper::header::ACCEPT_ENCODING, "gzip")
+    let mut req = Request::builder()
+        .uri(origin_form(parsed_url))
         .method(method)
-        .body(Empty::<Bytes>::new())?;
+        .body(body)?;
+    req.headers_mut().extend(headers.clone());
 
     let response = sender.send_request(req).await?;
 
-    println!("Status 1.x: {}", response.status());
+    info!(target: HTTP, status = %response.status(), "Status 1.x");
 
-    Ok(())
+    Ok(raw_response(response))
 }
 
-async fn http1_shizzle_with_upgrade(
-    method: Method,
-    url: &str,
-    parsed_url: Url,
-    tcp: TcpStream,
-) -> Result<(), Box<dyn Error>> {
-    let io = TokioIo::new(tcp);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake::<_, Empty<Bytes>>(io).await?;
+// An HTTP/1.1 connection that can be handed over to another protocol with `Upgrade`
+pub(super) async fn handshake_with_upgrades(
+    io: Box<dyn Streamable>,
+) -> Result<http1::SendRequest<OutgoingBody>, Box<dyn Error>> {
+    let (sender, conn) = http1::handshake::<_, OutgoingBody>(io).await?;
 
     tokio::task::spawn(async move {
         if let Err(err) = conn.with_upgrades().await {
-            println!("Connection failed: {:?}", err);
+            error!(target: CONNECTION, error = ?err, "Connection failed");
         }
     });
+    Ok(sender)
+}
+
+// The raw connection once the server has switched protocols
+pub(super) async fn upgraded(
+    response: &mut Response<Incoming>,
+    protocol: &str,
+) -> Result<TokioIo<Upgraded>, Box<dyn Error>> {
+    match hyper::upgrade::on(response).await {
+        Ok(upgraded) => {
+            debug!(target: CONNECTION, upgrade = protocol, "Connection upgraded");
+            Ok(TokioIo::new(upgraded))
+        }
+        Err(err) => {
+            warn!(target: HTTP, upgrade = protocol, error = ?err, "Upgrade failed");
+            Err(err.into())
+        }
+    }
+}
+
+async fn http1_shizzle_with_upgrade(
+    method: Method,
+    parsed_url: &Url,
+    headers: &HeaderMap,
+    body: OutgoingBody,
+    io: Box<dyn Streamable>,
+) -> Result<RawResponse, Box<dyn Error>> {
+    let mut sender = handshake_with_upgrades(io).await?;
 
     // probing with OPTIONS request
-    let req: Request<Empty<Bytes>> = Request::builder()
-        .uri(url)
-        .header(header::HOST, parsed_url.domain().unwrap_or_default())
-        .header(hyper::header::USER_AGENT, "apigrok/0.1.0")
-        .header(hyper::header::ACCEPT, "*/*")
-        .header(hyper::header::ACCEPT_ENCODING, "gzip")
+    let mut req = Request::builder()
+        .uri(origin_form(parsed_url))
         .header(hyper::header::CONNECTION, "Upgrade, HTTP2-Settings")
         .header(hyper::header::UPGRADE, "h2c")
         .header("HTTP2-Settings", "")
         .method(hyper::Method::OPTIONS)
-        .body(Empty::<Bytes>::new())?;
+        .body(empty())?;
+    req.headers_mut().extend(headers.clone());
 
     let mut response = sender.send_request(req).await?;
 
-    println!("Status 1.x: {}", response.status());
+    info!(target: HTTP, status = %response.status(), "Status 1.x");
 
-    if response.status() == hyper::StatusCode::SWITCHING_PROTOCOLS {
-        println!("Upgrade accepted!");
+    if response.status() != hyper::StatusCode::SWITCHING_PROTOCOLS {
+        warn!(target: HTTP, upgrade = "h2c", "Upgrade refused, staying on HTTP/1.1");
 
-        // Access the upgraded connection:
-        if let Some(upgraded) = hyper::upgrade::on(&mut response).await.ok() {
-            // Now you have a raw Upgraded I/O stream (impl AsyncRead + AsyncWrite)
-            println!("Connection upgraded!");
+        // The connection is still good for plain HTTP/1.1, so send the real request over it
+        let _ = response.into_body().collect().await?;
+        sender.ready().await?;
+        let mut req = Request::builder()
+            .uri(origin_form(parsed_url))
+            .method(method)
+            .body(body)?;
+        req.headers_mut().extend(headers.clone());
 
-            // Handle your protocol (WebSocket, h2c, etc.) here
-            let io = TokioIo::new(upgraded);
-            // Now upgraded can be used directly with h2
-            let (mut h2_client, h2_connection) = client::handshake(io).await?;
+        let response = sender.send_request(req).await?;
+        info!(target: HTTP, status = %response.status(), "Status 1.x");
+        return Ok(raw_response(response));
+    }
 
-            tokio::spawn(async move {
-                if let Err(e) = h2_connection.await {
-                    eprintln!("h2 connection error: {:?}", e);
-                }
-            });
+    info!(target: HTTP, upgrade = "h2c", "Upgrade accepted");
+
+    // Now upgraded can be used directly with h2
+    let io = upgraded(&mut response, "h2c").await?;
+    let (mut h2_client, h2_connection) = client::handshake(io).await?;
 
-            // probing with OPTIONS request, needs to be same as ORIGINAL upgrade request
-            let req = Request::builder()
-                .uri(url)
-                .header(header::HOST, parsed_url.domain().unwrap_or_default())
-                .header(hyper::header::USER_AGENT, "apigrok/0.1.0")
-                .header(hyper::header::ACCEPT, "*/*")
-                .header(hyper::header::ACCEPT_ENCODING, "gzip")
-                .version(http::Version::HTTP_2)
-                .method(hyper::Method::OPTIONS)
-                .body(())?;
-
-            let (response_future, _) = h2_client.send_request(req, true)?;
-            let response = response_future.await?;
-
-            println!("Status h2c: {}", response.status());
-
-            // user intended request over h2c
-            let req = Request::builder()
-                .uri(url)
-                .header(header::HOST, parsed_url.domain().unwrap_or_default())
-                .header(hyper::header::USER_AGENT, "apigrok/0.1.0")
-                .header(hyper::header::ACCEPT, "*/*")
-                .header(hyper::header::ACCEPT_ENCODING, "gzip")
-                .version(http::Version::HTTP_2)
-                .method(method)
-                .body(())?;
-
-            let (response_future, _) = h2_client.send_request(req, true)?;
-            let response = response_future.await?;
-
-            println!("Status h2c: {}", response.status());
-        } else {
-            eprintln!("Upgrade failed");
+    tokio::spawn(async move {
+        if let Err(e) = h2_connection.await {
+            error!(target: CONNECTION, error = ?e, "h2 connection error");
         }
+    });
+
+    // HTTP/2 carries the authority in the URI rather than the Host header
+    let mut h2_headers = headers.clone();
+    h2_headers.remove(header::HOST);
+
+    // probing with OPTIONS request, needs to be same as ORIGINAL upgrade request
+    let mut req = Request::builder()
+        .uri(parsed_url.as_str())
+        .version(Version::HTTP_2)
+        .method(hyper::Method::OPTIONS)
+        .body(())?;
+    req.headers_mut().extend(h2_headers.clone());
+
+    let (response_future, _) = h2_client.send_request(req, true)?;
+    let response = response_future.await?;
+
+    info!(target: HTTP, status = %response.status(), "Status h2c");
+
+    // user intended request over h2c
+    let mut req = Request::builder()
+        .uri(parsed_url.as_str())
+        .version(Version::HTTP_2)
+        .method(method)
+        .body(())?;
+    req.headers_mut().extend(h2_headers);
+
+    let mut body = body;
+    let (response_future, mut stream) = h2_client.send_request(req, body.is_end_stream())?;
+    if !body.is_end_stream() {
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame.map_err(|err| err as Box<dyn Error>)?.into_data() {
+                stream.send_data(data, false)?;
+            }
+        }
+        stream.send_data(Bytes::new(), true)?;
     }
+    let response = response_future.await?;
+    let first_byte = Instant::now();
+
+    info!(target: HTTP, status = %response.status(), "Status h2c");
+
+    // Hand the window back as data is read, or the server stalls on large bodies
+    let (parts, body) = response.into_parts();
+    let data = stream::unfold(body, |mut body| async move {
+        let chunk = body.data().await?.and_then(|chunk| {
+            body.flow_control().release_capacity(chunk.len())?;
+            Ok(chunk)
+        });
+        Some((chunk.map(Frame::data).map_err(|err| err.into()), body))
+    });
 
-    Ok(())
+    Ok(RawResponse {
+        status: parts.status,
+        version: parts.version,
+        headers: parts.headers,
+        body: StreamBody::new(data).boxed_unsync(),
+        first_byte,
+    })
 }
 
 // Wrap with TLS using ALP
-async fn wrap_stream_with_tls(
+pub(super) async fn wrap_stream_with_tls(
     tcp: TcpStream,
-    domain: &String,
-) -> Result<Option<Box<dyn Streamable>>, Box<dyn Error>> {
-    let server_name = ServerName::try_from(domain.clone())?;
+    domain: &str,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> Result<(Box<dyn Streamable>, TlsInfo), Box<dyn Error>> {
+    let server_name = ServerName::try_from(domain.to_string())?;
 
     let mut root_store = rustls::RootCertStore::empty();
     for cert in load_native_certs().expect("Could not load platform certificates") {
@@ -251,73 +671,102 @@ async fn wrap_stream_with_tls(
         .with_root_certificates(root_store)
         .with_no_client_auth();
     // Configure ALPN protocols (order matters!)
-    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()]; // Prefer HTTP/2
+    tls_config.alpn_protocols = alpn_protocols;
 
     let connector = TlsConnector::from(Arc::new(tls_config));
     let mut tls = connector.connect(server_name, tcp).await?;
 
+    let mut certificates = vec![];
     let (_, client_connection) = tls.get_mut();
-    match client_connection.peer_certificates() {
-        Some(certs) => {
-            for der_cert in certs {
-                // These are DER-encoded bytes (https://datatracker.ietf.org/doc/html/rfc5280)
-                let raw_cert = &der_cert.to_vec();
-                let (_, decoded_cert) = x509_parser::parse_x509_certificate(raw_cert)?;
-
-                println!("Certificate Version: {}", &decoded_cert.version);
-                println!("Certificate Issuer: {}", &decoded_cert.issuer);
-                println!("Certificate Subject: {}", &decoded_cert.subject);
-                let cert_validity = &decoded_cert.validity;
-                let start = &cert_validity.not_before;
-                let end = &cert_validity.not_after;
-                println!("Certificate Validity: From {} until {}", start, end);
-                println!("...");
-            }
+    if let Some(certs) = client_connection.peer_certificates() {
+        for (depth, der_cert) in certs.iter().enumerate() {
+            // These are DER-encoded bytes (https://datatracker.ietf.org/doc/html/rfc5280)
+            let raw_cert = &der_cert.to_vec();
+            let (_, decoded_cert) = x509_parser::parse_x509_certificate(raw_cert)?;
+
+            let cert_validity = &decoded_cert.validity;
+            info!(
+                target: TLS,
+                depth,
+                version = %decoded_cert.version,
+                issuer = %decoded_cert.issuer,
+                subject = %decoded_cert.subject,
+                not_before = %cert_validity.not_before,
+                not_after = %cert_validity.not_after,
+                "Peer certificate"
+            );
+
+            certificates.push(CertificateInfo {
+                subject: decoded_cert.subject.to_string(),
+                issuer: decoded_cert.issuer.to_string(),
+                not_before: cert_validity.not_before.to_string(),
+                not_after: cert_validity.not_after.to_string(),
+            });
         }
-        None => {}
     }
 
     let (_, session) = tls.get_ref();
-    if session.alpn_protocol() != Some(b"h2") {
-        return Err("Server didn't negotiate HTTP/2".into());
-    }
+    let info = TlsInfo {
+        version: session
+            .protocol_version()
+            .map(|v| format!("{:?}", v))
+            .unwrap_or_default(),
+        cipher_suite: session
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite()))
+            .unwrap_or_default(),
+        alpn: session
+            .alpn_protocol()
+            .map(|p| String::from_utf8_lossy(p).into_owned()),
+        certificates,
+    };
+    info!(
+        target: TLS,
+        version = %info.version,
+        cipher_suite = %info.cipher_suite,
+        alpn = info.alpn.as_deref().unwrap_or("none"),
+        "TLS handshake complete"
+    );
 
     let tokio_io = TokioIo::new(tls);
-    Ok(Some(Box::new(tokio_io)))
+    Ok((Box::new(tokio_io), info))
 }
 
 async fn process_stream(
+    method: Method,
     parsed_url: &Url,
-    scheme: &str,
-    domain: String,
+    headers: &HeaderMap,
+    body: OutgoingBody,
     io: Box<dyn Streamable>,
-) -> Result<(), Box<dyn Error>> {
+    settings: &Http2Settings,
+) -> Result<RawResponse, Box<dyn Error>> {
     let (mut sender, conn) = http2::Builder::new(TokioExecutor::new())
-        .initial_stream_window_size(65535)
-        .initial_connection_window_size(1_048_576)
+        .initial_stream_window_size(settings.stream_window)
+        .initial_connection_window_size(settings.connection_window)
+        .adaptive_window(settings.adaptive_window)
         .max_frame_size(16_384)
         .handshake(io)
         .await?;
 
     tokio::spawn(async move {
         if let Err(err) = conn.await {
-            eprintln!("Connection failed: {:?}", err);
+            error!(target: CONNECTION, error = ?err, "Connection failed");
         }
     });
 
-    println!("{}", format!("{}://{}", scheme, domain.clone()));
-
-    let req: Request<Empty<Bytes>> = Request::builder()
-        .uri(format!("{}://{}", scheme, domain.clone()))
-        .header(header::HOST, parsed_url.domain().unwrap_or_default())
-        .header(header::USER_AGENT, "my-client/0.1.0")
-        .header(header::ACCEPT, "*/*")
-        .body(Empty::new())?;
+    let mut req = Request::builder()
+        .uri(parsed_url.as_str())
+        .method(method)
+        .body(body)?;
+    req.headers_mut().extend(headers.clone());
+    // HTTP/2 carries the authority in the URI rather than the Host header
+    req.headers_mut().remove(header::HOST);
 
     let res = sender.send_request(req).await?;
 
-    println!("Status: {}", res.status());
-    Ok(())
+    info!(target: HTTP, status = %res.status(), "Status");
+
+    Ok(raw_response(res))
 }
 
 fn version_to_string(version: Version) -> String {
@@ -331,3 +780,66 @@ fn version_to_string(version: Version) -> String {
     }
     .to_string()
 }
+
+#[tokio::test]
+async fn test_outgoing_resend() -> Result<(), Box<dyn Error>> {
+    // A multipart body is rebuilt for every send
+    let multipart = RequestBody::Multipart(crate::forms::Multipart::new(vec![
+        crate::forms::Field::parse("title=Report")?,
+    ]));
+    let first = outgoing(&multipart)?
+        .collect()
+        .await
+        .map_err(|err| err.to_string())?
+        .to_bytes();
+    let second = outgoing(&multipart)?
+        .collect()
+        .await
+        .map_err(|err| err.to_string())?
+        .to_bytes();
+    assert!(!first.is_empty());
+    assert_eq!(first, second);
+
+    // A streamed one only exists once
+    let chunks = stream::iter([Ok(Bytes::from("chunk"))]).boxed();
+    let streamed = RequestBody::Stream(OutgoingStream::new(chunks));
+    assert_eq!(
+        outgoing(&streamed)?
+            .collect()
+            .await
+            .map_err(|err| err.to_string())?
+            .to_bytes(),
+        "chunk"
+    );
+    assert!(outgoing(&streamed).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_redirect_credentials() -> Result<(), Box<dyn Error>> {
+    use crate::auth::ApiKey;
+
+    let api_key = ApiKey::parse("X-API-Key=s3cret", ApiKeyLocation::Header)?;
+    let mut call = ApiCall::builder(Method::POST, "https://api.example.com/v1")
+        .api_key(api_key)
+        .build()?;
+
+    // Same origin, the key goes along
+    redirect(
+        &mut call,
+        StatusCode::TEMPORARY_REDIRECT,
+        Url::parse("https://api.example.com/v2")?,
+    );
+    assert_eq!(call.headers["x-api-key"], "s3cret");
+    assert_eq!(call.method, Method::POST);
+
+    // Another host never sees it
+    redirect(
+        &mut call,
+        StatusCode::FOUND,
+        Url::parse("https://cdn.example.net/v2")?,
+    );
+    assert!(!call.headers.contains_key("x-api-key"));
+    assert_eq!(call.method, Method::GET);
+    Ok(())
+}
@@ -1,15 +1,37 @@
+pub mod graphql;
 pub mod grpc;
 pub mod http;
+pub mod sse;
 pub mod websockets;
 
 use async_trait::async_trait;
-use base64::{Engine, engine::general_purpose};
 use clap::ValueEnum;
 use encoding_rs::{Encoding, UTF_8};
-use hyper::Method;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderName, HeaderValue};
+use hyper::{HeaderMap, Method};
 use mime::Mime;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, net::SocketAddr};
+use std::{
+    error::Error,
+    fmt::{self, Debug},
+    io::{BufWriter, Write, stdout},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::auth::sigv4::SigV4;
+use crate::auth::{self, ApiKey, Auth};
+use crate::cookies::CookieJar;
+use crate::download::Download;
+use crate::forms::{self, Field, FormPart, Multipart};
+use crate::render::{self, RenderOptions, markup::Dialect};
+use http::Http2Settings;
 
 #[derive(Debug, Clone, ValueEnum, Serialize, Deserialize)]
 pub enum Protocol {
@@ -22,13 +44,181 @@ pub enum Protocol {
 
 #[async_trait]
 pub trait ApiProtocol {
-    // TODO: refactor to a builder pattern
-    async fn execute(
-        &self,
-        method: Method,
-        url: &str,
-        h2c: bool,
-    ) -> Result<(ApiRequest, ApiResponse), Box<dyn Error>>;
+    async fn execute(&self, call: ApiCall) -> Result<(ApiRequest, ApiResponse), Box<dyn Error>>;
+}
+
+/// A request to perform, before any protocol specifics are applied
+pub struct ApiCall {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: RequestBody,
+    /// The form fields making up the body, for display
+    pub form: Option<Vec<FormPart>>,
+    pub auth: Option<Auth>,
+    /// Already applied to the headers or URL, kept so a redirect knows what to drop
+    pub api_key: Option<ApiKey>,
+    pub aws_sigv4: Option<SigV4>,
+    pub cookie_jar: Option<Arc<Mutex<CookieJar>>>,
+    /// How many redirects to follow, none by default
+    pub max_redirects: usize,
+    /// Save the response body to a file instead of keeping it in memory
+    pub download: Option<Download>,
+    /// Hand the response body over as it arrives instead of collecting it
+    pub stream: bool,
+    pub h2c: bool,
+    pub http2: Http2Settings,
+}
+
+pub struct ApiCallBuilder {
+    method: Method,
+    url: String,
+    headers: HeaderMap,
+    body: RequestBody,
+    form: Option<(Vec<Field>, bool)>,
+    auth: Option<Auth>,
+    api_key: Option<ApiKey>,
+    aws_sigv4: Option<SigV4>,
+    cookie_jar: Option<Arc<Mutex<CookieJar>>>,
+    max_redirects: usize,
+    download: Option<Download>,
+    stream: bool,
+    h2c: bool,
+    http2: Http2Settings,
+}
+
+impl ApiCall {
+    pub fn builder(method: Method, url: &str) -> ApiCallBuilder {
+        ApiCallBuilder {
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: RequestBody::Empty,
+            form: None,
+            auth: None,
+            api_key: None,
+            aws_sigv4: None,
+            cookie_jar: None,
+            max_redirects: 0,
+            download: None,
+            stream: false,
+            h2c: false,
+            http2: Http2Settings::default(),
+        }
+    }
+}
+
+impl ApiCallBuilder {
+    pub fn header(mut self, key: impl Into<HeaderName>, value: impl Into<HeaderValue>) -> Self {
+        self.headers.insert(key.into(), value.into());
+        self
+    }
+    pub fn body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            self.headers.insert(CONTENT_TYPE, value);
+        }
+        self.body = RequestBody::Bytes(body.into());
+        self
+    }
+    /// Sends the fields as `multipart/form-data`, or as a classic urlencoded form
+    pub fn form(mut self, fields: Vec<Field>, urlencoded: bool) -> Self {
+        self.form = Some((fields, urlencoded));
+        self
+    }
+    pub fn auth(mut self, auth: Auth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+    pub fn api_key(mut self, api_key: ApiKey) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+    pub fn aws_sigv4(mut self, aws_sigv4: SigV4) -> Self {
+        self.aws_sigv4 = Some(aws_sigv4);
+        self
+    }
+    pub fn cookie_jar(mut self, cookie_jar: Arc<Mutex<CookieJar>>) -> Self {
+        self.cookie_jar = Some(cookie_jar);
+        self
+    }
+    pub fn follow_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+    pub fn download(mut self, download: Download) -> Self {
+        self.download = Some(download);
+        self
+    }
+    pub fn stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+    pub fn h2c(mut self, h2c: bool) -> Self {
+        self.h2c = h2c;
+        self
+    }
+    pub fn http2(mut self, http2: Http2Settings) -> Self {
+        self.http2 = http2;
+        self
+    }
+    /// Sends the chunks as they're produced, for bodies that aren't all known up front
+    pub fn body_stream(mut self, content_type: &str, body: OutgoingStream) -> Self {
+        if let Ok(value) = HeaderValue::from_str(content_type) {
+            self.headers.insert(CONTENT_TYPE, value);
+        }
+        self.body = RequestBody::Stream(body);
+        self
+    }
+
+    pub fn build(self) -> Result<ApiCall, Box<dyn Error>> {
+        let mut url = Url::parse(&self.url)?;
+        let mut headers = self.headers;
+
+        if let Some(api_key) = &self.api_key {
+            api_key.apply(&mut url, &mut headers)?;
+        }
+        if let Some(auth) = &self.auth {
+            auth.apply(&mut headers)?;
+        }
+        if let Some(download) = &self.download {
+            download.apply(&mut headers)?;
+        }
+
+        let mut body = self.body;
+        let mut form = None;
+        if let Some((fields, urlencoded)) = self.form {
+            form = Some(forms::parts(&fields)?);
+            if urlencoded {
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-www-form-urlencoded"),
+                );
+                body = RequestBody::Bytes(forms::urlencoded(&fields)?.into_bytes());
+            } else {
+                let multipart = Multipart::new(fields);
+                headers.insert(CONTENT_TYPE, multipart.content_type().parse()?);
+                headers.insert(CONTENT_LENGTH, multipart.content_length()?.into());
+                body = RequestBody::Multipart(multipart);
+            }
+        }
+
+        Ok(ApiCall {
+            method: self.method,
+            url,
+            headers,
+            body,
+            form,
+            auth: self.auth,
+            api_key: self.api_key,
+            aws_sigv4: self.aws_sigv4,
+            cookie_jar: self.cookie_jar,
+            max_redirects: self.max_redirects,
+            download: self.download,
+            stream: self.stream,
+            h2c: self.h2c,
+            http2: self.http2,
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,61 +228,264 @@ pub struct ApiResponse {
     pub status: Option<u16>,
     pub headers: Option<Vec<(String, String)>>,
     pub body: Option<Vec<u8>>,
+    /// Headers sent after the body, as gRPC does with its status
+    pub trailers: Option<Vec<(String, String)>>,
+    /// The body when it's still arriving, see `ApiCall::stream`
+    #[serde(skip)]
+    pub stream: Option<BodyStream>,
     pub version: String,
     pub ip: Option<SocketAddr>,
     pub duration: std::time::Duration,
+    pub timings: Timings,
+    pub tls: Option<TlsInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Header names and values, in the order they were sent
+pub type Headers = Vec<(String, String)>;
+
+/// Body chunks as they're sent or received
+pub type Chunks = BoxStream<'static, Result<Bytes, Box<dyn Error + Send + Sync>>>;
+
+/// Response body chunks, in the order and sizes they came off the wire
+pub struct BodyStream {
+    pub chunks: Chunks,
+    /// Filled in when the body ends, if the server sent trailers after it
+    pub trailers: Arc<Mutex<Option<Headers>>>,
+}
+
+impl Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+/// Where the time went while performing a request, each phase measured on its own
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Timings {
+    pub dns: Duration,
+    pub connect: Duration,
+    pub tls: Option<Duration>,
+    /// From the start of the request until the response head arrived
+    pub first_byte: Duration,
+    pub total: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsInfo {
+    pub version: String,
+    pub cipher_suite: String,
+    pub alpn: Option<String>,
+    pub certificates: Vec<CertificateInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: String,
+    pub not_after: String,
+}
+
+/// What to send as the request body
+#[derive(Debug, Clone, Default)]
+pub enum RequestBody {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    Multipart(Multipart),
+    Stream(OutgoingStream),
+}
+
+/// Request body chunks produced while the request is under way, so it can only be sent once
+#[derive(Clone)]
+pub struct OutgoingStream(Arc<Mutex<Option<Chunks>>>);
+
+impl OutgoingStream {
+    pub fn new(chunks: Chunks) -> Self {
+        OutgoingStream(Arc::new(Mutex::new(Some(chunks))))
+    }
+
+    /// The chunks, unless they were already sent
+    pub fn take(&self) -> Option<Chunks> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+impl Debug for OutgoingStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutgoingStream")
+    }
+}
+
+impl RequestBody {
+    /// The body when it's held in memory, for schemes that need to hash it
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            RequestBody::Empty => Some(&[]),
+            RequestBody::Bytes(bytes) => Some(bytes),
+            RequestBody::Multipart(_) | RequestBody::Stream(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiRequest {
     pub headers: Option<Vec<(String, String)>>,
     pub method: String,
     pub path: String,
     pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub form: Option<Vec<FormPart>>,
+}
+
+impl ApiRequest {
+    /// A copy that's safe to show, with credentials in headers and query parameters masked
+    pub fn masked(&self) -> ApiRequest {
+        ApiRequest {
+            headers: self.headers.as_ref().map(|headers| {
+                headers
+                    .iter()
+                    .map(|(name, value)| (name.clone(), auth::mask_header(name, value)))
+                    .collect()
+            }),
+            path: auth::mask_url(&self.path),
+            ..self.clone()
+        }
+    }
 }
 
 impl ApiResponse {
-    pub fn render_body(&self) {
+    pub fn render_body(&self, options: &RenderOptions) {
         let (mime, charset) = self.parse_content_type();
 
-        let data = match &self.body {
-            Some(d) => d,
-            None => todo!(),
+        // Nothing to show, e.g. a HEAD response or a body saved to a file
+        let Some(data) = &self.body else {
+            return;
         };
 
-        if Self::is_text_based(&mime) {
-            self.render_text_content(data, &mime, charset)
+        // Magic bytes win over whatever the Content-Type claims
+        if Self::is_text_based(&mime) && render::binary::sniff(data).is_none() {
+            self.render_text_content(data, &mime, charset, options)
         } else {
-            self.render_binary_content(data, &mime)
+            self.render_binary_content(data, &mime, options)
+        }
+    }
+
+    /// Writes the body out chunk by chunk as it arrives, until it ends or Ctrl-C is pressed
+    pub async fn render_stream(&mut self, show_chunks: bool) -> Result<(), Box<dyn Error>> {
+        let Some(BodyStream { mut chunks, .. }) = self.stream.take() else {
+            return Ok(());
+        };
+
+        // Once we listen for Ctrl-C it no longer kills us, so whatever was printed stays printed
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        let mut out = stdout();
+        let (mut count, mut total) = (0, 0);
+        let mut last = Instant::now();
+        loop {
+            let chunk = tokio::select! {
+                chunk = chunks.next() => chunk,
+                _ = &mut interrupted => {
+                    out.flush()?;
+                    eprintln!("\n* Interrupted after {} bytes in {} chunks", total, count);
+                    return Ok(());
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let chunk = chunk.map_err(|err| err as Box<dyn Error>)?;
+
+            count += 1;
+            total += chunk.len();
+            if show_chunks {
+                out.flush()?;
+                eprintln!(
+                    "* chunk {}: {} bytes (+{:.1?})",
+                    count,
+                    chunk.len(),
+                    last.elapsed()
+                );
+            }
+            last = Instant::now();
+
+            out.write_all(&chunk)?;
+            out.flush()?;
+        }
+
+        if show_chunks {
+            eprintln!("* end of body: {} bytes in {} chunks", total, count);
         }
+        Ok(())
     }
 
-    fn render_text_content(&self, data: &[u8], mime: &Mime, charset: Option<&'static Encoding>) {
+    /// The body decoded as text, if the content type says it is text and it decodes cleanly
+    pub fn body_text(&self) -> Option<String> {
+        let (mime, charset) = self.parse_content_type();
+        let data = self.body.as_deref()?;
+        if !Self::is_text_based(&mime) {
+            return None;
+        }
+
+        let (text, _, had_errors) = charset.unwrap_or(UTF_8).decode(data);
+        (!had_errors).then(|| text.into_owned())
+    }
+
+    fn render_text_content(
+        &self,
+        data: &[u8],
+        mime: &Mime,
+        charset: Option<&'static Encoding>,
+        options: &RenderOptions,
+    ) {
         let encoding = charset.unwrap_or(UTF_8);
         let (text, _actual_encoding, had_errors) = encoding.decode(data);
         had_errors.then(|| eprintln!("⚠️  Decoding had errors for encoding: {:?}", encoding));
 
-        match mime {
-            _ if mime.type_() == mime::APPLICATION && mime.subtype() == mime::JSON => {
-                println!("{}", text.into_owned())
+        let out = BufWriter::new(stdout().lock());
+        let subtype = mime.subtype().as_str();
+        let suffix = mime.suffix().map(|s| s.as_str());
+
+        let rendered = match (mime.type_(), subtype, suffix) {
+            _ if Self::is_json(mime) => render::json::render(&text, options, out),
+            (mime::TEXT, "html", _) | (_, "xhtml", Some("xml")) => {
+                render::markup::render(&text, Dialect::Html, options, out)
+            }
+            (_, "xml", _) | (_, _, Some("xml")) => {
+                render::markup::render(&text, Dialect::Xml, options, out)
             }
-            _ if mime.type_() == mime::TEXT && mime.subtype() == mime::HTML => {
-                println!("{}", text.into_owned())
+            (_, "yaml" | "x-yaml", _) | (_, _, Some("yaml")) => {
+                render::yaml::render(&text, options, out)
             }
-            _ => println!("⚠️  Couldn't figure out what the heck this is..."),
+            (mime::TEXT, "csv", _) => render::csv::render(&text, options, out),
+            (mime::APPLICATION, "x-www-form-urlencoded", _) => {
+                render::form::render(&text, options, out)
+            }
+            (_, "javascript" | "ecmascript" | "x-javascript", _) => {
+                render::script::render(&text, options, out)
+            }
+            _ => render::plain(&text, out),
+        };
+
+        if let Err(err) = rendered {
+            eprintln!("⚠️  Failed to render {}: {}", mime.essence_str(), err);
         }
     }
 
-    fn render_binary_content(&self, data: &[u8], mime: &Mime) {
-        let _ = general_purpose::STANDARD.encode(data);
-        match mime.subtype().as_str() {
-            "png" | "jpeg" | "gif" => todo!("throw some binary output"),
-            "pdf" => todo!("throw some binary output"),
-            _ => println!("⚠️  Couldn't figure out what the heck this is..."),
+    fn render_binary_content(&self, data: &[u8], mime: &Mime, options: &RenderOptions) {
+        let out = BufWriter::new(stdout().lock());
+        let rendered = match render::protobuf::is_protobuf(mime.essence_str()) {
+            true => render::protobuf::render(data, options, out),
+            false => render::binary::render(data, mime.essence_str(), options, out),
+        };
+        if let Err(err) = rendered {
+            eprintln!("⚠️  Failed to render {}: {}", mime.essence_str(), err);
         }
     }
 
-    fn parse_content_type(&self) -> (Mime, Option<&'static Encoding>) {
+    pub fn parse_content_type(&self) -> (Mime, Option<&'static Encoding>) {
         let header_value = match self.headers.as_ref().and_then(|headers| {
             headers
                 .iter()
@@ -118,15 +511,29 @@ impl ApiResponse {
         (mime, charset)
     }
 
-    fn is_text_based(mime: &Mime) -> bool {
+    /// Whether this is a Server-Sent Events stream that's still being read
+    pub fn is_event_stream(&self) -> bool {
+        self.stream.is_some() && self.parse_content_type().0.essence_str() == "text/event-stream"
+    }
+
+    fn is_json(mime: &Mime) -> bool {
+        mime.type_() == mime::APPLICATION
+            && (mime.subtype() == mime::JSON || mime.suffix() == Some(mime::JSON))
+    }
+
+    pub fn is_text_based(mime: &Mime) -> bool {
         let subtype = mime.subtype().as_str();
         match (mime.type_(), subtype) {
             (mime::TEXT, _) => true,
             (mime::APPLICATION, "json") => true,
             (mime::APPLICATION, "xml") => true,
-            (mime::APPLICATION, "javascript") => true,
+            (mime::APPLICATION, "javascript" | "ecmascript" | "x-javascript") => true,
+            (mime::APPLICATION, "yaml" | "x-yaml") => true,
             (mime::APPLICATION, "x-www-form-urlencoded") => true,
-            (mime::APPLICATION, s) => s.ends_with("+json") || s.ends_with("+xml"),
+            (mime::APPLICATION, _) => matches!(
+                mime.suffix().map(|s| s.as_str()),
+                Some("json" | "xml" | "yaml")
+            ),
             _ => false,
         }
     }
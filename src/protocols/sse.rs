@@ -0,0 +1,401 @@
+//! Server-Sent Events (`text/event-stream`), parsed as the HTML Living Standard describes
+
+use std::error::Error;
+use std::future::Future;
+use std::io::{Write, stdout};
+use std::time::Duration;
+
+use ansi_term::Color::{Cyan, Yellow};
+use ansi_term::Style;
+use futures_util::StreamExt;
+use hyper::HeaderMap;
+use hyper::header::CONTENT_TYPE;
+use regex::Regex;
+use serde::Serialize;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use tracing::{info, warn};
+
+use super::{ApiCall, ApiProtocol, ApiResponse, BodyStream};
+use crate::diagnostics::HTTP;
+use crate::render::{self, RenderOptions};
+
+// What browsers wait before reconnecting when the server hasn't said otherwise
+const DEFAULT_RETRY: Duration = Duration::from_secs(3);
+
+/// One dispatched event
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub received: String,
+    pub event: String,
+    pub data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Reconnection time in milliseconds, when this event set one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<u64>,
+}
+
+/// Turns chunks of the stream into events, whatever the chunk boundaries
+#[derive(Debug, Default)]
+pub struct Parser {
+    line: Vec<u8>,
+    // A CR ends a line too, so a LF straight after it is part of the same line break
+    after_cr: bool,
+    started: bool,
+    event: Option<String>,
+    data: String,
+    retry: Option<u64>,
+    // The latest `id:`, which only counts once its event is dispatched
+    id: Option<String>,
+    /// The id the stream is at, sent back as `Last-Event-ID` when reconnecting
+    pub last_event_id: Option<String>,
+    /// How long the server asked us to wait before reconnecting
+    pub reconnect_after: Option<Duration>,
+}
+
+impl Parser {
+    /// Drops whatever a dropped connection left half read, keeping the id and retry for the next
+    pub fn reset(&mut self) {
+        *self = Parser {
+            id: self.last_event_id.clone(),
+            last_event_id: self.last_event_id.take(),
+            reconnect_after: self.reconnect_after,
+            ..Parser::default()
+        };
+    }
+
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Vec<Event> {
+        if !self.started && !chunk.is_empty() {
+            self.started = true;
+            chunk = chunk.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(chunk);
+        }
+
+        let mut events = vec![];
+        for &byte in chunk {
+            match byte {
+                b'\n' if self.after_cr => self.after_cr = false,
+                b'\n' | b'\r' => {
+                    self.after_cr = byte == b'\r';
+                    let line =
+                        String::from_utf8_lossy(&std::mem::take(&mut self.line)).into_owned();
+                    events.extend(self.process(&line));
+                }
+                _ => {
+                    self.after_cr = false;
+                    self.line.push(byte);
+                }
+            }
+        }
+        events
+    }
+
+    fn process(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => {
+                self.data.push_str(value);
+                self.data.push('\n');
+            }
+            "id" if !value.contains('\0') => {
+                self.id = Some(value.to_string()).filter(|id| !id.is_empty());
+            }
+            "retry" if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                if let Ok(retry) = value.parse() {
+                    self.retry = Some(retry);
+                    self.reconnect_after = Some(Duration::from_millis(retry));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<Event> {
+        self.last_event_id = self.id.clone();
+        let event = self.event.take();
+        let retry = self.retry.take();
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut data = std::mem::take(&mut self.data);
+        data.pop();
+        Some(Event {
+            received: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            event: event
+                .filter(|event| !event.is_empty())
+                .unwrap_or_else(|| "message".to_string()),
+            data,
+            id: self.last_event_id.clone(),
+            retry,
+        })
+    }
+}
+
+/// When to stop listening, for scripts
+#[derive(Debug, Clone, Default)]
+pub struct StopAt {
+    pub max_events: Option<usize>,
+    /// Stop after the first event whose data matches
+    pub until: Option<Regex>,
+}
+
+/// Why reading a connection's events ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The server closed the stream, it's fine to reconnect
+    Ended,
+    /// A stop condition was met or Ctrl-C was pressed
+    Stopped,
+}
+
+/// Events read so far over one or more connections
+pub struct Session {
+    stop_at: StopAt,
+    parser: Parser,
+    received: usize,
+    json: bool,
+}
+
+impl Session {
+    /// Events print as colored records, or as JSON lines with `json`
+    pub fn new(stop_at: StopAt, json: bool) -> Session {
+        Session {
+            stop_at,
+            parser: Parser::default(),
+            received: 0,
+            json,
+        }
+    }
+
+    /// Connects, and reconnects with `Last-Event-ID` whenever the server drops the stream
+    pub async fn run<F, Fut>(
+        &mut self,
+        client: &dyn ApiProtocol,
+        connect: F,
+        options: &RenderOptions,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(Option<String>) -> Fut,
+        Fut: Future<Output = Result<ApiCall, Box<dyn Error>>>,
+    {
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        loop {
+            self.parser.reset();
+            let call = connect(self.parser.last_event_id.clone()).await?;
+            match client.execute(call).await {
+                // 204 is how a server tells clients to stop reconnecting
+                Ok((_, response)) if response.status == Some(204) => {
+                    info!(target: HTTP, "Server asked us not to reconnect");
+                    return Ok(());
+                }
+                Ok((_, response)) if !response.status.is_some_and(|s| (200..300).contains(&s)) => {
+                    return Err(format!(
+                        "The event stream failed with status {}",
+                        response.status.unwrap_or_default()
+                    )
+                    .into());
+                }
+                Ok((_, mut response)) if response.is_event_stream() => {
+                    if self.consume(&mut response, options).await? == Outcome::Stopped {
+                        return Ok(());
+                    }
+                }
+                Ok(_) => return Err("The response isn't a text/event-stream".into()),
+                Err(err) => warn!(target: HTTP, error = %err, "Event stream connection failed"),
+            }
+
+            let wait = self.parser.reconnect_after.unwrap_or(DEFAULT_RETRY);
+            eprintln!(
+                "* Reconnecting in {:?}{}",
+                wait,
+                self.parser
+                    .last_event_id
+                    .as_ref()
+                    .map(|id| format!(" from event {}", id))
+                    .unwrap_or_default()
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = &mut interrupted => return Ok(()),
+            }
+        }
+    }
+
+    /// Prints events from one response until it ends, a stop condition is met or Ctrl-C
+    pub async fn consume(
+        &mut self,
+        response: &mut ApiResponse,
+        options: &RenderOptions,
+    ) -> Result<Outcome, Box<dyn Error>> {
+        let Some(BodyStream { mut chunks, .. }) = response.stream.take() else {
+            return Ok(Outcome::Ended);
+        };
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(interrupted);
+
+        loop {
+            let chunk = tokio::select! {
+                chunk = chunks.next() => chunk,
+                _ = &mut interrupted => {
+                    eprintln!("\n* Stopped after {} events", self.received);
+                    return Ok(Outcome::Stopped);
+                }
+            };
+            let chunk = match chunk {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(err)) => {
+                    warn!(target: HTTP, error = %err, "Event stream broke off");
+                    return Ok(Outcome::Ended);
+                }
+                None => return Ok(Outcome::Ended),
+            };
+
+            for event in self.parser.feed(&chunk) {
+                self.print(&event, options)?;
+                self.received += 1;
+
+                let matched = self
+                    .stop_at
+                    .until
+                    .as_ref()
+                    .is_some_and(|until| until.is_match(&event.data));
+                let enough = self
+                    .stop_at
+                    .max_events
+                    .is_some_and(|max| self.received >= max);
+                if matched || enough {
+                    return Ok(Outcome::Stopped);
+                }
+            }
+        }
+    }
+
+    fn print(&self, event: &Event, options: &RenderOptions) -> Result<(), Box<dyn Error>> {
+        let mut out = stdout().lock();
+        if self.json {
+            writeln!(out, "{}", serde_json::to_string(event)?)?;
+            out.flush()?;
+            return Ok(());
+        }
+
+        let paint = |style: Style, text: &str| match options.color {
+            true => style.paint(text).to_string(),
+            false => text.to_string(),
+        };
+        let received = OffsetDateTime::parse(&event.received, &Rfc3339)
+            .ok()
+            .and_then(|at| {
+                at.format(format_description!(
+                    "[hour]:[minute]:[second].[subsecond digits:3]"
+                ))
+                .ok()
+            })
+            .unwrap_or_default();
+
+        let mut head = format!(
+            "{} {}",
+            paint(Style::new().dimmed(), &received),
+            paint(Cyan.bold(), &event.event)
+        );
+        if let Some(id) = &event.id {
+            head.push_str(&format!(
+                " {}",
+                paint(Yellow.normal(), &format!("id={}", id))
+            ));
+        }
+        if let Some(retry) = event.retry {
+            head.push_str(&format!(" retry={}ms", retry));
+        }
+        writeln!(out, "{}", head)?;
+
+        // JSON payloads get the same treatment as JSON bodies
+        let mut data = vec![];
+        match serde_json::from_str::<serde::de::IgnoredAny>(&event.data) {
+            Ok(_) => render::json::render(&event.data, options, &mut data)?,
+            Err(_) => writeln!(data, "{}", event.data)?,
+        }
+        for line in String::from_utf8_lossy(&data).lines() {
+            writeln!(out, "  {}", line)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().starts_with("text/event-stream"))
+}
+
+#[test]
+fn test_parse_events() {
+    let mut parser = Parser::default();
+    let mut events = parser.feed(b"\xEF\xBB\xBF: comment\nevent: tick\nda");
+    events.extend(parser.feed(b"ta: one\r\ndata:two\r"));
+    events.extend(parser.feed(b"\nid: 7\nretry: 1500\n\n"));
+    events.extend(parser.feed(b"data\n\nid\n\n"));
+
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| {
+            (
+                event.event.as_str(),
+                event.data.as_str(),
+                event.id.as_deref(),
+                event.retry,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("tick", "one\ntwo", Some("7"), Some(1500)),
+            ("message", "", Some("7"), None),
+        ]
+    );
+    assert_eq!(parser.last_event_id, None);
+    assert_eq!(parser.reconnect_after, Some(Duration::from_millis(1500)));
+
+    // A connection dropped mid-event doesn't leak into the next one or move the id
+    parser.feed(
+        b"id: 8
+
+id: 9
+event: half
+data: lost",
+    );
+    assert_eq!(parser.last_event_id.as_deref(), Some("8"));
+    parser.reset();
+    let events = parser.feed(
+        b"data: fresh
+
+",
+    );
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        (events[0].event.as_str(), events[0].data.as_str()),
+        ("message", "fresh")
+    );
+    assert_eq!(events[0].id.as_deref(), Some("8"));
+    assert_eq!(parser.reconnect_after, Some(Duration::from_millis(1500)));
+}
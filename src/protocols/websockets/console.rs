@@ -0,0 +1,182 @@
+//! An interactive line-editing console on top of a WebSocket session
+
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use rustyline_async::{Readline, ReadlineEvent};
+
+use super::{Direction, Message, Transcript, WebSocket, close, report, respond, send};
+
+const HELP: &str = "\
+Lines are sent as text messages. Commands:
+  /ping [text]            send a ping
+  /binary <hex>           send a binary message, e.g. /binary 01 ff 7a
+  /close [code] [reason]  start the closing handshake (default 1000)
+  /save [file]            write the session so far as JSON lines (default websocket.jsonl)
+  /help                   show this help
+  //text                  send a text message that starts with a slash";
+
+/// What a line typed at the console asks for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Send(Message),
+    Save(PathBuf),
+    Help,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        if let Some(text) = line.strip_prefix("//") {
+            return Ok(Command::Send(Message::Text(format!("/{}", text))));
+        }
+        let Some(command) = line.strip_prefix('/') else {
+            return Ok(Command::Send(Message::Text(line.to_string())));
+        };
+
+        let (name, rest) = command.split_once(' ').unwrap_or((command, ""));
+        let rest = rest.trim();
+        match name {
+            "ping" => Ok(Command::Send(Message::Ping(rest.as_bytes().to_vec()))),
+            "binary" => {
+                let hex: String = rest.chars().filter(|c| !c.is_whitespace()).collect();
+                if hex.is_empty() || !hex.len().is_multiple_of(2) {
+                    return Err("Expected /binary followed by pairs of hex digits".to_string());
+                }
+                // Pairs of bytes, so a multibyte character can't be split
+                let bytes = hex
+                    .as_bytes()
+                    .chunks(2)
+                    .map(|pair| {
+                        let high = char::from(pair[0]).to_digit(16)?;
+                        let low = char::from(*pair.get(1)?).to_digit(16)?;
+                        Some((high << 4 | low) as u8)
+                    })
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or_else(|| format!("Not hex: {}", rest))?;
+                Ok(Command::Send(Message::Binary(bytes)))
+            }
+            "close" | "quit" => {
+                let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+                let code = match code {
+                    "" => 1000,
+                    code => code
+                        .parse()
+                        .map_err(|_| format!("Not a close code: {}", code))?,
+                };
+                Ok(Command::Send(Message::Close(Some((
+                    code,
+                    reason.trim().to_string(),
+                )))))
+            }
+            "save" => Ok(Command::Save(PathBuf::from(match rest {
+                "" => "websocket.jsonl",
+                path => path,
+            }))),
+            "help" => Ok(Command::Help),
+            other => Err(format!("Unknown command /{}, try /help", other)),
+        }
+    }
+}
+
+/// Runs the session from the keyboard until either side closes
+pub async fn run(socket: WebSocket, mut transcript: Transcript) -> Result<(), Box<dyn Error>> {
+    let WebSocket {
+        mut reader,
+        mut writer,
+        ..
+    } = socket;
+
+    // Incoming messages go through the readline's writer, so they print above the prompt
+    let (mut readline, mut out) = Readline::new("ws> ".to_string())?;
+    transcript.out = Box::new(out.clone());
+    transcript.kept = Some(vec![]);
+    writeln!(out, "* Type a message to send it, /help for commands")?;
+
+    loop {
+        tokio::select! {
+            message = reader.receive() => {
+                let Some(message) = message? else {
+                    writeln!(out, "* Connection closed without a Close frame")?;
+                    break;
+                };
+                transcript.print(Direction::Received, &message)?;
+                if respond(&mut writer, &mut transcript, message).await? {
+                    break;
+                }
+            }
+            event = readline.readline() => match event? {
+                ReadlineEvent::Line(line) if line.trim().is_empty() => {}
+                ReadlineEvent::Line(line) => {
+                    readline.add_history_entry(line.clone());
+                    match Command::parse(&line) {
+                        Ok(Command::Send(message @ Message::Close(_))) => {
+                            close(&mut reader, &mut writer, &mut transcript, message).await?;
+                            break;
+                        }
+                        Ok(Command::Send(message)) => {
+                            send(&mut writer, &mut transcript, message).await?;
+                        }
+                        Ok(Command::Save(path)) => {
+                            let kept = transcript.kept.as_deref().unwrap_or_default();
+                            let mut lines = kept.join("\n");
+                            lines.push('\n');
+                            match fs::write(&path, lines) {
+                                Ok(()) => writeln!(
+                                    out,
+                                    "* Saved {} messages to {}",
+                                    kept.len(),
+                                    path.display()
+                                )?,
+                                Err(err) => writeln!(out, "* Can't save to {}: {}", path.display(), err)?,
+                            }
+                        }
+                        Ok(Command::Help) => writeln!(out, "{}", HELP)?,
+                        Err(err) => writeln!(out, "* {}", err)?,
+                    }
+                }
+                // Ctrl-C and Ctrl-D both leave politely
+                ReadlineEvent::Eof | ReadlineEvent::Interrupted => {
+                    let normal = Message::Close(Some((1000, String::new())));
+                    close(&mut reader, &mut writer, &mut transcript, normal).await?;
+                    break;
+                }
+            }
+        }
+    }
+
+    readline.flush()?;
+    report(&reader, &writer);
+    Ok(())
+}
+
+#[test]
+fn test_parse_commands() {
+    assert_eq!(
+        Command::parse("hello"),
+        Ok(Command::Send(Message::Text("hello".to_string())))
+    );
+    assert_eq!(
+        Command::parse("//ping"),
+        Ok(Command::Send(Message::Text("/ping".to_string())))
+    );
+    assert_eq!(
+        Command::parse("/binary 01 ff7a"),
+        Ok(Command::Send(Message::Binary(vec![0x01, 0xff, 0x7a])))
+    );
+    assert_eq!(
+        Command::parse("/close 4000 done for today"),
+        Ok(Command::Send(Message::Close(Some((
+            4000,
+            "done for today".to_string()
+        )))))
+    );
+    assert!(Command::parse("/binary abc").is_err());
+    assert!(Command::parse("/binary +1").is_err());
+    assert_eq!(
+        Command::parse("/binary aé1"),
+        Err("Not hex: aé1".to_string())
+    );
+    assert!(Command::parse("/nope").is_err());
+}
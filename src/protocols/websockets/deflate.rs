@@ -0,0 +1,211 @@
+//! The `permessage-deflate` extension (RFC 7692)
+
+use std::error::Error;
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+
+/// What goes in `Sec-WebSocket-Extensions` to ask for it
+///
+/// We don't offer `client_max_window_bits`, so the server can't ask for a smaller window than the
+/// 32K one we compress with
+pub const OFFER: &str = "permessage-deflate";
+
+// Every message is flushed with an empty stored block, which is left off on the wire
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The parameters the server agreed to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Params {
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+    pub server_max_window_bits: Option<u8>,
+}
+
+impl Params {
+    /// Reads the server's `Sec-WebSocket-Extensions`, failing on anything we didn't offer
+    pub fn negotiate(header: &str) -> Result<Option<Params>, Box<dyn Error>> {
+        let mut agreed = None;
+        for extension in header.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = extension.split(';').map(str::trim);
+            match parts.next() {
+                Some("permessage-deflate") if agreed.is_none() => {}
+                _ => {
+                    return Err(format!(
+                        "The server accepted an extension not offered: {}",
+                        extension
+                    )
+                    .into());
+                }
+            }
+
+            let mut params = Params::default();
+            for param in parts {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match (name, value) {
+                    ("server_no_context_takeover", None) => {
+                        params.server_no_context_takeover = true
+                    }
+                    ("client_no_context_takeover", None) => {
+                        params.client_no_context_takeover = true
+                    }
+                    ("server_max_window_bits", Some(bits)) => match bits.parse() {
+                        Ok(bits @ 8..=15) => params.server_max_window_bits = Some(bits),
+                        _ => return Err(format!("Invalid server_max_window_bits: {}", bits).into()),
+                    },
+                    _ => {
+                        return Err(
+                            format!("Unexpected permessage-deflate parameter: {}", param).into(),
+                        );
+                    }
+                }
+            }
+            agreed = Some(params);
+        }
+        Ok(agreed)
+    }
+}
+
+impl std::fmt::Display for Params {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(OFFER)?;
+        if self.server_no_context_takeover {
+            f.write_str("; server_no_context_takeover")?;
+        }
+        if self.client_no_context_takeover {
+            f.write_str("; client_no_context_takeover")?;
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            write!(f, "; server_max_window_bits={}", bits)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compresses the messages we send
+pub struct Deflater {
+    compress: Compress,
+    reset: bool,
+}
+
+impl Deflater {
+    pub fn new(params: Params) -> Deflater {
+        Deflater {
+            compress: Compress::new(Compression::default(), false),
+            reset: params.client_no_context_takeover,
+        }
+    }
+
+    pub fn deflate(&mut self, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let start = self.compress.total_in();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&payload[consumed..], &mut out, FlushCompress::Sync)?;
+            let consumed = (self.compress.total_in() - start) as usize;
+            // A full buffer may be hiding more of the flush
+            if consumed == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if self.reset {
+            self.compress.reset();
+        }
+
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        // An empty message still needs a block, RFC 7692 section 7.2.3.6
+        if out.is_empty() {
+            out.push(0x00);
+        }
+        Ok(out)
+    }
+}
+
+/// Decompresses the messages we receive
+pub struct Inflater {
+    decompress: Decompress,
+    reset: bool,
+}
+
+impl Inflater {
+    pub fn new(params: Params) -> Inflater {
+        Inflater {
+            decompress: Decompress::new(false),
+            reset: params.server_no_context_takeover,
+        }
+    }
+
+    /// Inflates a message, failing once it grows past `limit` bytes
+    pub fn inflate(&mut self, payload: &[u8], limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut input = payload.to_vec();
+        input.extend_from_slice(&TAIL);
+
+        let start = self.decompress.total_in();
+        let mut out =
+            Vec::with_capacity(input.len().saturating_mul(4).min(limit.saturating_add(1)));
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let written = self.decompress.total_out();
+            self.decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|err| format!("Can't inflate the message: {}", err))?;
+            if out.len() > limit {
+                return Err(
+                    format!("The message inflates to over the {} byte limit", limit).into(),
+                );
+            }
+
+            let now_consumed = (self.decompress.total_in() - start) as usize;
+            if now_consumed == input.len() && out.len() < out.capacity() {
+                break;
+            }
+            if now_consumed == consumed && self.decompress.total_out() == written {
+                return Err("Can't inflate the message, the deflate stream stalled".into());
+            }
+            out.reserve(out.capacity().max(1024));
+        }
+        if self.reset {
+            self.decompress.reset(false);
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn test_deflate() {
+    // RFC 7692 section 7.2.3.1: "Hello" compressed in a single frame
+    let params = Params::negotiate("permessage-deflate; server_max_window_bits=10")
+        .unwrap()
+        .unwrap();
+    assert_eq!(params.server_max_window_bits, Some(10));
+    let mut inflater = Inflater::new(params);
+    assert_eq!(
+        inflater
+            .inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 5)
+            .unwrap(),
+        b"Hello"
+    );
+
+    let mut deflater = Deflater::new(Params::default());
+    let mut inflater = Inflater::new(Params::default());
+    let text = "hello hello hello hello ".repeat(200);
+    for _ in 0..2 {
+        let compressed = deflater.deflate(text.as_bytes()).unwrap();
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(
+            inflater.inflate(&compressed, text.len()).unwrap(),
+            text.as_bytes()
+        );
+    }
+    let compressed = deflater.deflate(text.as_bytes()).unwrap();
+    assert!(inflater.inflate(&compressed, 1000).is_err());
+
+    assert!(Params::negotiate("x-webkit-deflate-frame").is_err());
+    assert!(Params::negotiate("permessage-deflate; client_max_window_bits=9").is_err());
+}
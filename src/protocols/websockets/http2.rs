@@ -0,0 +1,231 @@
+//! WebSockets on an HTTP/2 stream, opened with the extended CONNECT from RFC 8441
+
+use std::error::Error;
+use std::future::poll_fn;
+use std::io;
+use std::time::Duration;
+
+use futures_util::{sink, stream};
+use h2::client::{self};
+use h2::ext::Protocol;
+use h2::{Ping, SendStream};
+use hyper::body::Bytes;
+use hyper::header::{
+    HOST, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION,
+};
+use hyper::{HeaderMap, Method, Request, Version};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::{CopyToBytes, SinkWriter, StreamReader};
+use tracing::{debug, error, info};
+
+use super::{Handshake, deflate};
+use crate::diagnostics::{CONNECTION, HTTP};
+use crate::protocols::ApiCall;
+use crate::protocols::http::{self, Streamable};
+
+// Long enough for the server's SETTINGS to arrive, short enough to fall back without fuss
+const SETTINGS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The two directions of the stream the WebSocket runs on
+pub(super) struct Tunnel {
+    pub read: Box<dyn AsyncRead + Send + Unpin>,
+    pub write: Box<dyn AsyncWrite + Send + Unpin>,
+    pub headers: HeaderMap,
+}
+
+/// Opens the WebSocket on a stream of its own, or None when the server can't do that
+pub(super) async fn connect(
+    io: Box<dyn Streamable>,
+    call: &ApiCall,
+    handshake: &Handshake,
+) -> Result<Option<Tunnel>, Box<dyn Error>> {
+    let (mut sender, mut connection) = match client::handshake(TokioIo::new(io)).await {
+        Ok(handshaken) => handshaken,
+        Err(err) => {
+            debug!(target: CONNECTION, error = %err, "HTTP/2 handshake failed");
+            return Ok(None);
+        }
+    };
+    let mut ping_pong = connection
+        .ping_pong()
+        .ok_or("The HTTP/2 connection can't send a PING")?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            error!(target: CONNECTION, error = ?err, "h2 connection error");
+        }
+    });
+
+    // The server's SETTINGS come before its answer to our PING, so after it we know
+    match tokio::time::timeout(SETTINGS_TIMEOUT, ping_pong.ping(Ping::opaque())).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => {
+            debug!(target: CONNECTION, error = %err, "The server doesn't speak HTTP/2");
+            return Ok(None);
+        }
+        Err(_) => {
+            debug!(target: CONNECTION, "No HTTP/2 SETTINGS from the server");
+            return Ok(None);
+        }
+    }
+    if !sender.is_extended_connect_protocol_enabled() {
+        info!(target: HTTP, "The server doesn't advertise SETTINGS_ENABLE_CONNECT_PROTOCOL");
+        return Ok(None);
+    }
+
+    // The scheme is what the HTTP/2 request would use, the `:protocol` says it's a WebSocket
+    let mut url = call.url.clone();
+    let scheme = match url.scheme() {
+        "wss" => "https",
+        _ => "http",
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| format!("Can't send a CONNECT for {}", call.url))?;
+
+    let mut req = Request::builder()
+        .method(Method::CONNECT)
+        .uri(url.as_str())
+        .version(Version::HTTP_2)
+        .extension(Protocol::from_static("websocket"))
+        .header(SEC_WEBSOCKET_VERSION, "13")
+        .body(())?;
+    if !handshake.subprotocols.is_empty() {
+        req.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            handshake.subprotocols.join(", ").parse()?,
+        );
+    }
+    if handshake.deflate {
+        req.headers_mut()
+            .insert(SEC_WEBSOCKET_EXTENSIONS, deflate::OFFER.parse()?);
+    }
+    req.headers_mut().extend(http::default_headers(&call.url)?);
+    req.headers_mut().extend(call.headers.clone());
+    super::add_cookies(call, req.headers_mut())?;
+    // HTTP/2 carries the authority in the URI rather than the Host header
+    req.headers_mut().remove(HOST);
+
+    let (response, send) = sender.send_request(req, false)?;
+    let response = response.await?;
+    info!(target: HTTP, status = %response.status(), "Status h2");
+    super::store_cookies(call, response.headers())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "The server refused the WebSocket CONNECT with {}",
+            response.status()
+        )
+        .into());
+    }
+
+    // Hand the window back as data is read, as for any other HTTP/2 body
+    let (parts, body) = response.into_parts();
+    let incoming = stream::unfold(body, |mut body| async move {
+        let chunk = body.data().await?.and_then(|chunk| {
+            body.flow_control().release_capacity(chunk.len())?;
+            Ok(chunk)
+        });
+        Some((chunk.map_err(io::Error::other), body))
+    });
+    let outgoing = sink::unfold(send, |mut send, data: Bytes| async move {
+        send_data(&mut send, data).await?;
+        Ok::<_, io::Error>(send)
+    });
+
+    Ok(Some(Tunnel {
+        read: Box::new(StreamReader::new(Box::pin(incoming))),
+        write: Box::new(SinkWriter::new(CopyToBytes::new(Box::pin(outgoing)))),
+        headers: parts.headers,
+    }))
+}
+
+// Sends as much as flow control lets through at a time
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> io::Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let available = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(Ok(available)) => available,
+            Some(Err(err)) => return Err(io::Error::other(err)),
+            None => return Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        if available > 0 {
+            let chunk = data.split_to(available.min(data.len()));
+            send.send_data(chunk, false).map_err(io::Error::other)?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fallback_to_http1() -> Result<(), Box<dyn Error>> {
+    use base64::{Engine, engine::general_purpose};
+    use hyper::Method;
+    use sha1::{Digest, Sha1};
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+
+    use crate::cookies::CookieJar;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{ACCEPT_GUID, Transport, WebSocket};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ws://{}/chat", listener.local_addr()?);
+    let server = tokio::spawn(async move {
+        // HTTP/2 with prior knowledge, but without SETTINGS_ENABLE_CONNECT_PROTOCOL
+        let (tcp, _) = listener.accept().await?;
+        let mut connection = h2::server::handshake(tcp).await?;
+        tokio::spawn(async move { while let Some(Ok(_)) = connection.accept().await {} });
+
+        // Then the Upgrade on a connection of its own
+        let (mut tcp, _) = listener.accept().await?;
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(tcp.read_u8().await?);
+        }
+        let request = String::from_utf8(request)?;
+        if !request.contains("cookie: session=abc\r\n") {
+            return Err("The jar's cookie wasn't sent".into());
+        }
+        let key = request
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(": ")?;
+                name.eq_ignore_ascii_case("sec-websocket-key")
+                    .then(|| value.to_string())
+            })
+            .ok_or("No Sec-WebSocket-Key")?;
+        let accept = general_purpose::STANDARD.encode(Sha1::digest(key + ACCEPT_GUID));
+        tcp.write_all(
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\n\
+                 connection: Upgrade\r\nsec-websocket-accept: {}\r\n\
+                 set-cookie: seen=1\r\n\r\n",
+                accept
+            )
+            .as_bytes(),
+        )
+        .await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>(tcp)
+    });
+
+    // The jar's cookies go along with the Upgrade, and the 101's are kept
+    let jar = Arc::new(Mutex::new(CookieJar::default()));
+    let url = Url::parse(&url)?;
+    jar.lock().unwrap().store(&url, "session=abc");
+    let call = ApiCall::builder(Method::GET, url.as_str())
+        .cookie_jar(jar.clone())
+        .build()?;
+    let handshake = super::Handshake {
+        http2: true,
+        ..Default::default()
+    };
+    let websocket = WebSocket::connect(&call, &handshake).await?;
+    assert_eq!(websocket.transport, Transport::Http1Upgrade);
+    server.await?.map_err(|err| err.to_string())?;
+    assert_eq!(
+        jar.lock().unwrap().header_for(&url, &Method::GET, false),
+        Some("session=abc; seen=1".to_string())
+    );
+    Ok(())
+}
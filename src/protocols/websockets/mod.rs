@@ -0,0 +1,1219 @@
+//! WebSocket client (RFC 6455), connected with an HTTP/1.1 Upgrade or an HTTP/2 CONNECT (RFC 8441)
+
+pub mod console;
+pub mod deflate;
+mod http2;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{Write, stdout};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ansi_term::Color::{Cyan, Yellow};
+use base64::{Engine, engine::general_purpose};
+use hyper::header::{
+    CONNECTION, COOKIE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::{HeaderMap, Method, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use rand::RngCore;
+use serde::Serialize;
+use serde::de::IgnoredAny;
+use sha1::{Digest, Sha1};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::ApiCall;
+use super::http::{self, Streamable};
+use crate::diagnostics::{CONNECTION as CONNECTION_TARGET, FRAMES, HTTP};
+use crate::render::{self, RenderOptions};
+use deflate::{Deflater, Inflater, Params};
+
+// RFC 6455 section 1.3, appended to the key to prove the server speaks WebSocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// How long to wait for the server to answer our Close before hanging up anyway
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// permessage-deflate marks the first frame of a compressed message with RSV1
+const RSV1: u8 = 0b100;
+
+/// The most a frame or a whole message may hold once inflated, so a server can't run us out of
+/// memory
+pub const MAX_MESSAGE: usize = 64 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+    Reserved(u8),
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Opcode {
+        match value {
+            0x0 => Opcode::Continuation,
+            0x1 => Opcode::Text,
+            0x2 => Opcode::Binary,
+            0x8 => Opcode::Close,
+            0x9 => Opcode::Ping,
+            0xA => Opcode::Pong,
+            other => Opcode::Reserved(other),
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+            Opcode::Reserved(other) => other,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Opcode::Continuation => f.write_str("continuation"),
+            Opcode::Text => f.write_str("text"),
+            Opcode::Binary => f.write_str("binary"),
+            Opcode::Close => f.write_str("close"),
+            Opcode::Ping => f.write_str("ping"),
+            Opcode::Pong => f.write_str("pong"),
+            Opcode::Reserved(other) => write!(f, "reserved({:#x})", other),
+        }
+    }
+}
+
+/// A single frame as it goes over the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    /// The RSV1-3 bits, for extensions
+    pub rsv: u8,
+    pub opcode: Opcode,
+    pub mask: Option<[u8; 4]>,
+    /// The payload, already unmasked
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: Opcode, payload: Vec<u8>) -> Frame {
+        Frame {
+            fin: true,
+            rsv: 0,
+            opcode,
+            mask: None,
+            payload,
+        }
+    }
+
+    /// Clients mask every frame they send, with a fresh key each time
+    fn masked(mut self) -> Frame {
+        let mut key = [0u8; 4];
+        rand::rng().fill_bytes(&mut key);
+        self.mask = Some(key);
+        self
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![(self.fin as u8) << 7 | (self.rsv & 0x7) << 4 | self.opcode.as_u8()];
+        let mask_bit = (self.mask.is_some() as u8) << 7;
+        match self.payload.len() {
+            len @ 0..=125 => out.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        match self.mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend(apply_mask(&self.payload, key));
+            }
+            None => out.extend_from_slice(&self.payload),
+        }
+        out
+    }
+
+    /// Decodes a frame from the front of `buffer`, returning it with the bytes it took up, or
+    /// None until all of it has arrived
+    ///
+    /// Fails as soon as the header shows a frame that can't be read, before its payload is
+    /// buffered.
+    pub fn decode(buffer: &[u8], limit: usize) -> Result<Option<(Frame, usize)>, FrameError> {
+        let [first, second, ..] = *buffer else {
+            return Ok(None);
+        };
+        let mut at = 2;
+
+        let length = match second & 0x7F {
+            126 => {
+                let Some(bytes) = buffer.get(at..at + 2) else {
+                    return Ok(None);
+                };
+                at += 2;
+                u64::from(u16::from_be_bytes([bytes[0], bytes[1]]))
+            }
+            127 => {
+                let Some(bytes) = buffer.get(at..at + 8) else {
+                    return Ok(None);
+                };
+                at += 8;
+                let length = u64::from_be_bytes([
+                    bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+                ]);
+                // RFC 6455 section 5.2
+                if length >> 63 != 0 {
+                    return Err(FrameError::LengthTopBit);
+                }
+                length
+            }
+            length => u64::from(length),
+        };
+        let length = match usize::try_from(length) {
+            Ok(length) if length <= limit => length,
+            _ => return Err(FrameError::TooBig(length)),
+        };
+
+        let mask = match second & 0x80 != 0 {
+            true => {
+                let Some(key) = buffer.get(at..at + 4) else {
+                    return Ok(None);
+                };
+                at += 4;
+                Some([key[0], key[1], key[2], key[3]])
+            }
+            false => None,
+        };
+
+        let end = at
+            .checked_add(length)
+            .ok_or(FrameError::TooBig(length as u64))?;
+        let Some(payload) = buffer.get(at..end) else {
+            return Ok(None);
+        };
+        let payload = match mask {
+            Some(key) => apply_mask(payload, key),
+            None => payload.to_vec(),
+        };
+
+        let frame = Frame {
+            fin: first & 0x80 != 0,
+            rsv: (first >> 4) & 0x7,
+            opcode: Opcode::from_u8(first & 0x0F),
+            mask,
+            payload,
+        };
+        Ok(Some((frame, end)))
+    }
+}
+
+/// Why a frame can't be read at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// A 64-bit payload length with its most significant bit set
+    LengthTopBit,
+    /// A payload over the limit, with its length
+    TooBig(u64),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::LengthTopBit => {
+                f.write_str("a 64-bit payload length has its most significant bit set")
+            }
+            FrameError::TooBig(length) => write!(
+                f,
+                "a {} byte frame is over the {} byte limit",
+                length, MAX_MESSAGE
+            ),
+        }
+    }
+}
+
+fn apply_mask(payload: &[u8], key: [u8; 4]) -> Vec<u8> {
+    payload
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ key[i % 4])
+        .collect()
+}
+
+fn log_frame(direction: Direction, frame: &Frame) {
+    debug!(
+        target: FRAMES,
+        fin = frame.fin as u8,
+        rsv = %format!("{:03b}", frame.rsv),
+        opcode = %frame.opcode,
+        masked = frame.mask.is_some(),
+        length = frame.payload.len(),
+        "{} frame",
+        direction.arrow()
+    );
+}
+
+/// What a close code means, from RFC 6455 section 7.4 and the IANA registry
+pub fn close_meaning(code: u16) -> &'static str {
+    match code {
+        1000 => "normal closure",
+        1001 => "going away",
+        1002 => "protocol error",
+        1003 => "unsupported data",
+        1005 => "no status received",
+        1006 => "abnormal closure",
+        1007 => "invalid payload data",
+        1008 => "policy violation",
+        1009 => "message too big",
+        1010 => "mandatory extension missing",
+        1011 => "internal error",
+        1012 => "service restart",
+        1013 => "try again later",
+        1014 => "bad gateway",
+        1015 => "TLS handshake failure",
+        3000..=3999 => "registered for libraries and frameworks",
+        4000..=4999 => "private use",
+        _ => "unassigned",
+    }
+}
+
+// 1005, 1006 and 1015 only exist to report a close locally, they never go over the wire
+fn close_code_allowed(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+// Bytes of compressed messages on the wire, against the bytes they stand for
+#[derive(Debug, Clone, Copy, Default)]
+struct Savings {
+    messages: usize,
+    wire: u64,
+    raw: u64,
+}
+
+impl Savings {
+    fn add(&mut self, wire: usize, raw: usize, direction: Direction) {
+        self.messages += 1;
+        self.wire += wire as u64;
+        self.raw += raw as u64;
+        debug!(
+            target: FRAMES,
+            wire,
+            raw,
+            ratio = %ratio(wire as u64, raw as u64),
+            "{} {}",
+            direction.arrow(),
+            match direction {
+                Direction::Sent => "Deflated message",
+                Direction::Received => "Inflated message",
+            }
+        );
+    }
+}
+
+impl fmt::Display for Savings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} messages, {} bytes as {} ({})",
+            self.messages,
+            self.raw,
+            self.wire,
+            ratio(self.wire, self.raw)
+        )
+    }
+}
+
+fn ratio(wire: u64, raw: u64) -> String {
+    match raw {
+        0 => "-".to_string(),
+        raw => format!("{:.0}%", wire as f64 * 100.0 / raw as f64),
+    }
+}
+
+/// A whole message, reassembled from its fragments
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<(u16, String)>),
+}
+
+impl Message {
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Message::Text(_) => Opcode::Text,
+            Message::Binary(_) => Opcode::Binary,
+            Message::Ping(_) => Opcode::Ping,
+            Message::Pong(_) => Opcode::Pong,
+            Message::Close(_) => Opcode::Close,
+        }
+    }
+
+    fn into_frame(self) -> Frame {
+        let opcode = self.opcode();
+        let payload = match self {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+            Message::Close(None) => vec![],
+            Message::Close(Some((code, reason))) => {
+                let mut payload = code.to_be_bytes().to_vec();
+                payload.extend_from_slice(reason.as_bytes());
+                payload
+            }
+        };
+        Frame::new(opcode, payload)
+    }
+}
+
+// A fragmented message being put back together
+struct Partial {
+    opcode: Opcode,
+    compressed: bool,
+    frames: usize,
+    payload: Vec<u8>,
+}
+
+/// The receiving half of a connection
+pub struct Reader {
+    io: Box<dyn AsyncRead + Send + Unpin>,
+    buffer: Vec<u8>,
+    partial: Option<Partial>,
+    inflater: Option<Inflater>,
+    savings: Savings,
+}
+
+impl Reader {
+    fn new(io: Box<dyn AsyncRead + Send + Unpin>, inflater: Option<Inflater>) -> Reader {
+        Reader {
+            io,
+            buffer: vec![],
+            partial: None,
+            inflater,
+            savings: Savings::default(),
+        }
+    }
+
+    async fn frame(&mut self) -> Result<Option<Frame>, Box<dyn Error>> {
+        loop {
+            match Frame::decode(&self.buffer, MAX_MESSAGE) {
+                Ok(Some((frame, used))) => {
+                    self.buffer.drain(..used);
+                    return Ok(Some(frame));
+                }
+                Ok(None) => {}
+                Err(error @ FrameError::LengthTopBit) => {
+                    warn!(target: FRAMES, "Protocol violation: {}", error);
+                    return Err(format!("Can't read the next frame, {}", error).into());
+                }
+                Err(error) => return Err(format!("Can't read the next frame, {}", error).into()),
+            }
+
+            let mut chunk = [0u8; 8192];
+            match self.io.read(&mut chunk).await? {
+                0 if self.buffer.is_empty() => return Ok(None),
+                0 => return Err("The connection closed in the middle of a frame".into()),
+                read => self.buffer.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    /// The next message, or None once the connection is gone. Safe to cancel between calls.
+    pub async fn receive(&mut self) -> Result<Option<Message>, Box<dyn Error>> {
+        loop {
+            let Some(frame) = self.frame().await? else {
+                return Ok(None);
+            };
+            log_frame(Direction::Received, &frame);
+            for violation in self.violations(&frame) {
+                warn!(target: FRAMES, "Protocol violation: {}", violation);
+            }
+
+            let compressed = frame.rsv & RSV1 != 0;
+            let (opcode, compressed, payload) = match frame.opcode {
+                Opcode::Continuation => {
+                    let mut partial = self
+                        .partial
+                        .take()
+                        .ok_or("Got a continuation frame with no message to continue")?;
+                    if partial.payload.len() + frame.payload.len() > MAX_MESSAGE {
+                        return Err(format!(
+                            "The server sent a fragmented message over the {} byte limit",
+                            MAX_MESSAGE
+                        )
+                        .into());
+                    }
+                    partial.payload.extend_from_slice(&frame.payload);
+                    partial.frames += 1;
+                    if !frame.fin {
+                        self.partial = Some(partial);
+                        continue;
+                    }
+                    debug!(
+                        target: FRAMES,
+                        frames = partial.frames,
+                        length = partial.payload.len(),
+                        "Reassembled a fragmented {} message",
+                        partial.opcode
+                    );
+                    (partial.opcode, partial.compressed, partial.payload)
+                }
+                opcode @ (Opcode::Text | Opcode::Binary) if !frame.fin => {
+                    self.partial = Some(Partial {
+                        opcode,
+                        compressed,
+                        frames: 1,
+                        payload: frame.payload,
+                    });
+                    continue;
+                }
+                opcode => (opcode, compressed && !opcode.is_control(), frame.payload),
+            };
+
+            let payload = match &mut self.inflater {
+                Some(inflater) if compressed => {
+                    let inflated = inflater.inflate(&payload, MAX_MESSAGE)?;
+                    self.savings
+                        .add(payload.len(), inflated.len(), Direction::Received);
+                    inflated
+                }
+                _ => payload,
+            };
+
+            return Ok(Some(match opcode {
+                // Show what we can rather than hang up, this is a debugging tool
+                Opcode::Text => Message::Text(String::from_utf8(payload).unwrap_or_else(|err| {
+                    warn!(
+                        target: FRAMES,
+                        "Protocol violation: a text message isn't valid UTF-8 ({})",
+                        err.utf8_error()
+                    );
+                    String::from_utf8_lossy(err.as_bytes()).into_owned()
+                })),
+                Opcode::Binary => Message::Binary(payload),
+                Opcode::Ping => Message::Ping(payload),
+                Opcode::Pong => Message::Pong(payload),
+                Opcode::Close => Message::Close(match payload.as_slice() {
+                    [high, low, reason @ ..] => Some((
+                        u16::from_be_bytes([*high, *low]),
+                        String::from_utf8_lossy(reason).into_owned(),
+                    )),
+                    _ => None,
+                }),
+                other => return Err(format!("Got a frame with {} opcode", other).into()),
+            }));
+        }
+    }
+
+    // Whatever RFC 6455 says a server must not send, checked frame by frame
+    fn violations(&self, frame: &Frame) -> Vec<String> {
+        let mut violations = vec![];
+        if frame.mask.is_some() {
+            violations.push("the server masked a frame, only clients mask".to_string());
+        }
+
+        let data = matches!(frame.opcode, Opcode::Text | Opcode::Binary);
+        let allowed = match self.inflater {
+            Some(_) if data => RSV1,
+            _ => 0,
+        };
+        if frame.rsv & !allowed != 0 {
+            violations.push(format!(
+                "RSV bits {:03b} are set on a {} frame without an extension for them",
+                frame.rsv, frame.opcode
+            ));
+        }
+
+        if let Opcode::Reserved(opcode) = frame.opcode {
+            violations.push(format!("opcode {:#x} is reserved", opcode));
+        }
+        if frame.opcode.is_control() && !frame.fin {
+            violations.push(format!("a {} frame is fragmented", frame.opcode));
+        }
+        if frame.opcode.is_control() && frame.payload.len() > 125 {
+            violations.push(format!(
+                "a {} frame carries {} bytes, control frames are limited to 125",
+                frame.opcode,
+                frame.payload.len()
+            ));
+        }
+        if data && self.partial.is_some() {
+            violations.push("a new message started before the fragmented one finished".to_string());
+        }
+
+        if frame.opcode == Opcode::Close {
+            match frame.payload.as_slice() {
+                [_] => violations.push("a close frame has a 1 byte payload".to_string()),
+                [high, low, reason @ ..] => {
+                    let code = u16::from_be_bytes([*high, *low]);
+                    if !close_code_allowed(code) {
+                        violations.push(format!(
+                            "close code {} ({}) must not be sent",
+                            code,
+                            close_meaning(code)
+                        ));
+                    }
+                    if std::str::from_utf8(reason).is_err() {
+                        violations.push("the close reason isn't valid UTF-8".to_string());
+                    }
+                }
+                [] => {}
+            }
+        }
+        violations
+    }
+}
+
+/// The sending half of a connection
+pub struct Writer {
+    io: Box<dyn AsyncWrite + Send + Unpin>,
+    deflater: Option<Deflater>,
+    savings: Savings,
+}
+
+impl Writer {
+    pub async fn send(&mut self, message: Message) -> Result<(), Box<dyn Error>> {
+        let mut frame = message.into_frame();
+        if let Some(deflater) = &mut self.deflater
+            && !frame.opcode.is_control()
+        {
+            let compressed = deflater.deflate(&frame.payload)?;
+            self.savings
+                .add(compressed.len(), frame.payload.len(), Direction::Sent);
+            frame.payload = compressed;
+            frame.rsv = RSV1;
+        }
+
+        let frame = frame.masked();
+        log_frame(Direction::Sent, &frame);
+        self.io.write_all(&frame.encode()).await?;
+        self.io.flush().await?;
+        Ok(())
+    }
+}
+
+/// How the opening handshake should go
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    /// Subprotocols to offer, in order of preference
+    pub subprotocols: Vec<String>,
+    /// Offer permessage-deflate compression
+    pub deflate: bool,
+    /// Try an HTTP/2 extended CONNECT before the HTTP/1.1 Upgrade
+    pub http2: bool,
+}
+
+/// What the WebSocket ended up running on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Http1Upgrade,
+    Http2Connect,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Http1Upgrade => f.write_str("HTTP/1.1 Upgrade"),
+            Transport::Http2Connect => f.write_str("HTTP/2 extended CONNECT"),
+        }
+    }
+}
+
+/// An open connection, split so it can send and receive at the same time
+pub struct WebSocket {
+    pub reader: Reader,
+    pub writer: Writer,
+    /// The subprotocol the server picked from the ones offered
+    pub subprotocol: Option<String>,
+    /// The permessage-deflate parameters, when the server agreed to compress
+    pub deflate: Option<Params>,
+    pub transport: Transport,
+}
+
+impl WebSocket {
+    /// Opens the connection and performs the opening handshake for `call`
+    pub async fn connect(
+        call: &ApiCall,
+        handshake: &Handshake,
+    ) -> Result<WebSocket, Box<dyn Error>> {
+        let (io, alpn) = open(&call.url, handshake.http2).await?;
+
+        // Cleartext has no ALPN to ask with, so HTTP/2 is tried with prior knowledge
+        let io = match alpn.as_deref() {
+            Some("h2") | None if handshake.http2 => {
+                if let Some(tunnel) = http2::connect(io, call, handshake).await? {
+                    return WebSocket::established(
+                        tunnel.read,
+                        tunnel.write,
+                        &tunnel.headers,
+                        handshake,
+                        Transport::Http2Connect,
+                    );
+                }
+                info!(target: HTTP, "Falling back to an HTTP/1.1 Upgrade");
+                open(&call.url, false).await?.0
+            }
+            _ => io,
+        };
+        WebSocket::upgrade(io, call, handshake).await
+    }
+
+    async fn upgrade(
+        io: Box<dyn Streamable>,
+        call: &ApiCall,
+        handshake: &Handshake,
+    ) -> Result<WebSocket, Box<dyn Error>> {
+        let url = &call.url;
+        let mut sender = http::handshake_with_upgrades(io).await?;
+
+        let mut nonce = [0u8; 16];
+        rand::rng().fill_bytes(&mut nonce);
+        let key = general_purpose::STANDARD.encode(nonce);
+
+        let mut req = Request::builder()
+            .uri(http::origin_form(url))
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .header(SEC_WEBSOCKET_KEY, &key)
+            .body(http::empty())?;
+        if !handshake.subprotocols.is_empty() {
+            req.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                handshake.subprotocols.join(", ").parse()?,
+            );
+        }
+        if handshake.deflate {
+            req.headers_mut()
+                .insert(SEC_WEBSOCKET_EXTENSIONS, deflate::OFFER.parse()?);
+        }
+        req.headers_mut().extend(http::default_headers(url)?);
+        req.headers_mut().extend(call.headers.clone());
+        add_cookies(call, req.headers_mut())?;
+
+        let mut response = sender.send_request(req).await?;
+        info!(target: HTTP, status = %response.status(), "Status 1.x");
+        store_cookies(call, response.headers())?;
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return Err(format!(
+                "The server refused the WebSocket upgrade with {}",
+                response.status()
+            )
+            .into());
+        }
+
+        let expected = general_purpose::STANDARD
+            .encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+        if response.headers().get(SEC_WEBSOCKET_ACCEPT) != Some(&expected.parse()?) {
+            return Err("The server answered with the wrong Sec-WebSocket-Accept".into());
+        }
+
+        let headers = response.headers().clone();
+        let upgraded = http::upgraded(&mut response, "websocket").await?;
+        let (read, write) = tokio::io::split(upgraded);
+        WebSocket::established(
+            Box::new(read),
+            Box::new(write),
+            &headers,
+            handshake,
+            Transport::Http1Upgrade,
+        )
+    }
+
+    // Checks what the server agreed to, which is the same whatever carries the connection
+    fn established(
+        read: Box<dyn AsyncRead + Send + Unpin>,
+        write: Box<dyn AsyncWrite + Send + Unpin>,
+        headers: &HeaderMap,
+        handshake: &Handshake,
+        transport: Transport,
+    ) -> Result<WebSocket, Box<dyn Error>> {
+        let subprotocol = match headers.get(SEC_WEBSOCKET_PROTOCOL) {
+            Some(picked) => {
+                let picked = picked.to_str()?.to_string();
+                if !handshake.subprotocols.contains(&picked) {
+                    return Err(
+                        format!("The server picked a subprotocol not offered: {}", picked).into(),
+                    );
+                }
+                Some(picked)
+            }
+            None => None,
+        };
+
+        let params = match headers.get(SEC_WEBSOCKET_EXTENSIONS) {
+            Some(extensions) if handshake.deflate => Params::negotiate(extensions.to_str()?)?,
+            Some(extensions) => {
+                return Err(format!(
+                    "The server accepted an extension not offered: {}",
+                    extensions.to_str()?
+                )
+                .into());
+            }
+            None => None,
+        };
+        match params {
+            Some(params) => info!(target: FRAMES, extension = %params, "Negotiated compression"),
+            None if handshake.deflate => {
+                info!(target: FRAMES, "The server declined permessage-deflate")
+            }
+            None => {}
+        }
+
+        Ok(WebSocket {
+            reader: Reader::new(read, params.map(Inflater::new)),
+            writer: Writer {
+                io: write,
+                deflater: params.map(Deflater::new),
+                savings: Savings::default(),
+            },
+            subprotocol,
+            deflate: params,
+            transport,
+        })
+    }
+}
+
+// The handshake is a plain GET as far as the cookie jar is concerned
+fn add_cookies(call: &ApiCall, headers: &mut HeaderMap) -> Result<(), Box<dyn Error>> {
+    let Some(jar) = &call.cookie_jar else {
+        return Ok(());
+    };
+    let jar = jar.lock().map_err(|_| "Cookie jar poisoned")?;
+    if let Some(cookies) = jar.header_for(&call.url, &Method::GET, false) {
+        // Keep any cookies given inline alongside the jar's
+        let value = match headers.get(COOKIE) {
+            Some(inline) => format!("{}; {}", inline.to_str()?, cookies),
+            None => cookies,
+        };
+        headers.insert(COOKIE, value.parse()?);
+    }
+    Ok(())
+}
+
+fn store_cookies(call: &ApiCall, headers: &HeaderMap) -> Result<(), Box<dyn Error>> {
+    if let Some(jar) = &call.cookie_jar {
+        jar.lock()
+            .map_err(|_| "Cookie jar poisoned")?
+            .store_response(&call.url, headers);
+    }
+    Ok(())
+}
+
+// Connects, with TLS for wss, returning the protocol ALPN settled on
+async fn open(
+    url: &Url,
+    http2: bool,
+) -> Result<(Box<dyn Streamable>, Option<String>), Box<dyn Error>> {
+    let host = url.host_str().ok_or("Invalid host")?.to_string();
+    let port = url.port_or_known_default().ok_or("Invalid port")?;
+
+    let addr = lookup_host((host.as_str(), port))
+        .await?
+        .next()
+        .ok_or("Host did not resolve to any address")?;
+    let tcp = TcpStream::connect(addr).await?;
+    info!(target: CONNECTION_TARGET, %host, port, scheme = url.scheme(), "Connected");
+
+    match url.scheme() {
+        "wss" => {
+            let alpn = match http2 {
+                true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                false => vec![b"http/1.1".to_vec()],
+            };
+            let (io, tls) = http::wrap_stream_with_tls(tcp, &host, alpn).await?;
+            Ok((io, tls.alpn))
+        }
+        "ws" => Ok((Box::new(TokioIo::new(tcp)), None)),
+        scheme => Err(format!("Unsupported scheme: {}", scheme).into()),
+    }
+}
+
+/// Where the messages to send come from
+#[derive(Debug, Clone, Default)]
+pub struct Outgoing {
+    pub messages: Vec<String>,
+    /// Sent whole, as a single message
+    pub file: Option<PathBuf>,
+    /// Each line is sent as its own message
+    pub stdin: bool,
+    pub binary: bool,
+}
+
+impl Outgoing {
+    // Feeds the messages into a channel, which closes once there's nothing more to send
+    fn spawn(self) -> mpsc::Receiver<Result<Message, String>> {
+        let (sender, receiver) = mpsc::channel(16);
+        let binary = self.binary;
+        let message = move |data: Vec<u8>| match binary {
+            true => Message::Binary(data),
+            false => Message::Text(String::from_utf8_lossy(&data).into_owned()),
+        };
+
+        tokio::spawn(async move {
+            for text in self.messages {
+                if sender.send(Ok(message(text.into_bytes()))).await.is_err() {
+                    return;
+                }
+            }
+            if let Some(path) = &self.file {
+                let read = tokio::fs::read(path)
+                    .await
+                    .map(&message)
+                    .map_err(|err| format!("Can't read {}: {}", path.display(), err));
+                if sender.send(read).await.is_err() {
+                    return;
+                }
+            }
+            if self.stdin {
+                let mut lines = BufReader::new(tokio::io::stdin()).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if sender.send(Ok(message(line.into_bytes()))).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        receiver
+    }
+}
+
+/// Which way a message went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn arrow(self) -> &'static str {
+        match self {
+            Direction::Sent => "→",
+            Direction::Received => "←",
+        }
+    }
+}
+
+// One line of the session transcript
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    direction: Direction,
+    time: String,
+    opcode: String,
+    length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<u16>,
+}
+
+/// Prints each message as it's sent or received
+pub struct Transcript {
+    json: bool,
+    options: RenderOptions,
+    out: Box<dyn Write + Send>,
+    // Every message as a JSON line, when the session can be saved
+    kept: Option<Vec<String>>,
+}
+
+impl Transcript {
+    /// Messages print as colored lines, or as JSON lines with `json`
+    pub fn new(json: bool, options: RenderOptions) -> Transcript {
+        Transcript {
+            json,
+            options,
+            out: Box::new(stdout()),
+            kept: None,
+        }
+    }
+
+    pub fn print(&mut self, direction: Direction, message: &Message) -> Result<(), Box<dyn Error>> {
+        let now = OffsetDateTime::now_utc();
+        let (length, text, binary, code) = match message {
+            Message::Text(text) => (text.len(), Some(text.as_str()), None, None),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => {
+                (data.len(), None, Some(data.as_slice()), None)
+            }
+            Message::Close(Some((code, reason))) => {
+                (reason.len() + 2, Some(reason.as_str()), None, Some(*code))
+            }
+            Message::Close(None) => (0, None, None, None),
+        };
+
+        if self.json || self.kept.is_some() {
+            let record = serde_json::to_string(&Record {
+                direction,
+                time: now.format(&Rfc3339)?,
+                opcode: message.opcode().to_string(),
+                length,
+                text,
+                base64: binary.map(|data| general_purpose::STANDARD.encode(data)),
+                code,
+            })?;
+            if self.json {
+                writeln!(self.out, "{}", record)?;
+                self.out.flush()?;
+                return Ok(());
+            }
+            if let Some(kept) = &mut self.kept {
+                kept.push(record);
+            }
+        }
+
+        let time = now.format(format_description!(
+            "[hour]:[minute]:[second].[subsecond digits:3]"
+        ))?;
+        let mut line = format!("{} {} {:<6}", direction.arrow(), time, message.opcode());
+        if let Some(code) = code {
+            line.push_str(&format!(" {} ({})", code, close_meaning(code)));
+        }
+
+        // JSON payloads get the same treatment as JSON bodies, on the lines below
+        let mut body = vec![];
+        match (text, binary) {
+            (Some(text), _) if serde_json::from_str::<IgnoredAny>(text).is_ok() => {
+                render::json::render(text, &self.options, &mut body)?;
+            }
+            (Some(text), _) if !text.is_empty() => line.push_str(&format!(" {}", text)),
+            (_, Some(data)) if !data.is_empty() => {
+                let preview: Vec<String> =
+                    data.iter().take(32).map(|b| format!("{:02x}", b)).collect();
+                let more = if data.len() > 32 { " …" } else { "" };
+                line.push_str(&format!(
+                    " {} bytes: {}{}",
+                    data.len(),
+                    preview.join(" "),
+                    more
+                ));
+            }
+            _ => {}
+        }
+
+        let line = match (self.options.color, direction) {
+            (false, _) => line,
+            (true, Direction::Sent) => Yellow.paint(line).to_string(),
+            (true, Direction::Received) => Cyan.paint(line).to_string(),
+        };
+        writeln!(self.out, "{}", line)?;
+        for line in String::from_utf8_lossy(&body).lines() {
+            writeln!(self.out, "  {}", line)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// Prints a message on its way out
+async fn send(
+    writer: &mut Writer,
+    transcript: &mut Transcript,
+    message: Message,
+) -> Result<(), Box<dyn Error>> {
+    transcript.print(Direction::Sent, &message)?;
+    writer.send(message).await
+}
+
+// We're the ones closing, so give the server a moment to answer before hanging up
+async fn close(
+    reader: &mut Reader,
+    writer: &mut Writer,
+    transcript: &mut Transcript,
+    close: Message,
+) -> Result<(), Box<dyn Error>> {
+    send(writer, transcript, close).await?;
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        while let Ok(Some(message)) = reader.receive().await {
+            let _ = transcript.print(Direction::Received, &message);
+            if matches!(message, Message::Close(_)) {
+                break;
+            }
+        }
+    })
+    .await;
+    Ok(())
+}
+
+// How much permessage-deflate saved over the whole session
+fn report(reader: &Reader, writer: &Writer) {
+    if reader.inflater.is_some() {
+        info!(
+            target: FRAMES,
+            sent = %writer.savings,
+            received = %reader.savings,
+            "Compression"
+        );
+    }
+}
+
+// Answers what the protocol needs answered, returning whether the connection is done
+async fn respond(
+    writer: &mut Writer,
+    transcript: &mut Transcript,
+    message: Message,
+) -> Result<bool, Box<dyn Error>> {
+    match message {
+        Message::Ping(data) => send(writer, transcript, Message::Pong(data)).await?,
+        // Echo the code back to complete the closing handshake, unless it's one we can't send
+        Message::Close(close) => {
+            let code = close.map(|(code, _)| match close_code_allowed(code) {
+                true => (code, String::new()),
+                false => (1002, String::new()),
+            });
+            send(writer, transcript, Message::Close(code)).await?;
+            return Ok(true);
+        }
+        _ => {}
+    }
+    Ok(false)
+}
+
+/// Sends the outgoing messages and prints everything that comes back, until either side closes
+pub async fn run(
+    socket: WebSocket,
+    outgoing: Outgoing,
+    wait: Option<Duration>,
+    mut transcript: Transcript,
+) -> Result<(), Box<dyn Error>> {
+    let WebSocket {
+        mut reader,
+        mut writer,
+        ..
+    } = socket;
+    let mut outgoing = Some(outgoing.spawn());
+
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    // Only counts down once everything has been sent
+    let idle = tokio::time::sleep(Duration::MAX);
+    tokio::pin!(idle);
+
+    // Whether the server went first, so there's no closing handshake left to start
+    let closed = loop {
+        tokio::select! {
+            message = reader.receive() => {
+                let Some(message) = message? else {
+                    eprintln!("* Connection closed without a Close frame");
+                    break true;
+                };
+                transcript.print(Direction::Received, &message)?;
+                if respond(&mut writer, &mut transcript, message).await? {
+                    break true;
+                }
+                if let Some(wait) = wait.filter(|_| outgoing.is_none()) {
+                    idle.as_mut().reset(tokio::time::Instant::now() + wait);
+                }
+            }
+            next = async { outgoing.as_mut()?.recv().await }, if outgoing.is_some() => {
+                match next {
+                    Some(message) => send(&mut writer, &mut transcript, message?).await?,
+                    None => {
+                        outgoing = None;
+                        if let Some(wait) = wait {
+                            idle.as_mut().reset(tokio::time::Instant::now() + wait);
+                        }
+                    }
+                }
+            }
+            _ = &mut idle => break false,
+            _ = &mut interrupted => break false,
+        }
+    };
+
+    if !closed {
+        let normal = Message::Close(Some((1000, String::new())));
+        close(&mut reader, &mut writer, &mut transcript, normal).await?;
+    }
+    report(&reader, &writer);
+    Ok(())
+}
+
+#[test]
+fn test_frame_round_trip() {
+    // RFC 6455 section 5.7: a masked "Hello" from a client
+    let bytes = [
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    let (frame, used) = Frame::decode(&bytes, MAX_MESSAGE)
+        .unwrap()
+        .expect("a whole frame");
+    assert_eq!(used, bytes.len());
+    assert_eq!(frame.opcode, Opcode::Text);
+    assert!(frame.fin);
+    assert_eq!(frame.payload, b"Hello");
+    assert_eq!(frame.encode(), bytes);
+
+    let long = Frame::new(Opcode::Binary, vec![7; 300]);
+    let encoded = long.encode();
+    assert_eq!(&encoded[..4], &[0x82, 126, 0x01, 0x2c]);
+    assert_eq!(Frame::decode(&encoded[..100], MAX_MESSAGE), Ok(None));
+    assert_eq!(Frame::decode(&encoded, MAX_MESSAGE), Ok(Some((long, 304))));
+    assert_eq!(
+        Frame::decode(&encoded[..100], 299),
+        Err(FrameError::TooBig(300))
+    );
+
+    // A 64-bit length must leave its top bit clear, and can't wrap the offset either way
+    let mut huge = vec![0x82, 127];
+    huge.extend_from_slice(&u64::MAX.to_be_bytes());
+    assert_eq!(
+        Frame::decode(&huge, MAX_MESSAGE),
+        Err(FrameError::LengthTopBit)
+    );
+    huge[2] = 0x7f;
+    assert_eq!(Frame::decode(&huge, usize::MAX), Ok(None));
+}
+
+#[test]
+fn test_violations() {
+    let reader = Reader::new(Box::new(tokio::io::empty()), None);
+    let mut frame = Frame::new(Opcode::Close, vec![0x03, 0xed]);
+    assert_eq!(
+        reader.violations(&frame),
+        vec!["close code 1005 (no status received) must not be sent"]
+    );
+
+    frame.fin = false;
+    frame.rsv = RSV1;
+    frame.mask = Some([1, 2, 3, 4]);
+    frame.payload = vec![0x03, 0xe8];
+    assert_eq!(reader.violations(&frame).len(), 3);
+
+    let compressed = Reader::new(
+        Box::new(tokio::io::empty()),
+        Some(Inflater::new(Params::default())),
+    );
+    let mut text = Frame::new(Opcode::Text, vec![]);
+    text.rsv = RSV1;
+    assert!(compressed.violations(&text).is_empty());
+    assert_eq!(reader.violations(&text).len(), 1);
+}
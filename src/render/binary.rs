@@ -0,0 +1,389 @@
+use std::io::{self, Cursor, Write};
+
+use super::{RenderOptions, image, paint};
+use crate::color::Token;
+
+/// A file type recognized from the first bytes of a body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Kind {
+    pub mime: &'static str,
+    pub description: &'static str,
+}
+
+impl Kind {
+    pub fn is_image(&self) -> bool {
+        self.mime.starts_with("image/")
+    }
+}
+
+// Signatures are matched at the given offset. Only formats that can't be mistaken for text belong
+// here, so a match always means the body should be treated as binary.
+const SIGNATURES: &[(usize, &[u8], Kind)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", kind("image/png", "PNG image")),
+    (0, b"\xff\xd8\xff", kind("image/jpeg", "JPEG image")),
+    (0, b"GIF87a", kind("image/gif", "GIF image")),
+    (0, b"GIF89a", kind("image/gif", "GIF image")),
+    (8, b"WEBP", kind("image/webp", "WebP image")),
+    (0, b"BM", kind("image/bmp", "BMP image")),
+    (0, b"\x00\x00\x01\x00", kind("image/x-icon", "Windows icon")),
+    (0, b"%PDF-", kind("application/pdf", "PDF document")),
+    (0, b"PK\x03\x04", kind("application/zip", "ZIP archive")),
+    (
+        0,
+        b"\x1f\x8b",
+        kind("application/gzip", "gzip compressed data"),
+    ),
+    (
+        0,
+        b"BZh",
+        kind("application/x-bzip2", "bzip2 compressed data"),
+    ),
+    (
+        0,
+        b"\xfd7zXZ\x00",
+        kind("application/x-xz", "xz compressed data"),
+    ),
+    (
+        0,
+        b"(\xb5/\xfd",
+        kind("application/zstd", "Zstandard compressed data"),
+    ),
+    (
+        0,
+        b"7z\xbc\xaf\x27\x1c",
+        kind("application/x-7z-compressed", "7-Zip archive"),
+    ),
+    (257, b"ustar", kind("application/x-tar", "tar archive")),
+    (
+        0,
+        b"\x7fELF",
+        kind("application/x-executable", "ELF executable"),
+    ),
+    (
+        0,
+        b"\x00asm",
+        kind("application/wasm", "WebAssembly module"),
+    ),
+    (0, b"OggS", kind("audio/ogg", "Ogg media")),
+    (0, b"ID3", kind("audio/mpeg", "MP3 audio")),
+    (4, b"ftyp", kind("video/mp4", "MPEG-4 media")),
+    (
+        0,
+        b"\x1aE\xdf\xa3",
+        kind("video/webm", "Matroska/WebM media"),
+    ),
+    (0, b"wOFF", kind("font/woff", "WOFF font")),
+    (0, b"wOF2", kind("font/woff2", "WOFF2 font")),
+    (
+        0,
+        b"SQLite format 3\x00",
+        kind("application/vnd.sqlite3", "SQLite database"),
+    ),
+];
+
+const fn kind(mime: &'static str, description: &'static str) -> Kind {
+    Kind { mime, description }
+}
+
+/// Recognizes a body from its magic bytes, regardless of what the Content-Type claims
+pub fn sniff(data: &[u8]) -> Option<Kind> {
+    // BMP's two-byte signature is too weak on its own, so check the header size as well
+    let plausible = |kind: &Kind| kind.mime != "image/bmp" || data.get(14) == Some(&40);
+
+    SIGNATURES
+        .iter()
+        .find(|(offset, magic, kind)| {
+            data.get(*offset..offset + magic.len()) == Some(*magic) && plausible(kind)
+        })
+        .map(|(_, _, kind)| *kind)
+}
+
+/// Renders a binary body: a one-line summary, an inline preview for images when the terminal can
+/// show them, and a hexdump.
+pub fn render<W: Write>(
+    data: &[u8],
+    declared: &str,
+    options: &RenderOptions,
+    mut out: W,
+) -> io::Result<()> {
+    let detected = sniff(data);
+
+    let description = detected.map(|k| k.description).unwrap_or("Binary data");
+    let mut summary = format!("{}, {}", description, human_size(data.len()));
+    match detected {
+        Some(kind) if kind.is_image() => {
+            if let Some((width, height)) = image_dimensions(data) {
+                summary.push_str(&format!(", {}×{}", width, height));
+            }
+        }
+        Some(kind) if kind.mime == "application/pdf" => {
+            let info = pdf_info(data);
+            if let Some(pages) = info.pages {
+                summary.push_str(&format!(
+                    ", {} page{}",
+                    pages,
+                    if pages == 1 { "" } else { "s" }
+                ));
+            }
+            if let Some(title) = info.title {
+                summary.push_str(&format!(", titled \"{}\"", title));
+            }
+        }
+        _ => {}
+    }
+    paint(&mut out, Token::Comment, &summary, options.color)?;
+    writeln!(out)?;
+
+    if let Some(kind) = detected.filter(|k| k.mime != declared) {
+        paint(
+            &mut out,
+            Token::Comment,
+            &format!(
+                "Content-Type says {}, but the body looks like {}",
+                declared, kind.mime
+            ),
+            options.color,
+        )?;
+        writeln!(out)?;
+    }
+
+    if let (Some(protocol), true) = (
+        options.image_preview,
+        detected.is_some_and(|k| k.is_image()),
+    ) {
+        // A preview is a nicety, falling back to the hexdump alone is fine
+        if image::preview(data, protocol, &mut out).is_ok() {
+            writeln!(out)?;
+        }
+    }
+
+    hexdump(data, options, &mut out)?;
+    out.flush()
+}
+
+/// Dumps bytes in the layout of `xxd`: offset, sixteen bytes in pairs, then the printable ones
+pub fn hexdump<W: Write>(data: &[u8], options: &RenderOptions, out: &mut W) -> io::Result<()> {
+    let shown = match options.hexdump_limit {
+        0 => data.len(),
+        limit => data.len().min(limit),
+    };
+
+    for (line, chunk) in data[..shown].chunks(16).enumerate() {
+        paint(
+            out,
+            Token::Punctuation,
+            &format!("{:08x}:", line * 16),
+            options.color,
+        )?;
+
+        for (i, byte) in chunk.iter().enumerate() {
+            if i % 2 == 0 {
+                write!(out, " ")?;
+            }
+            paint(
+                out,
+                byte_token(*byte),
+                &format!("{:02x}", byte),
+                options.color,
+            )?;
+        }
+        // Pad short lines so the text column stays aligned
+        let used = chunk.len() * 2 + chunk.len().div_ceil(2);
+        write!(out, "{:width$}", "", width = 40 - used)?;
+
+        write!(out, "  ")?;
+        for byte in chunk {
+            let c = if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            };
+            paint(out, byte_token(*byte), &c.to_string(), options.color)?;
+        }
+        writeln!(out)?;
+    }
+
+    if shown < data.len() {
+        paint(
+            out,
+            Token::Comment,
+            &format!(
+                "... {} more bytes (use --hexdump-limit 0 to show everything)",
+                data.len() - shown
+            ),
+            options.color,
+        )?;
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+// Colors bytes by class, the way `xxd -R` does: printable, whitespace, NUL and everything else
+fn byte_token(byte: u8) -> Token {
+    match byte {
+        0 => Token::Punctuation,
+        _ if byte.is_ascii_graphic() => Token::String,
+        b' ' | b'\t' | b'\r' | b'\n' => Token::Literal,
+        _ => Token::Number,
+    }
+}
+
+fn human_size(bytes: usize) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{} bytes", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+fn image_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    ::image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct PdfInfo {
+    pages: Option<usize>,
+    title: Option<String>,
+}
+
+// Best effort: objects inside compressed object streams are invisible to this
+fn pdf_info(data: &[u8]) -> PdfInfo {
+    let mut pages = 0;
+    let mut rest = data;
+    while let Some(position) = find(rest, b"/Type") {
+        rest = rest[position + b"/Type".len()..].trim_ascii_start();
+        if let Some(after) = rest.strip_prefix(b"/Page") {
+            // `/Pages` is the page tree, not a page
+            if !after.first().is_some_and(u8::is_ascii_alphanumeric) {
+                pages += 1;
+            }
+        }
+    }
+
+    let title = find(data, b"/Title")
+        .and_then(|position| pdf_string(data[position + b"/Title".len()..].trim_ascii_start()));
+
+    PdfInfo {
+        pages: (pages > 0).then_some(pages),
+        title,
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Decodes a PDF literal `(...)` or hex `<...>` string, including UTF-16 ones
+fn pdf_string(data: &[u8]) -> Option<String> {
+    let bytes: Vec<u8> = if let Some(literal) = data.strip_prefix(b"(") {
+        let mut bytes = vec![];
+        let mut depth = 0;
+        let mut iter = literal.iter().copied().peekable();
+        while let Some(byte) = iter.next() {
+            match byte {
+                b'\\' => match iter.next()? {
+                    b'n' => bytes.push(b'\n'),
+                    b'r' => bytes.push(b'\r'),
+                    b't' => bytes.push(b'\t'),
+                    digit @ b'0'..=b'7' => {
+                        // Up to three octal digits
+                        let mut value = u32::from(digit - b'0');
+                        for _ in 0..2 {
+                            match iter.peek() {
+                                Some(next @ b'0'..=b'7') => {
+                                    value = value * 8 + u32::from(next - b'0');
+                                    iter.next();
+                                }
+                                _ => break,
+                            }
+                        }
+                        bytes.push(value as u8);
+                    }
+                    other => bytes.push(other),
+                },
+                b'(' => {
+                    depth += 1;
+                    bytes.push(byte);
+                }
+                b')' if depth == 0 => break,
+                b')' => {
+                    depth -= 1;
+                    bytes.push(byte);
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        bytes
+    } else if let Some(hex) = data.strip_prefix(b"<") {
+        let digits: Vec<u8> = hex
+            .iter()
+            .copied()
+            .take_while(|b| *b != b'>')
+            .filter(u8::is_ascii_hexdigit)
+            .collect();
+        digits
+            .chunks_exact(2)
+            .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+            .collect()
+    } else {
+        return None;
+    };
+
+    let title = match bytes.strip_prefix(&[0xfe, 0xff]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        // PDFDocEncoding, which agrees with Latin-1 for everything that matters here
+        None => bytes.iter().map(|b| *b as char).collect(),
+    };
+    let title = title.trim().to_string();
+    (!title.is_empty()).then_some(title)
+}
+
+#[test]
+fn test_sniff_and_hexdump() -> Result<(), Box<dyn std::error::Error>> {
+    let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x02\x00\x00\x00\x03";
+    assert_eq!(sniff(png).map(|k| k.mime), Some("image/png"));
+    assert_eq!(sniff(b"{\"json\": true}"), None);
+
+    let mut out = vec![];
+    hexdump(png, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "00000000: 8950 4e47 0d0a 1a0a 0000 000d 4948 4452  .PNG........IHDR\n\
+         00000010: 0000 0002 0000 0003                      ........\n"
+    );
+
+    let pdf = b"%PDF-1.4\n1 0 obj << /Type /Pages /Count 2 >>\n2 0 obj << /Type /Page >>\n\
+                3 0 obj << /Type/Page >>\n4 0 obj << /Title (Quarterly \\(draft\\)) >>";
+    assert_eq!(
+        pdf_info(pdf),
+        PdfInfo {
+            pages: Some(2),
+            title: Some("Quarterly (draft)".to_string()),
+        }
+    );
+
+    Ok(())
+}
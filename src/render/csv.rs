@@ -0,0 +1,82 @@
+use std::io::{self, Write};
+
+use super::{RenderOptions, paint};
+use crate::color::Token;
+
+/// Lays out comma-separated values as an aligned table with the first row as the header
+pub fn render<W: Write>(text: &str, options: &RenderOptions, mut out: W) -> io::Result<()> {
+    let mut reader = ::csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(text.as_bytes());
+
+    let mut rows: Vec<Vec<String>> = vec![];
+    for record in reader.records() {
+        match record {
+            Ok(record) => rows.push(record.iter().map(str::to_string).collect()),
+            // Not really CSV after all, show it as it came
+            Err(_) => return super::plain(text, out),
+        }
+    }
+
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for (index, row) in rows.iter().enumerate() {
+        let token = if index == 0 {
+            Token::Key
+        } else {
+            Token::String
+        };
+        for (column, width) in widths.iter().enumerate() {
+            let cell = row.get(column).map(String::as_str).unwrap_or_default();
+            if column > 0 {
+                paint(&mut out, Token::Punctuation, " │ ", options.color)?;
+            }
+            let padded = format!("{:<width$}", cell, width = width);
+            let padded = if column + 1 == columns {
+                padded.trim_end()
+            } else {
+                &padded
+            };
+            paint(&mut out, token, padded, options.color)?;
+        }
+        writeln!(out)?;
+
+        if index == 0 {
+            let rule: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+            paint(
+                &mut out,
+                Token::Punctuation,
+                &rule.join("─┼─"),
+                options.color,
+            )?;
+            writeln!(out)?;
+        }
+    }
+
+    out.flush()
+}
+
+#[test]
+fn test_render_csv() -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = vec![];
+    render(
+        "name,qty\nwidget,3\n\"a, b\",10,extra\n",
+        &RenderOptions::default(),
+        &mut out,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "name   │ qty │ \n\
+         ───────┼─────┼──────\n\
+         widget │ 3   │ \n\
+         a, b   │ 10  │ extra\n"
+    );
+    Ok(())
+}
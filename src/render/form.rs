@@ -0,0 +1,42 @@
+use std::io::{self, Write};
+
+use url::form_urlencoded;
+
+use super::{RenderOptions, paint};
+use crate::color::Token;
+
+/// Decodes `application/x-www-form-urlencoded` data into aligned `key = value` pairs
+pub fn render<W: Write>(text: &str, options: &RenderOptions, mut out: W) -> io::Result<()> {
+    let pairs: Vec<_> = form_urlencoded::parse(text.trim().as_bytes()).collect();
+    let width = pairs
+        .iter()
+        .map(|(key, _)| key.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (key, value) in &pairs {
+        paint(
+            &mut out,
+            Token::Key,
+            &format!("{:<width$}", key, width = width),
+            options.color,
+        )?;
+        paint(&mut out, Token::Punctuation, " = ", options.color)?;
+        paint(&mut out, Token::String, value, options.color)?;
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+#[test]
+fn test_render_form() -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = vec![];
+    render(
+        "b=2&long+key=a%20b%26c\n",
+        &RenderOptions::default(),
+        &mut out,
+    )?;
+    assert_eq!(String::from_utf8(out)?, "b        = 2\nlong key = a b&c\n");
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::env;
+use std::error::Error;
+use std::io::{Cursor, Write};
+
+use base64::{Engine, engine::general_purpose};
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
+
+// Previews are meant to be thumbnails, not a replacement for an image viewer
+const PREVIEW_COLUMNS: u32 = 40;
+const SIXEL_MAX_WIDTH: u32 = 320;
+const SIXEL_MAX_HEIGHT: u32 = 240;
+
+// Kitty wants the payload split into chunks of at most this many base64 characters
+const KITTY_CHUNK: usize = 4096;
+
+/// Which inline image protocol to preview images with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ImagePreview {
+    /// Pick a protocol based on the terminal we're running in
+    #[default]
+    Auto,
+    Kitty,
+    Iterm,
+    Sixel,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageProtocol {
+    Kitty,
+    Iterm,
+    Sixel,
+}
+
+impl ImagePreview {
+    pub fn protocol(self) -> Option<ImageProtocol> {
+        match self {
+            ImagePreview::Auto => detect(),
+            ImagePreview::Kitty => Some(ImageProtocol::Kitty),
+            ImagePreview::Iterm => Some(ImageProtocol::Iterm),
+            ImagePreview::Sixel => Some(ImageProtocol::Sixel),
+            ImagePreview::None => None,
+        }
+    }
+}
+
+// Terminals advertise themselves through the environment. Querying the terminal directly would
+// be more accurate but means putting it in raw mode and racing the response.
+fn detect() -> Option<ImageProtocol> {
+    let var = |name: &str| env::var(name).unwrap_or_default();
+    let term = var("TERM");
+    let program = var("TERM_PROGRAM");
+
+    if term == "xterm-kitty" || !var("KITTY_WINDOW_ID").is_empty() || program == "ghostty" {
+        Some(ImageProtocol::Kitty)
+    } else if program == "iTerm.app" || program == "WezTerm" || var("LC_TERMINAL") == "iTerm2" {
+        Some(ImageProtocol::Iterm)
+    } else if term.contains("sixel") || matches!(program.as_str(), "mlterm" | "contour" | "foot") {
+        Some(ImageProtocol::Sixel)
+    } else {
+        None
+    }
+}
+
+/// Draws a small inline preview of an image using the given terminal protocol
+pub fn preview<W: Write>(
+    data: &[u8],
+    protocol: ImageProtocol,
+    out: &mut W,
+) -> Result<(), Box<dyn Error>> {
+    match protocol {
+        ImageProtocol::Kitty => kitty(data, out),
+        ImageProtocol::Iterm => iterm(data, out),
+        ImageProtocol::Sixel => sixel(&decode(data)?, out),
+    }
+}
+
+fn decode(data: &[u8]) -> Result<DynamicImage, Box<dyn Error>> {
+    Ok(ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+// https://sw.kovidgoyal.net/kitty/graphics-protocol/
+fn kitty<W: Write>(data: &[u8], out: &mut W) -> Result<(), Box<dyn Error>> {
+    // Kitty only understands PNG and raw pixels, so convert anything else
+    let png = if image::guess_format(data)? == ImageFormat::Png {
+        data.to_vec()
+    } else {
+        let mut png = vec![];
+        decode(data)?.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        png
+    };
+
+    let encoded = general_purpose::STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        if i == 0 {
+            write!(out, "\x1b_Ga=T,f=100,c={},m={};", PREVIEW_COLUMNS, more)?;
+        } else {
+            write!(out, "\x1b_Gm={};", more)?;
+        }
+        out.write_all(chunk)?;
+        write!(out, "\x1b\\")?;
+    }
+    Ok(())
+}
+
+// https://iterm2.com/documentation-images.html
+fn iterm<W: Write>(data: &[u8], out: &mut W) -> Result<(), Box<dyn Error>> {
+    write!(
+        out,
+        "\x1b]1337;File=inline=1;size={};width={};preserveAspectRatio=1:{}\x07",
+        data.len(),
+        PREVIEW_COLUMNS,
+        general_purpose::STANDARD.encode(data)
+    )?;
+    Ok(())
+}
+
+// https://vt100.net/docs/vt3xx-gp/chapter14.html
+//
+// Pixels are quantized onto a fixed 6×6×6 color cube, which is crude but needs no palette
+// optimization and looks fine at thumbnail size.
+fn sixel<W: Write>(image: &DynamicImage, out: &mut W) -> Result<(), Box<dyn Error>> {
+    let image = if image.width() > SIXEL_MAX_WIDTH || image.height() > SIXEL_MAX_HEIGHT {
+        image.resize(SIXEL_MAX_WIDTH, SIXEL_MAX_HEIGHT, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+    let pixels = image.to_rgba8();
+    let (width, height) = pixels.dimensions();
+
+    let level = |channel: u8| (u16::from(channel) * 5 + 127) / 255;
+    // Transparent pixels are left unpainted
+    let index = |x: u32, y: u32| {
+        let [r, g, b, a] = pixels.get_pixel(x, y).0;
+        (a >= 128).then(|| level(r) * 36 + level(g) * 6 + level(b))
+    };
+
+    write!(out, "\x1bPq\"1;1;{};{}", width, height)?;
+    for color in 0..216u16 {
+        let percent = |l: u16| l * 100 / 5;
+        write!(
+            out,
+            "#{};2;{};{};{}",
+            color,
+            percent(color / 36),
+            percent(color / 6 % 6),
+            percent(color % 6)
+        )?;
+    }
+
+    for band in (0..height).step_by(6) {
+        let rows = (height - band).min(6);
+
+        let mut colors: Vec<u16> = (0..width)
+            .flat_map(|x| (0..rows).filter_map(move |dy| index(x, band + dy)))
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+
+        for color in colors {
+            write!(out, "#{}", color)?;
+
+            let mut run: Option<(u8, usize)> = None;
+            for x in 0..width {
+                let bits = (0..rows)
+                    .filter(|dy| index(x, band + dy) == Some(color))
+                    .fold(0u8, |bits, dy| bits | (1 << dy));
+                run = match run {
+                    Some((previous, count)) if previous == bits => Some((bits, count + 1)),
+                    Some((previous, count)) => {
+                        write_sixel_run(out, previous, count)?;
+                        Some((bits, 1))
+                    }
+                    None => Some((bits, 1)),
+                };
+            }
+            if let Some((bits, count)) = run {
+                write_sixel_run(out, bits, count)?;
+            }
+            // Back to the start of the band for the next color
+            write!(out, "$")?;
+        }
+        write!(out, "-")?;
+    }
+    write!(out, "\x1b\\")?;
+
+    Ok(())
+}
+
+fn write_sixel_run<W: Write>(out: &mut W, bits: u8, count: usize) -> std::io::Result<()> {
+    let sixel = (63 + bits) as char;
+    match count {
+        1..=3 => write!(out, "{}", sixel.to_string().repeat(count)),
+        _ => write!(out, "!{}{}", count, sixel),
+    }
+}
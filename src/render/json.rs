@@ -0,0 +1,341 @@
+use std::io::{self, Write};
+
+use super::RenderOptions;
+use crate::color::{Token, style};
+
+/// Pretty-prints a JSON document to `out`.
+///
+/// In the original key order the document is reformatted as it's read, without parsing it first.
+/// Sorting keys needs the whole document parsed first. Either way the output is held back until
+/// the end, so anything that turns out not to be JSON is written out untouched instead.
+pub fn render<W: Write>(text: &str, options: &RenderOptions, mut out: W) -> io::Result<()> {
+    let mut formatted = vec![];
+    let mut formatter = JsonFormatter::new(&mut formatted, options);
+    let written = if options.sort_keys {
+        // serde_json's map is ordered by key unless `preserve_order` is enabled
+        serde_json::from_str::<serde_json::Value>(text)
+            .and_then(|value| serde_json::to_writer(&mut formatter, &value))
+            .map_err(io::Error::from)
+    } else {
+        formatter.write_all(text.as_bytes())
+    };
+
+    match written.and_then(|_| formatter.finish()) {
+        Ok(()) => {
+            out.write_all(&formatted)?;
+            out.flush()
+        }
+        Err(_) => super::plain(text, out),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    Object,
+    Array,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Between,
+    InString { escaped: bool },
+    InLiteral,
+}
+
+/// A streaming JSON reformatter.
+///
+/// Bytes of JSON go in through `Write`, indented and highlighted JSON comes out the other side. It
+/// keeps just enough state to survive tokens being split across writes, and to notice what
+/// can't be JSON: brackets that don't match, misspelled literals, or a second value after the
+/// first.
+pub struct JsonFormatter<W: Write> {
+    out: W,
+    indent: usize,
+    color: bool,
+    stack: Vec<Container>,
+    state: State,
+    expect_key: bool,
+    // Set right after `{` or `[` so empty containers stay on one line
+    just_opened: bool,
+    // The number, `true`, `false` or `null` being read, checked once it ends
+    literal: Vec<u8>,
+    // Set once the top-level value has started, there's only one
+    started: bool,
+}
+
+impl<W: Write> JsonFormatter<W> {
+    pub fn new(out: W, options: &RenderOptions) -> Self {
+        JsonFormatter {
+            out,
+            indent: options.indent,
+            color: options.color,
+            stack: vec![],
+            state: State::Between,
+            expect_key: false,
+            just_opened: false,
+            literal: vec![],
+            started: false,
+        }
+    }
+
+    /// Flushes whatever is pending and terminates the output with a newline
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.state == State::InLiteral {
+            self.end_literal()?;
+        }
+        writeln!(self.out)?;
+        self.out.flush()?;
+        match (self.state, self.stack.is_empty()) {
+            (State::InString { .. }, _) | (_, false) => Err(invalid("Unexpected end of JSON")),
+            _ => Ok(()),
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> io::Result<()> {
+        match self.state {
+            State::InString { escaped } => {
+                self.out.write_all(&[byte])?;
+                self.state = match byte {
+                    _ if escaped => State::InString { escaped: false },
+                    b'\\' => State::InString { escaped: true },
+                    b'"' => {
+                        self.end_token()?;
+                        State::Between
+                    }
+                    _ => State::InString { escaped: false },
+                };
+                Ok(())
+            }
+            State::InLiteral if !is_delimiter(byte) => {
+                self.literal.push(byte);
+                self.out.write_all(&[byte])
+            }
+            State::InLiteral => {
+                self.end_literal()?;
+                self.state = State::Between;
+                self.structural(byte)
+            }
+            State::Between => self.structural(byte),
+        }
+    }
+
+    fn structural(&mut self, byte: u8) -> io::Result<()> {
+        match byte {
+            b' ' | b'\t' | b'\r' | b'\n' => Ok(()),
+            b'{' | b'[' => {
+                self.before_value()?;
+                self.punctuation(&[byte])?;
+                self.stack.push(if byte == b'{' {
+                    Container::Object
+                } else {
+                    Container::Array
+                });
+                self.expect_key = byte == b'{';
+                self.just_opened = true;
+                Ok(())
+            }
+            b'}' | b']' => {
+                let expected = match byte {
+                    b'}' => Container::Object,
+                    _ => Container::Array,
+                };
+                if self.stack.pop() != Some(expected) {
+                    return Err(invalid(format!("Unexpected '{}'", byte as char)));
+                }
+                if !std::mem::take(&mut self.just_opened) {
+                    self.newline()?;
+                }
+                self.punctuation(&[byte])
+            }
+            b',' | b':' if self.stack.is_empty() => {
+                Err(invalid(format!("Unexpected '{}'", byte as char)))
+            }
+            b',' => {
+                self.punctuation(b",")?;
+                self.newline()?;
+                self.expect_key = self.stack.last() == Some(&Container::Object);
+                Ok(())
+            }
+            b':' => {
+                self.punctuation(b":")?;
+                self.out.write_all(b" ")?;
+                self.expect_key = false;
+                Ok(())
+            }
+            b'"' => {
+                self.before_value()?;
+                let token = if self.expect_key {
+                    Token::Key
+                } else {
+                    Token::String
+                };
+                self.start_token(token)?;
+                self.out.write_all(b"\"")?;
+                self.state = State::InString { escaped: false };
+                Ok(())
+            }
+            b'-' | b'0'..=b'9' | b't' | b'f' | b'n' => {
+                self.before_value()?;
+                let token = match byte {
+                    b'-' | b'0'..=b'9' => Token::Number,
+                    _ => Token::Literal,
+                };
+                self.start_token(token)?;
+                self.out.write_all(&[byte])?;
+                self.literal = vec![byte];
+                self.state = State::InLiteral;
+                Ok(())
+            }
+            _ => Err(invalid(format!("Unexpected byte 0x{:02x}", byte))),
+        }
+    }
+
+    fn before_value(&mut self) -> io::Result<()> {
+        if self.stack.is_empty() && std::mem::replace(&mut self.started, true) {
+            return Err(invalid("Unexpected value after the end of the document"));
+        }
+        if std::mem::take(&mut self.just_opened) {
+            self.newline()?;
+        }
+        Ok(())
+    }
+
+    fn end_literal(&mut self) -> io::Result<()> {
+        self.end_token()?;
+        match is_literal(&self.literal) {
+            true => Ok(()),
+            false => Err(invalid(format!(
+                "Unexpected {}",
+                String::from_utf8_lossy(&self.literal)
+            ))),
+        }
+    }
+
+    fn newline(&mut self) -> io::Result<()> {
+        let width = self.stack.len() * self.indent;
+        write!(self.out, "\n{:width$}", "", width = width)
+    }
+
+    fn punctuation(&mut self, text: &[u8]) -> io::Result<()> {
+        self.start_token(Token::Punctuation)?;
+        self.out.write_all(text)?;
+        self.end_token()
+    }
+
+    fn start_token(&mut self, token: Token) -> io::Result<()> {
+        if self.color {
+            write!(self.out, "{}", style(token).prefix())?;
+        }
+        Ok(())
+    }
+
+    fn end_token(&mut self) -> io::Result<()> {
+        if self.color {
+            // Every style we use resets the same way
+            write!(self.out, "{}", style(Token::String).suffix())?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for JsonFormatter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.feed(byte)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+// `true`, `false`, `null` or a number as JSON spells it, without leading zeros or a bare `.`
+fn is_literal(literal: &[u8]) -> bool {
+    if matches!(literal, b"true" | b"false" | b"null") {
+        return true;
+    }
+    let digits = |rest: &[u8]| rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    let mut rest = literal.strip_prefix(b"-").unwrap_or(literal);
+    match digits(rest) {
+        0 => return false,
+        n if n > 1 && rest[0] == b'0' => return false,
+        n => rest = &rest[n..],
+    }
+    if let Some(fraction) = rest.strip_prefix(b".") {
+        match digits(fraction) {
+            0 => return false,
+            n => rest = &fraction[n..],
+        }
+    }
+    if let Some(exponent) = rest.strip_prefix(b"e").or(rest.strip_prefix(b"E")) {
+        let exponent = exponent
+            .strip_prefix(b"+")
+            .or(exponent.strip_prefix(b"-"))
+            .unwrap_or(exponent);
+        match digits(exponent) {
+            0 => return false,
+            n => rest = &exponent[n..],
+        }
+    }
+    rest.is_empty()
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b',' | b':' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n'
+    )
+}
+
+#[test]
+fn test_json_formatter() -> Result<(), Box<dyn std::error::Error>> {
+    let input = r#"{"b":[1, true,{}],"a":{"s":"x\"}"},"e":[]}"#;
+
+    let mut out = vec![];
+    render(input, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "{\n  \"b\": [\n    1,\n    true,\n    {}\n  ],\n  \"a\": {\n    \"s\": \"x\\\"}\"\n  },\n  \"e\": []\n}\n"
+    );
+
+    let options = RenderOptions {
+        indent: 1,
+        sort_keys: true,
+        ..Default::default()
+    };
+    let mut out = vec![];
+    render(r#"{"b":1,"a":2}"#, &options, &mut out)?;
+    assert_eq!(String::from_utf8(out)?, "{\n \"a\": 2,\n \"b\": 1\n}\n");
+
+    // Anything that isn't JSON after all is shown as it is
+    let broken = [
+        "<html>oops</html>",
+        r#"{"a":[1}"#,
+        r#"{"a":"#,
+        r#"{"a":"b"#,
+        "[1]]",
+        "{x}",
+        "[tru]",
+        "nul",
+        "1 2",
+        "{} []",
+        "[01, 1.]",
+        r#"[1"a"]"#,
+    ];
+    for text in broken {
+        let mut out = vec![];
+        render(text, &RenderOptions::default(), &mut out)?;
+        assert_eq!(String::from_utf8(out)?, format!("{}\n", text));
+    }
+    let mut out = vec![];
+    render("[-0.5e+3, 10, null]", &RenderOptions::default(), &mut out)?;
+    assert_eq!(String::from_utf8(out)?, "[\n  -0.5e+3,\n  10,\n  null\n]\n");
+
+    Ok(())
+}
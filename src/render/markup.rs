@@ -0,0 +1,340 @@
+use std::io::{self, Write};
+
+use super::{RenderOptions, paint};
+use crate::color::Token;
+
+/// Which markup dialect is being rendered; HTML is far more forgiving about structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    Xml,
+    Html,
+}
+
+// Elements that never have content or a closing tag
+const HTML_VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// Elements whose content must be reproduced exactly as received
+const HTML_RAW_ELEMENTS: &[&str] = &["script", "style", "pre", "textarea"];
+
+// Text children shorter than this stay on the same line as their element
+const INLINE_TEXT_LIMIT: usize = 80;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Node<'a> {
+    Open {
+        name: &'a str,
+        attributes: Vec<(&'a str, Option<&'a str>)>,
+        self_closing: bool,
+    },
+    Close {
+        name: &'a str,
+    },
+    Text(&'a str),
+    // Content of raw elements, kept verbatim
+    Raw(&'a str),
+    // Comments, doctypes, processing instructions and CDATA sections
+    Other(&'a str),
+}
+
+/// Re-indents an XML or HTML document, one element per line
+pub fn render<W: Write>(
+    text: &str,
+    dialect: Dialect,
+    options: &RenderOptions,
+    mut out: W,
+) -> io::Result<()> {
+    let nodes = parse(text, dialect);
+    let mut open: Vec<&str> = vec![];
+    let mut i = 0;
+
+    while i < nodes.len() {
+        let pad = " ".repeat(open.len() * options.indent);
+
+        match &nodes[i] {
+            Node::Open {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                write!(out, "{}", pad)?;
+                write_tag(&mut out, name, attributes, *self_closing, options.color)?;
+
+                let is_void = dialect == Dialect::Html && is_one_of(name, HTML_VOID_ELEMENTS);
+                if *self_closing || is_void {
+                    writeln!(out)?;
+                    i += 1;
+                    continue;
+                }
+
+                // Keep `<b>short text</b>` on one line
+                if let (Some(Node::Text(inner)), Some(Node::Close { name: closing })) =
+                    (nodes.get(i + 1), nodes.get(i + 2))
+                {
+                    let inner = collapse(inner);
+                    if closing.eq_ignore_ascii_case(name) && inner.len() <= INLINE_TEXT_LIMIT {
+                        paint(&mut out, Token::String, &inner, options.color)?;
+                        write_close(&mut out, closing, options.color)?;
+                        writeln!(out)?;
+                        i += 3;
+                        continue;
+                    }
+                }
+
+                writeln!(out)?;
+                open.push(name);
+            }
+            Node::Close { name } => {
+                // Unbalanced HTML is common, so only unwind when we know the element
+                if let Some(position) = open.iter().rposition(|o| o.eq_ignore_ascii_case(name)) {
+                    open.truncate(position);
+                }
+                write!(out, "{}", " ".repeat(open.len() * options.indent))?;
+                write_close(&mut out, name, options.color)?;
+                writeln!(out)?;
+            }
+            Node::Text(content) => {
+                for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    write!(out, "{}", pad)?;
+                    paint(&mut out, Token::String, line, options.color)?;
+                    writeln!(out)?;
+                }
+            }
+            Node::Raw(content) => {
+                let content = content.trim_matches(['\r', '\n']);
+                if !content.trim().is_empty() {
+                    writeln!(out, "{}", content)?;
+                }
+            }
+            Node::Other(content) => {
+                write!(out, "{}", pad)?;
+                paint(&mut out, Token::Comment, content, options.color)?;
+                writeln!(out)?;
+            }
+        }
+
+        i += 1;
+    }
+
+    out.flush()
+}
+
+fn write_tag<W: Write>(
+    out: &mut W,
+    name: &str,
+    attributes: &[(&str, Option<&str>)],
+    self_closing: bool,
+    color: bool,
+) -> io::Result<()> {
+    paint(out, Token::Punctuation, "<", color)?;
+    paint(out, Token::Tag, name, color)?;
+    for (attribute, value) in attributes {
+        write!(out, " ")?;
+        paint(out, Token::Attribute, attribute, color)?;
+        if let Some(value) = value {
+            paint(out, Token::Punctuation, "=", color)?;
+            paint(out, Token::String, value, color)?;
+        }
+    }
+    paint(
+        out,
+        Token::Punctuation,
+        if self_closing { "/>" } else { ">" },
+        color,
+    )
+}
+
+fn write_close<W: Write>(out: &mut W, name: &str, color: bool) -> io::Result<()> {
+    paint(out, Token::Punctuation, "</", color)?;
+    paint(out, Token::Tag, name, color)?;
+    paint(out, Token::Punctuation, ">", color)
+}
+
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|n| n.eq_ignore_ascii_case(name))
+}
+
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// A forgiving tokenizer: anything it doesn't understand is passed through as text
+fn parse(text: &str, dialect: Dialect) -> Vec<Node<'_>> {
+    let mut nodes = vec![];
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            push_text(&mut nodes, rest);
+            break;
+        };
+        push_text(&mut nodes, &rest[..start]);
+        rest = &rest[start..];
+
+        let (terminator, is_other) = if rest.starts_with("<!--") {
+            ("-->", true)
+        } else if rest.starts_with("<![CDATA[") {
+            ("]]>", true)
+        } else if rest.starts_with("<!") || rest.starts_with("<?") {
+            (">", true)
+        } else {
+            (">", false)
+        };
+
+        let end = match find_tag_end(rest, terminator) {
+            Some(end) => end,
+            None => {
+                push_text(&mut nodes, rest);
+                break;
+            }
+        };
+        let markup = &rest[..end];
+        rest = &rest[end..];
+
+        if is_other {
+            nodes.push(Node::Other(markup));
+            continue;
+        }
+
+        match parse_tag(markup) {
+            Some(Node::Open {
+                name,
+                attributes,
+                self_closing,
+            }) => {
+                let raw = dialect == Dialect::Html && is_one_of(name, HTML_RAW_ELEMENTS);
+                nodes.push(Node::Open {
+                    name,
+                    attributes,
+                    self_closing,
+                });
+
+                if raw && !self_closing {
+                    let closing = format!("</{}", name.to_ascii_lowercase());
+                    let content_end = rest
+                        .to_ascii_lowercase()
+                        .find(&closing)
+                        .unwrap_or(rest.len());
+                    nodes.push(Node::Raw(&rest[..content_end]));
+                    rest = &rest[content_end..];
+                }
+            }
+            Some(node) => nodes.push(node),
+            None => push_text(&mut nodes, markup),
+        }
+    }
+
+    nodes
+}
+
+fn push_text<'a>(nodes: &mut Vec<Node<'a>>, text: &'a str) {
+    if !text.trim().is_empty() {
+        nodes.push(Node::Text(text));
+    }
+}
+
+// Finds the end of a tag, skipping over quoted attribute values that might contain `>`
+fn find_tag_end(markup: &str, terminator: &str) -> Option<usize> {
+    if terminator != ">" {
+        return markup.find(terminator).map(|i| i + terminator.len());
+    }
+
+    let mut quote = None;
+    for (i, c) in markup.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if q == c => quote = None,
+            (None, '>') => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn parse_tag(markup: &str) -> Option<Node<'_>> {
+    let inner = markup.strip_prefix('<')?.strip_suffix('>')?;
+
+    if let Some(name) = inner.strip_prefix('/') {
+        return Some(Node::Close { name: name.trim() });
+    }
+
+    let (inner, self_closing) = match inner.strip_suffix('/') {
+        Some(inner) => (inner, true),
+        None => (inner, false),
+    };
+
+    let name_end = inner
+        .find(|c: char| c.is_whitespace())
+        .unwrap_or(inner.len());
+    let name = &inner[..name_end];
+    if name.is_empty() || !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return None;
+    }
+
+    Some(Node::Open {
+        name,
+        attributes: parse_attributes(&inner[name_end..]),
+        self_closing,
+    })
+}
+
+fn parse_attributes(mut rest: &str) -> Vec<(&str, Option<&str>)> {
+    let mut attributes = vec![];
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+
+        let Some(after_equals) = rest.strip_prefix('=') else {
+            attributes.push((name, None));
+            continue;
+        };
+        let after_equals = after_equals.trim_start();
+
+        let value_end = match after_equals.chars().next() {
+            Some(quote @ ('"' | '\'')) => after_equals[1..]
+                .find(quote)
+                .map(|i| i + 2)
+                .unwrap_or(after_equals.len()),
+            _ => after_equals
+                .find(char::is_whitespace)
+                .unwrap_or(after_equals.len()),
+        };
+        attributes.push((name, Some(&after_equals[..value_end])));
+        rest = &after_equals[value_end..];
+    }
+
+    attributes
+}
+
+#[test]
+fn test_render_markup() -> Result<(), Box<dyn std::error::Error>> {
+    let xml =
+        r#"<?xml version="1.0"?><root a="1 > 0"><item>one</item><empty/><!-- note --></root>"#;
+    let mut out = vec![];
+    render(xml, Dialect::Xml, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "<?xml version=\"1.0\"?>\n<root a=\"1 > 0\">\n  <item>one</item>\n  <empty/>\n  <!-- note -->\n</root>\n"
+    );
+
+    let html = "<html><body><br><p>Hi<script>if (a < b) {}</script></body></html>";
+    let mut out = vec![];
+    render(html, Dialect::Html, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "<html>\n  <body>\n    <br>\n    <p>\n      Hi\n      <script>\nif (a < b) {}\n      </script>\n  </body>\n</html>\n"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+pub mod binary;
+pub mod csv;
+pub mod form;
+pub mod image;
+pub mod json;
+pub mod markup;
+pub mod protobuf;
+pub mod script;
+pub mod yaml;
+
+use std::io::{self, Write};
+
+use crate::color::{Token, style};
+use image::ImageProtocol;
+
+/// How bodies should be laid out when rendered to the terminal
+#[derive(Debug, Clone, Copy)]
+pub struct RenderOptions {
+    /// Number of spaces per nesting level
+    pub indent: usize,
+    /// Emit object keys in sorted order rather than the order they were received in
+    pub sort_keys: bool,
+    /// Highlight tokens with ANSI colors
+    pub color: bool,
+    /// How to preview images inline, if the terminal can do it at all
+    pub image_preview: Option<ImageProtocol>,
+    /// Number of bytes of a binary body to hexdump, 0 for all of them
+    pub hexdump_limit: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            indent: 2,
+            sort_keys: false,
+            color: false,
+            image_preview: None,
+            hexdump_limit: 512,
+        }
+    }
+}
+
+// Writes a single token, highlighted when colors are enabled
+fn paint<W: Write>(out: &mut W, token: Token, text: &str, color: bool) -> io::Result<()> {
+    if color {
+        write!(out, "{}", style(token).paint(text))
+    } else {
+        out.write_all(text.as_bytes())
+    }
+}
+
+/// Prints text that has no dedicated renderer exactly as it was received
+pub fn plain<W: Write>(text: &str, mut out: W) -> io::Result<()> {
+    out.write_all(text.as_bytes())?;
+    if !text.ends_with('\n') {
+        writeln!(out)?;
+    }
+    out.flush()
+}
@@ -0,0 +1,299 @@
+use std::io::{self, Write};
+
+use base64::{Engine, engine::general_purpose};
+use serde_json::{Map, Value, json};
+
+use super::{RenderOptions, binary, paint};
+use crate::color::Token;
+
+// Field numbers are 29 bits
+const MAX_FIELD: u64 = (1 << 29) - 1;
+
+// Guessing goes no deeper than this, anything nested further is shown as bytes
+const MAX_DEPTH: usize = 32;
+
+/// One field as it was encoded, what it means left to guess
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub number: u64,
+    pub payload: Payload,
+}
+
+/// A field's value by wire type, with length-delimited ones told apart by their content
+#[derive(Debug, Clone, PartialEq)]
+pub enum Payload {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    /// Length-delimited bytes that parse as a message of their own
+    Message(Vec<Field>),
+    /// Length-delimited bytes that are printable UTF-8
+    String(String),
+    Bytes(Vec<u8>),
+    /// The deprecated start and end group markers, with the fields in between
+    Group(Vec<Field>),
+}
+
+/// Whether the MIME type is one protobuf bodies are sent as
+pub fn is_protobuf(mime: &str) -> bool {
+    matches!(
+        mime,
+        "application/protobuf"
+            | "application/x-protobuf"
+            | "application/vnd.google.protobuf"
+            | "application/x-google-protobuf"
+    )
+}
+
+/// Walks the wire format, returning the fields if all of the data is a message
+pub fn decode(data: &[u8]) -> Option<Vec<Field>> {
+    message(data, 0)
+}
+
+fn message(data: &[u8], depth: usize) -> Option<Vec<Field>> {
+    let mut rest = data;
+    fields(&mut rest, None, depth)
+}
+
+// Reads fields until the data runs out, or the group being read ends
+fn fields(data: &mut &[u8], group: Option<u64>, depth: usize) -> Option<Vec<Field>> {
+    let mut fields = vec![];
+    while !data.is_empty() {
+        let key = varint(data)?;
+        let number = key >> 3;
+        if number == 0 || number > MAX_FIELD {
+            return None;
+        }
+        let payload = match key & 7 {
+            0 => Payload::Varint(varint(data)?),
+            1 => Payload::Fixed64(u64::from_le_bytes(take(data, 8)?.try_into().ok()?)),
+            2 => {
+                let length = usize::try_from(varint(data)?).ok()?;
+                guess(take(data, length)?, depth)
+            }
+            // Groups nest without a length, so this is the only thing bounding the recursion
+            3 if depth >= MAX_DEPTH => return None,
+            3 => Payload::Group(self::fields(data, Some(number), depth + 1)?),
+            4 => return (group == Some(number)).then_some(fields),
+            5 => Payload::Fixed32(u32::from_le_bytes(take(data, 4)?.try_into().ok()?)),
+            _ => return None,
+        };
+        fields.push(Field { number, payload });
+    }
+    // A group that never ends isn't a message
+    group.is_none().then_some(fields)
+}
+
+fn varint(data: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for (index, byte) in data.iter().take(10).enumerate() {
+        value |= u64::from(byte & 0x7f) << (7 * index);
+        if byte & 0x80 == 0 {
+            *data = &data[index + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    let taken = data.get(..length)?;
+    *data = &data[length..];
+    Some(taken)
+}
+
+// Text reads as text first: short strings often happen to parse as messages too
+fn guess(bytes: &[u8], depth: usize) -> Payload {
+    if let Ok(text) = std::str::from_utf8(bytes)
+        && text
+            .chars()
+            .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+    {
+        return Payload::String(text.to_string());
+    }
+    if depth < MAX_DEPTH
+        && let Some(fields) = message(bytes, depth + 1)
+    {
+        return Payload::Message(fields);
+    }
+    Payload::Bytes(bytes.to_vec())
+}
+
+/// The fields as JSON keyed by field number, repeated ones as arrays and bytes as base64
+pub fn to_json(fields: &[Field]) -> Value {
+    let mut object = Map::new();
+    for field in fields {
+        let value = match &field.payload {
+            Payload::Varint(value) | Payload::Fixed64(value) => json!(value),
+            Payload::Fixed32(value) => json!(value),
+            Payload::String(text) => json!(text),
+            Payload::Bytes(bytes) => json!(general_purpose::STANDARD.encode(bytes)),
+            Payload::Message(fields) | Payload::Group(fields) => to_json(fields),
+        };
+        let key = field.number.to_string();
+        match object.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(first) => *first = json!([first.take(), value]),
+            None => {
+                object.insert(key, value);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+/// Renders a protobuf body field by field, as `number wire-type: value`, with a hexdump instead
+/// when it doesn't parse
+pub fn render<W: Write>(data: &[u8], options: &RenderOptions, mut out: W) -> io::Result<()> {
+    match decode(data) {
+        Some(fields) => {
+            paint(
+                &mut out,
+                Token::Comment,
+                &format!(
+                    "Protobuf message, {} bytes, decoded without a schema",
+                    data.len()
+                ),
+                options.color,
+            )?;
+            writeln!(out)?;
+            render_fields(&fields, 0, options, &mut out)?;
+        }
+        None => {
+            paint(
+                &mut out,
+                Token::Comment,
+                "Not a valid protobuf message",
+                options.color,
+            )?;
+            writeln!(out)?;
+            binary::hexdump(data, options, &mut out)?;
+        }
+    }
+    out.flush()
+}
+
+/// Writes the fields one per line, nested messages and groups indented inside braces
+pub fn render_fields<W: Write>(
+    fields: &[Field],
+    depth: usize,
+    options: &RenderOptions,
+    out: &mut W,
+) -> io::Result<()> {
+    let color = options.color;
+    let indent = " ".repeat(options.indent * depth);
+    for field in fields {
+        write!(out, "{}", indent)?;
+        paint(out, Token::Key, &field.number.to_string(), color)?;
+        write!(out, " ")?;
+        let (wire, value, token) = match &field.payload {
+            Payload::Varint(value) => {
+                let mut text = value.to_string();
+                // Negative int32 and int64 values take all ten bytes
+                if *value > i64::MAX as u64 {
+                    text.push_str(&format!(" ({} as int64)", *value as i64));
+                }
+                ("varint", text, Token::Number)
+            }
+            Payload::Fixed64(value) => (
+                "fixed64",
+                format!("{} ({} as double)", value, f64::from_bits(*value)),
+                Token::Number,
+            ),
+            Payload::Fixed32(value) => (
+                "fixed32",
+                format!("{} ({} as float)", value, f32::from_bits(*value)),
+                Token::Number,
+            ),
+            Payload::String(text) => ("string", format!("{:?}", text), Token::String),
+            Payload::Bytes(bytes) => {
+                let shown = match options.hexdump_limit {
+                    0 => bytes.len(),
+                    limit => bytes.len().min(limit),
+                };
+                let mut hex: Vec<String> = bytes[..shown]
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                if shown < bytes.len() {
+                    hex.push(format!("... {} more", bytes.len() - shown));
+                }
+                ("bytes", hex.join(" "), Token::Literal)
+            }
+            Payload::Message(fields) | Payload::Group(fields) => {
+                let wire = match field.payload {
+                    Payload::Group(_) => "group",
+                    _ => "message",
+                };
+                paint(out, Token::Comment, wire, color)?;
+                write!(out, " ")?;
+                paint(out, Token::Punctuation, "{", color)?;
+                writeln!(out)?;
+                render_fields(fields, depth + 1, options, out)?;
+                write!(out, "{}", indent)?;
+                paint(out, Token::Punctuation, "}", color)?;
+                writeln!(out)?;
+                continue;
+            }
+        };
+        paint(out, Token::Comment, wire, color)?;
+        paint(out, Token::Punctuation, ":", color)?;
+        write!(out, " ")?;
+        paint(out, token, &value, color)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_decode_without_schema() -> Result<(), Box<dyn std::error::Error>> {
+    // 1: 150, 2: "Ada", 3: {1: -1 as int64}, 4: 1.0f, 5: 0xff 0x00, 5 again
+    let data = b"\x08\x96\x01\x12\x03Ada\x1a\x0b\x08\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01\
+                 \x25\x00\x00\x80\x3f\x2a\x02\xff\x00\x2a\x00";
+    let fields = decode(data).ok_or("doesn't decode")?;
+    assert_eq!(fields[0].payload, Payload::Varint(150));
+    assert_eq!(fields[1].payload, Payload::String("Ada".to_string()));
+    assert_eq!(
+        fields[2].payload,
+        Payload::Message(vec![Field {
+            number: 1,
+            payload: Payload::Varint(u64::MAX)
+        }])
+    );
+    assert_eq!(fields[4].payload, Payload::Bytes(vec![0xff, 0]));
+    assert_eq!(
+        to_json(&fields),
+        json!({"1": 150, "2": "Ada", "3": {"1": u64::MAX}, "4": 1065353216, "5": ["/wA=", ""]})
+    );
+
+    let mut out = vec![];
+    render_fields(&fields[..4], 0, &RenderOptions::default(), &mut out)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "1 varint: 150\n\
+         2 string: \"Ada\"\n\
+         3 message {\n  1 varint: 18446744073709551615 (-1 as int64)\n}\n\
+         4 fixed32: 1065353216 (1 as float)\n"
+    );
+
+    // Truncated, and an end group with no start
+    assert_eq!(decode(b"\x12\x05Ada"), None);
+    assert_eq!(decode(b"\x0c"), None);
+
+    // Groups nested past the limit give up instead of overflowing the stack
+    assert_eq!(
+        decode(b"\x0b\x08\x01\x0c"),
+        Some(vec![Field {
+            number: 1,
+            payload: Payload::Group(vec![Field {
+                number: 1,
+                payload: Payload::Varint(1)
+            }])
+        }])
+    );
+    assert_eq!(decode(&vec![0x0b; 1 << 20]), None);
+    let mut nested = vec![0x0b; MAX_DEPTH + 1];
+    nested.extend(vec![0x0c; MAX_DEPTH + 1]);
+    assert_eq!(decode(&nested), None);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::io::{self, Write};
+
+use super::{RenderOptions, paint};
+use crate::color::Token;
+
+const KEYWORDS: &[&str] = &[
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "export",
+    "extends",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "import",
+    "in",
+    "instanceof",
+    "let",
+    "new",
+    "of",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "yield",
+];
+
+const LITERALS: &[&str] = &["true", "false", "null", "undefined", "NaN", "Infinity"];
+
+/// Highlights JavaScript source while leaving its layout untouched
+pub fn render<W: Write>(text: &str, options: &RenderOptions, mut out: W) -> io::Result<()> {
+    let color = options.color;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        let (token, length) = if rest.starts_with("//") {
+            (Some(Token::Comment), rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            (
+                Some(Token::Comment),
+                comment.find("*/").map(|i| i + 4).unwrap_or(rest.len()),
+            )
+        } else if matches!(c, '"' | '\'' | '`') {
+            (Some(Token::String), string_length(rest, c))
+        } else if c.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(rest.len());
+            (Some(Token::Number), length)
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let length = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            let word = &rest[..length];
+            let token = if KEYWORDS.contains(&word) {
+                Some(Token::Keyword)
+            } else if LITERALS.contains(&word) {
+                Some(Token::Literal)
+            } else {
+                None
+            };
+            (token, length)
+        } else {
+            (None, c.len_utf8())
+        };
+
+        let (lexeme, remainder) = rest.split_at(length);
+        match token {
+            Some(token) => paint(&mut out, token, lexeme, color)?,
+            None => out.write_all(lexeme.as_bytes())?,
+        }
+        rest = remainder;
+    }
+
+    if !text.ends_with('\n') {
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+// Length of a quoted string including both quotes, honoring backslash escapes
+fn string_length(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+#[test]
+fn test_render_script() -> Result<(), Box<dyn std::error::Error>> {
+    let paint = |token, text: &str| crate::color::style(token).paint(text).to_string();
+    let options = RenderOptions {
+        color: true,
+        ..Default::default()
+    };
+
+    let mut out = vec![];
+    render(
+        "const n = 42; // answer\nlet s = 'it\\'s' /* x */;",
+        &options,
+        &mut out,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        [
+            paint(Token::Keyword, "const"),
+            " n = ".to_string(),
+            paint(Token::Number, "42"),
+            "; ".to_string(),
+            paint(Token::Comment, "// answer"),
+            "\n".to_string(),
+            paint(Token::Keyword, "let"),
+            " s = ".to_string(),
+            paint(Token::String, "'it\\'s'"),
+            " ".to_string(),
+            paint(Token::Comment, "/* x */"),
+            ";\n".to_string(),
+        ]
+        .concat()
+    );
+    Ok(())
+}
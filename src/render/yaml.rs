@@ -0,0 +1,151 @@
+use std::io::{self, Write};
+
+use super::{RenderOptions, paint};
+use crate::color::Token;
+
+/// Highlights a YAML document line by line.
+///
+/// Indentation is meaningful in YAML, so the layout is left exactly as received and only the
+/// tokens are colored: comments, keys, sequence markers and scalar values.
+pub fn render<W: Write>(text: &str, options: &RenderOptions, mut out: W) -> io::Result<()> {
+    let color = options.color;
+
+    for line in text.lines() {
+        let content = line.trim_start();
+        let indentation = &line[..line.len() - content.len()];
+        write!(out, "{}", indentation)?;
+
+        if content.starts_with('#') {
+            paint(&mut out, Token::Comment, content, color)?;
+        } else if content == "---" || content == "..." {
+            paint(&mut out, Token::Punctuation, content, color)?;
+        } else {
+            write_entry(&mut out, content, color)?;
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()
+}
+
+fn write_entry<W: Write>(out: &mut W, mut content: &str, color: bool) -> io::Result<()> {
+    // Sequence items, possibly nested like `- - value`
+    while let Some(rest) = content
+        .strip_prefix("- ")
+        .or(content.strip_prefix('-').filter(|r| r.is_empty()))
+    {
+        paint(out, Token::Punctuation, "-", color)?;
+        write!(out, " ")?;
+        content = rest.trim_start();
+    }
+
+    let (value, comment) = split_comment(content);
+
+    match find_key(value) {
+        Some(colon) => {
+            paint(out, Token::Key, &value[..colon], color)?;
+            paint(out, Token::Punctuation, ":", color)?;
+            let scalar = &value[colon + 1..];
+            let trimmed = scalar.trim_start();
+            write!(out, "{}", &scalar[..scalar.len() - trimmed.len()])?;
+            write_scalar(out, trimmed, color)?;
+        }
+        None => write_scalar(out, value, color)?,
+    }
+
+    if let Some(comment) = comment {
+        paint(out, Token::Comment, comment, color)?;
+    }
+    Ok(())
+}
+
+fn write_scalar<W: Write>(out: &mut W, scalar: &str, color: bool) -> io::Result<()> {
+    let trimmed = scalar.trim_end();
+    let token = match trimmed {
+        "" => return write!(out, "{}", scalar),
+        "true" | "false" | "yes" | "no" | "on" | "off" | "null" | "~" => Token::Literal,
+        _ if trimmed.parse::<f64>().is_ok() => Token::Number,
+        _ if trimmed.starts_with(['&', '*', '!']) => Token::Keyword,
+        _ if trimmed.starts_with(['|', '>', '{', '[']) => Token::Punctuation,
+        _ => Token::String,
+    };
+    paint(out, token, trimmed, color)?;
+    write!(out, "{}", &scalar[trimmed.len()..])
+}
+
+// The position of the `:` that ends a mapping key, ignoring any inside quotes
+fn find_key(content: &str) -> Option<usize> {
+    let mut quote = None;
+    let bytes = content.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        match (quote, b) {
+            (None, b'"' | b'\'') if i == 0 => quote = Some(b),
+            (Some(q), _) if q == b => quote = None,
+            (None, b':') if bytes.get(i + 1).is_none_or(|n| *n == b' ') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Splits off a trailing ` # comment`, which needs whitespace before the `#`
+fn split_comment(content: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let bytes = content.as_bytes();
+    for (i, &b) in bytes.iter().enumerate() {
+        match (quote, b) {
+            (None, b'"' | b'\'') => quote = Some(b),
+            (Some(q), _) if q == b => quote = None,
+            (None, b'#') if i > 0 && bytes[i - 1] == b' ' => {
+                return (&content[..i], Some(&content[i..]));
+            }
+            _ => {}
+        }
+    }
+    (content, None)
+}
+
+#[test]
+fn test_render_yaml() -> Result<(), Box<dyn std::error::Error>> {
+    let paint = |token, text: &str| crate::color::style(token).paint(text).to_string();
+    let options = RenderOptions {
+        color: true,
+        ..Default::default()
+    };
+
+    let mut out = vec![];
+    render(
+        "# doc\nname: \"a: b\" # note\nitems:\n  - 3\n  - &x true\n---\n",
+        &options,
+        &mut out,
+    )?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        [
+            paint(Token::Comment, "# doc"),
+            "\n".to_string(),
+            paint(Token::Key, "name"),
+            paint(Token::Punctuation, ":"),
+            " ".to_string(),
+            paint(Token::String, "\"a: b\""),
+            " ".to_string(),
+            paint(Token::Comment, "# note"),
+            "\n".to_string(),
+            paint(Token::Key, "items"),
+            paint(Token::Punctuation, ":"),
+            "\n  ".to_string(),
+            paint(Token::Punctuation, "-"),
+            " ".to_string(),
+            paint(Token::Number, "3"),
+            "\n  ".to_string(),
+            paint(Token::Punctuation, "-"),
+            " ".to_string(),
+            paint(Token::Keyword, "&x true"),
+            "\n".to_string(),
+            paint(Token::Punctuation, "---"),
+            "\n".to_string(),
+        ]
+        .concat()
+    );
+    Ok(())
+}